@@ -0,0 +1,49 @@
+// Global variable access, from scripts and from the host.
+// Run with `cargo bench --bench globals`
+use std::time::Instant;
+
+use purua::state::LuaState;
+use purua::value::Value;
+
+const N: i64 = 1_000_000;
+
+fn bench(name: &str, f: impl FnOnce()) {
+    let start = Instant::now();
+    f();
+    println!("{:<24} {:?}", name, start.elapsed());
+}
+
+fn main() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.assign_global("x", Value::Number(1));
+    l.assign_global("n", Value::Number(N));
+
+    bench("script reads", || {
+        l.run_string("for i = 1, n do y = x end").unwrap();
+    });
+    bench("script writes", || {
+        l.run_string("for i = 1, n do x = i end").unwrap();
+    });
+    bench("script calls", || {
+        l.run_string("function id(v) return v end\nfor i = 1, n do id(i) end")
+            .unwrap();
+    });
+
+    let mut sum = 0;
+    bench("host reads by name", || {
+        for _ in 0..N {
+            if let Some(Value::Number(v)) = l.get_global("x") {
+                sum += v;
+            }
+        }
+    });
+    let id = l.global_id("x");
+    bench("host reads by id", || {
+        for _ in 0..N {
+            if let Value::Number(v) = l.get_global_by_id(id) {
+                sum += v;
+            }
+        }
+    });
+    assert!(sum > 0);
+}
@@ -0,0 +1,28 @@
+Account = {}
+Account.__index = Account
+
+function Account.new(owner, balance)
+   local self = setmetatable({}, Account)
+   self.owner = owner
+   self.balance = balance
+   return self
+end
+
+function Account:deposit(amount)
+   self.balance = self.balance + amount
+end
+
+function Account:withdraw(amount)
+   if amount > self.balance then
+      error("insufficient funds")
+   end
+   self.balance = self.balance - amount
+end
+
+acc = Account.new("alice", 50)
+acc:deposit(100)
+acc:withdraw(30)
+print(acc.owner .. ": " .. acc.balance)
+print("\n")
+print(getmetatable(acc) == Account)
+print("\n")
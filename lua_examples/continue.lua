@@ -0,0 +1,9 @@
+for i = 1, 10 do
+   if i % 2 == 1 then
+      goto cont
+   end
+   local square = i * i
+   print(square)
+   print("\n")
+   ::cont::
+end
@@ -0,0 +1,10 @@
+function early()
+   do return 5 end
+   print("Unreachable!\n")
+end
+
+print(early())
+print("\n")
+
+do return end
+print("Unreachable too!\n")
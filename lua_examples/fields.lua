@@ -0,0 +1,14 @@
+point = { 10, x = 1, 20, ["y"] = 2 }
+shape = { name = "square", size = { w = 3, h = 4 } }
+
+print(point.x + point.y)
+print("\n")
+print(shape.name .. ": " .. shape.size.w * shape.size.h)
+print("\n")
+print(shape.color)
+print("\n")
+print(point[1] + point[2])
+print("\n")
+key = "size"
+print(shape[key]["h"])
+print("\n")
@@ -0,0 +1,14 @@
+count = 0
+for i = 1, 3 do
+   i = 10
+   count = count + 1
+end
+print(count)
+print("\n")
+
+for i = 1, 2 do
+   print(i)
+   i = i * 100
+   print(i)
+   print("\n")
+end
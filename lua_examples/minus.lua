@@ -0,0 +1,12 @@
+a = 5
+b = 3
+x = 7
+
+print(a - -b)
+print("\n")
+print(1 - -1)
+print("\n")
+print(- -x)
+print("\n")
+print(2 - - 1)
+print("\n")
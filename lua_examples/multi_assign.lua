@@ -0,0 +1,15 @@
+function divmod(a, b)
+   return a // b, a % b
+end
+
+function show()
+   local q, r, extra = divmod(17, 5)
+   print(q)
+   print(" ")
+   print(r)
+   print(" ")
+   print(extra)
+   print("\n")
+end
+
+show()
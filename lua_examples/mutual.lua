@@ -0,0 +1,18 @@
+function is_even(n)
+   if n == 0 then
+      return true
+   end
+   return is_odd(n - 1)
+end
+
+function is_odd(n)
+   if n == 0 then
+      return false
+   end
+   return is_even(n - 1)
+end
+
+print(is_even(10))
+print("\n")
+print(is_odd(7))
+print("\n")
@@ -0,0 +1,349 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::parser::{Rule, StatKind};
+
+// A problem found in a parsed tree without running it. location
+// names the enclosing function, or "main chunk", and line is the
+// source line of the statement involved, 0 when unknown
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub name: Option<String>,
+    pub location: String,
+    pub line: usize,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line > 0 {
+            write!(f, "{}, line {}: {}", self.location, self.line, self.message)
+        } else {
+            write!(f, "{}: {}", self.location, self.message)
+        }
+    }
+}
+
+const MAIN_CHUNK: &str = "main chunk";
+
+pub fn analyze(rule: &Rule) -> Vec<Diagnostic> {
+    let mut diags = vec![];
+    walk(rule, MAIN_CHUNK, &mut diags);
+    diags
+}
+
+fn report(diags: &mut Vec<Diagnostic>, location: &str, line: usize, message: impl Into<String>) {
+    diags.push(Diagnostic {
+        message: message.into(),
+        name: None,
+        location: location.to_string(),
+        line,
+    });
+}
+
+fn function_location(stat: &Rule) -> Option<String> {
+    match stat {
+        Rule::Stat(StatKind::DeclareFunction, Some(name), _, _, _, _) => match name.as_ref() {
+            Rule::FuncName(path, method) => {
+                let mut name = symbol_names(path).join(".");
+                if let Some(Rule::Symbol(m)) = method.as_deref() {
+                    name = format!("{}:{}", name, m);
+                }
+                Some(format!("function '{}'", name))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn walk(rule: &Rule, location: &str, diags: &mut Vec<Diagnostic>) {
+    if let Rule::Chunk(stats, _, lines) = rule {
+        check_chunk(stats, lines, location, diags);
+    }
+    let inner = function_location(rule);
+    for child in children(rule) {
+        walk(child, inner.as_deref().unwrap_or(location), diags);
+    }
+}
+
+fn check_chunk(stats: &[Box<Rule>], lines: &[usize], location: &str, diags: &mut Vec<Diagnostic>) {
+    for (i, stat) in stats.iter().enumerate() {
+        let is_last = i + 1 == stats.len();
+        if let Rule::Stat(StatKind::Break, _, _, _, _, _) = stat.as_ref() {
+            if !is_last {
+                let line = lines.get(i + 1).copied().unwrap_or(0);
+                report(diags, location, line, "unreachable statement after break");
+            }
+        }
+    }
+}
+
+// Flags reads of names that are neither a local in scope nor a
+// global: one assigned somewhere in the tree, or listed in known
+// (the builtins, say). Scoping follows the evaluator: a function
+// body sees its own parameters and locals only
+pub fn check_undefined(rule: &Rule, known: &[&str]) -> Vec<Diagnostic> {
+    let mut globals: HashSet<String> = known.iter().map(|s| s.to_string()).collect();
+    collect_globals(rule, &mut globals);
+    let mut resolver = Resolver {
+        globals,
+        scopes: vec![vec![]],
+        location: MAIN_CHUNK.to_string(),
+        line: 0,
+        diags: vec![],
+    };
+    resolver.walk(rule);
+    resolver.diags
+}
+
+fn collect_globals(rule: &Rule, globals: &mut HashSet<String>) {
+    match rule {
+        Rule::Stat(StatKind::VarAssign, Some(vars), _, _, _, _) => {
+            if let Rule::VarList(vars) = vars.as_ref() {
+                for var in vars.iter() {
+                    if let Some(name) = var_name(var) {
+                        globals.insert(name.to_string());
+                    }
+                }
+            }
+        }
+        Rule::FuncName(sym, None) => {
+            if let Rule::Symbol(name) = sym.as_ref() {
+                globals.insert(name.to_string());
+            }
+        }
+        _ => {}
+    }
+    for child in children(rule) {
+        collect_globals(child, globals);
+    }
+}
+
+fn symbol_names(rule: &Rule) -> Vec<&str> {
+    match rule {
+        Rule::Symbol(name) => vec![name],
+        Rule::SymbolList(v) => v.iter().flat_map(|r| symbol_names(r)).collect(),
+        _ => vec![],
+    }
+}
+
+fn var_name(rule: &Rule) -> Option<&str> {
+    match rule {
+        Rule::Var(sym) => match sym.as_ref() {
+            Rule::Name(name, _) => Some(name),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn symbol_list(rule: &Rule) -> Vec<String> {
+    match rule {
+        Rule::Symbol(name) => vec![name.to_string()],
+        Rule::SymbolList(v) => v.iter().flat_map(|r| symbol_list(r)).collect(),
+        Rule::ParList1(r) => symbol_list(r),
+        _ => vec![],
+    }
+}
+
+struct Resolver {
+    globals: HashSet<String>,
+    scopes: Vec<Vec<String>>,
+    location: String,
+    line: usize,
+    diags: Vec<Diagnostic>,
+}
+
+impl Resolver {
+    fn declare(&mut self, names: Vec<String>) {
+        self.scopes.last_mut().unwrap().extend(names);
+    }
+
+    fn scoped(&mut self, names: Vec<String>, rule: &Rule) {
+        self.scopes.push(names);
+        self.walk(rule);
+        self.scopes.pop();
+    }
+
+    fn read(&mut self, name: &str) {
+        let local = self.scopes.iter().any(|s| s.iter().any(|n| n == name));
+        if !local && !self.globals.contains(name) {
+            self.diags.push(Diagnostic {
+                message: format!("undefined variable '{}'", name),
+                name: Some(name.to_string()),
+                location: self.location.clone(),
+                line: self.line,
+            });
+        }
+    }
+
+    fn walk_opt(&mut self, rule: &Option<Box<Rule>>) {
+        if let Some(rule) = rule {
+            self.walk(rule);
+        }
+    }
+
+    fn walk(&mut self, rule: &Rule) {
+        match rule {
+            Rule::Block(chunk) => self.scoped(vec![], chunk),
+            Rule::Chunk(stats, last, lines) => {
+                for (i, stat) in stats.iter().chain(last).enumerate() {
+                    self.line = lines.get(i).copied().unwrap_or(0);
+                    self.walk(stat);
+                }
+            }
+            Rule::Var(var) => match var_name(rule) {
+                Some(name) => self.read(name),
+                None => self.walk(var),
+            },
+            // a name after the dot is a field, not a variable
+            Rule::Index(prefix, key) => {
+                self.walk(prefix);
+                if !matches!(key.as_ref(), Rule::Symbol(_)) {
+                    self.walk(key);
+                }
+            }
+            Rule::FunctionCall(callee, args) => {
+                match callee.as_ref() {
+                    Rule::Name(name, _) => self.read(name),
+                    callee => self.walk(callee),
+                }
+                self.walk(args);
+            }
+            // the method name is a field, not a variable
+            Rule::MethodCall(recv, _, args) => {
+                self.walk(recv);
+                self.walk(args);
+            }
+            Rule::Field(key, value) => {
+                if !matches!(key.as_ref(), Rule::Symbol(_)) {
+                    self.walk(key);
+                }
+                self.walk(value);
+            }
+            Rule::FuncBody(params, body) => {
+                let params = params.as_deref().map(symbol_list).unwrap_or_default();
+                let outer = std::mem::replace(&mut self.scopes, vec![params]);
+                self.walk(body);
+                self.scopes = outer;
+            }
+            Rule::Stat(kind, a, b, c, d, e) => match kind {
+                StatKind::LocalVar => {
+                    self.walk_opt(b);
+                    self.declare(a.as_deref().map(symbol_list).unwrap_or_default());
+                }
+                StatKind::VarAssign => {
+                    if let Some(Rule::VarList(vars)) = a.as_deref() {
+                        for var in vars.iter().filter(|v| var_name(v).is_none()) {
+                            self.walk(var);
+                        }
+                    }
+                    self.walk_opt(b);
+                }
+                StatKind::DeclareFunction => {
+                    let location = function_location(rule).unwrap_or_default();
+                    let outer = std::mem::replace(&mut self.location, location);
+                    self.walk_opt(b);
+                    self.location = outer;
+                }
+                StatKind::For => {
+                    self.walk_opt(b);
+                    self.walk_opt(c);
+                    self.walk_opt(d);
+                    let names = a.as_deref().map(symbol_list).unwrap_or_default();
+                    if let Some(body) = e {
+                        self.scoped(names, body);
+                    }
+                }
+                // the condition sees the body's locals
+                StatKind::Repeat => {
+                    self.scopes.push(vec![]);
+                    match a.as_deref() {
+                        Some(Rule::Block(chunk)) => self.walk(chunk),
+                        Some(body) => self.walk(body),
+                        None => {}
+                    }
+                    self.walk_opt(b);
+                    self.scopes.pop();
+                }
+                StatKind::ForIn => {
+                    self.walk_opt(b);
+                    let names = a.as_deref().map(symbol_list).unwrap_or_default();
+                    if let Some(body) = c {
+                        self.scoped(names, body);
+                    }
+                }
+                _ => {
+                    for child in children(rule) {
+                        self.walk(child);
+                    }
+                }
+            },
+            _ => {
+                for child in children(rule) {
+                    self.walk(child);
+                }
+            }
+        }
+    }
+}
+
+fn boxed(v: &[Box<Rule>]) -> Vec<&Rule> {
+    v.iter().map(|r| r.as_ref()).collect()
+}
+
+fn children(rule: &Rule) -> Vec<&Rule> {
+    match rule {
+        Rule::SymbolList(v)
+        | Rule::VarList(v)
+        | Rule::ExpList(v)
+        | Rule::Args(v)
+        | Rule::FieldList(v) => boxed(v),
+        Rule::Chunk(v, last, _) => {
+            let mut c = boxed(v);
+            c.extend(last.as_deref());
+            c
+        }
+        Rule::IfStat(v, w) => {
+            let mut c = boxed(v);
+            c.extend(boxed(w));
+            c
+        }
+        Rule::FuncName(path, method) => {
+            let mut c = vec![path.as_ref()];
+            c.extend(method.as_deref());
+            c
+        }
+        Rule::Stat(_, a, b, c, d, e) => [a, b, c, d, e]
+            .iter()
+            .filter_map(|r| r.as_deref())
+            .collect(),
+        Rule::Block(r)
+        | Rule::LastStat(r)
+        | Rule::Var(r)
+        | Rule::Exp(r)
+        | Rule::Prefixexp(r)
+        | Rule::ParList1(r)
+        | Rule::TableConst(r)
+        | Rule::UnOp(_, r) => vec![r.as_ref()],
+        Rule::FunctionCall(a, b) | Rule::Index(a, b) | Rule::Field(a, b) | Rule::BinOp(_, a, b) => {
+            vec![a.as_ref(), b.as_ref()]
+        }
+        Rule::FuncBody(params, body) => {
+            let mut c: Vec<&Rule> = params.as_deref().into_iter().collect();
+            c.push(body.as_ref());
+            c
+        }
+        Rule::MethodCall(a, b, c) => vec![a.as_ref(), b.as_ref(), c.as_ref()],
+        Rule::Nil
+        | Rule::Reserved(_)
+        | Rule::Bool(_)
+        | Rule::Numeral(_)
+        | Rule::Float(_)
+        | Rule::LiteralString(_)
+        | Rule::Symbol(_)
+        | Rule::Name(_, _)
+        | Rule::Nop => vec![],
+    }
+}
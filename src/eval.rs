@@ -1,8 +1,10 @@
+use crate::function::LuaFunction;
 use crate::parser::*;
 use crate::state::*;
-use crate::value::Value;
+use crate::table::LuaTable;
+use crate::value::{float_to_int, Value};
 
-use log::debug;
+use std::rc::Rc;
 
 macro_rules! is_exact_rule1 {
     ($rule:path, $y:expr) => {
@@ -27,64 +29,138 @@ macro_rules! is_exact_rule2 {
 }
 
 pub fn eval_exp(l: &mut LuaState, exp: &Rule) -> Result<Value, LuaError> {
-    let exp_: &Box<Rule> = is_exact_rule1!(Rule::Exp, exp)?;
-    let exp_ = exp_.as_ref();
+    // nesting is bounded so that a deep expression errors
+    // instead of overflowing the native stack
+    l.enter_exp()?;
+    let v = eval_exp_nested(l, exp);
+    l.leave_exp();
+    v
+}
+
+fn eval_exp_nested(l: &mut LuaState, exp: &Rule) -> Result<Value, LuaError> {
+    let exp_ = is_exact_rule1!(Rule::Exp, exp)?.as_ref();
     match exp_ {
-        Rule::Nil => return Ok(Value::Nil),
-        Rule::Bool(b) => return Ok(Value::Bool(b.to_owned())),
-        Rule::Numeral(n) => return Ok(Value::Number(n.to_owned() as i64)),
-        Rule::LiteralString(s) => return Ok(Value::LuaString(s.to_string())),
+        Rule::Nil => Ok(Value::Nil),
+        Rule::Bool(b) => Ok(Value::Bool(b.to_owned())),
+        Rule::Numeral(n) => Ok(Value::Number(*n)),
+        Rule::Float(f) => Ok(Value::Float(*f)),
+        Rule::LiteralString(s) => Ok(Value::LuaString(s.clone())),
         Rule::Prefixexp(_) => eval_prefixexp(l, exp_),
         Rule::TableConst(_) => eval_tableconst(l, exp_),
         Rule::BinOp(_, _, _) => eval_binop(l, exp_),
         Rule::UnOp(_, _) => eval_unop(l, exp_),
+        Rule::FuncBody(_, _) => eval_function_def(l, exp_),
         _ => Err(LuaError {
             message: format!("Unsupported exp rule: {:?}", exp_),
         }),
     }
 }
 
+// Each evaluation makes a distinct function value
+fn eval_function_def(l: &mut LuaState, body: &Rule) -> Result<Value, LuaError> {
+    let (params, block) = eval_funcbody(l, body)?;
+    Ok(Value::Function(LuaFunction::from_code(params, block)))
+}
+
+// Chains like `1 + 2 + 3` nest on the left and `a .. b .. c` on the
+// right. Both are walked in a loop, so only real nesting counts
+// towards the expression depth
 pub fn eval_binop(l: &mut LuaState, binop: &Rule) -> Result<Value, LuaError> {
     match binop {
+        Rule::BinOp(c, _, _) if is_right_assoc(*c) => eval_right_chain(l, binop),
         Rule::BinOp(c, lhs, rhs) => {
-            let lhs = lhs.as_ref();
-            let lvalue = match lhs {
-                Rule::Exp(_) => eval_exp(l, lhs)?,
-                Rule::BinOp(_, _, _) => eval_binop(l, lhs)?,
-                Rule::UnOp(_, _) => eval_unop(l, lhs)?,
-                _ => {
-                    return Err(l.error("lhs invalid"));
-                }
-            };
-            let rhs = rhs.as_ref();
-            let rvalue = match rhs {
-                Rule::Exp(_) => eval_exp(l, rhs)?,
-                Rule::BinOp(_, _, _) => eval_binop(l, rhs)?,
-                Rule::UnOp(_, _) => eval_unop(l, rhs)?,
-                _ => {
-                    return Err(l.error("rhs invalid"));
-                }
-            };
-
+            let lvalue = match chained_binop(lhs) {
+                Some(chain) => eval_left_chain(l, chain),
+                None => eval_operand(l, lhs, "lhs invalid"),
+            }?;
+            // `and` and `or` yield an operand and skip the right
+            // one once the left decides the result
+            match c {
+                '&' if !lvalue.is_truthy() => return Ok(lvalue),
+                '|' if lvalue.is_truthy() => return Ok(lvalue),
+                _ => {}
+            }
+            let rvalue = eval_operand(l, rhs, "rhs invalid")?;
             l.process_op(c, lvalue, rvalue)
         }
         _ => Err(l.error("binop invalid")),
     }
 }
 
+#[inline(never)]
+fn eval_left_chain(l: &mut LuaState, binop: &Rule) -> Result<Value, LuaError> {
+    let mut chain = vec![];
+    let mut first = binop;
+    while let Some(Rule::BinOp(c, lhs, rhs)) = chained_binop(first) {
+        chain.push((c, rhs.as_ref()));
+        first = lhs.as_ref();
+    }
+    let mut lvalue = eval_operand(l, first, "lhs invalid")?;
+    for (c, rhs) in chain.into_iter().rev() {
+        lvalue = match c {
+            '&' if !lvalue.is_truthy() => lvalue,
+            '|' if lvalue.is_truthy() => lvalue,
+            _ => {
+                let rvalue = eval_operand(l, rhs, "rhs invalid")?;
+                l.process_op(c, lvalue, rvalue)?
+            }
+        };
+    }
+    Ok(lvalue)
+}
+
+fn is_right_assoc(c: char) -> bool {
+    c == 'c' || c == '^'
+}
+
+// The operation an operand consists of, without parentheses
+fn chained_binop(exp: &Rule) -> Option<&Rule> {
+    match exp {
+        Rule::Exp(inner) => chained_binop(inner),
+        Rule::BinOp(_, _, _) => Some(exp),
+        _ => None,
+    }
+}
+
+// Operands are evaluated left to right, then combined from the right
+#[inline(never)]
+fn eval_right_chain(l: &mut LuaState, binop: &Rule) -> Result<Value, LuaError> {
+    let mut ops = vec![];
+    let mut values = vec![];
+    let mut cur = binop;
+    let last = loop {
+        match cur {
+            Rule::BinOp(c, lhs, rhs) if is_right_assoc(*c) => {
+                ops.push(*c);
+                values.push(eval_operand(l, lhs, "lhs invalid")?);
+                match chained_binop(rhs) {
+                    Some(next) => cur = next,
+                    None => break rhs.as_ref(),
+                }
+            }
+            _ => break cur,
+        }
+    };
+    let mut rvalue = eval_operand(l, last, "rhs invalid")?;
+    while let (Some(c), Some(lvalue)) = (ops.pop(), values.pop()) {
+        rvalue = l.process_op(&c, lvalue, rvalue)?;
+    }
+    Ok(rvalue)
+}
+
+fn eval_operand(l: &mut LuaState, exp: &Rule, invalid: &str) -> Result<Value, LuaError> {
+    match exp {
+        Rule::Exp(_) => eval_exp(l, exp),
+        Rule::BinOp(_, _, _) => eval_binop(l, exp),
+        Rule::UnOp(_, _) => eval_unop(l, exp),
+        _ => Err(l.error(invalid)),
+    }
+}
+
 pub fn eval_unop(l: &mut LuaState, unop: &Rule) -> Result<Value, LuaError> {
     match unop {
         Rule::UnOp(c, exp) => {
-            let exp = exp.as_ref();
-            let value = match exp {
-                Rule::Exp(_) => eval_exp(l, exp)?,
-                Rule::BinOp(_, _, _) => eval_binop(l, exp)?,
-                Rule::UnOp(_, _) => eval_unop(l, exp)?,
-                _ => {
-                    return Err(l.error("unop exp invalid"));
-                }
-            };
-
+            let value = eval_operand(l, exp, "unop exp invalid")?;
             l.process_unop(c, value)
         }
         _ => Err(l.error("unop invalid")),
@@ -93,18 +169,69 @@ pub fn eval_unop(l: &mut LuaState, unop: &Rule) -> Result<Value, LuaError> {
 
 pub fn eval_get_var(l: &mut LuaState, exp: &Rule) -> Result<Value, LuaError> {
     let var = is_exact_rule1!(Rule::Var, exp)?;
-    let name = is_exact_rule1!(Rule::Symbol, var.as_ref())?;
+    if let Rule::Index(prefix, key) = var.as_ref() {
+        return eval_index(l, prefix, key);
+    }
+    let (name, slot) = is_exact_rule2!(Rule::Name, var.as_ref())?;
 
-    l.get_local(name)
-        .or(l.get_global(name))
-        .ok_or(l.error("Variable not found"))
+    // Unknown names resolve as globals, and unset globals read as nil
+    match l.get_local(name) {
+        Some(v) => Ok(v),
+        None => l.read_global_slot(name, slot),
+    }
+}
+
+// `prefix.name` or `prefix[exp]` reads the field, looking through
+// __index too
+fn eval_index(l: &mut LuaState, prefix: &Rule, key: &Rule) -> Result<Value, LuaError> {
+    let obj = eval_prefixexp(l, prefix)?;
+    let key = eval_key(l, key)?;
+    if !matches!(obj, Value::Table(_)) && l.metatable_of(&obj).is_none() {
+        return Err(l.error(format!(
+            "attempt to index a {} value{}",
+            obj.type_name(),
+            describe_var(l, prefix)
+        )));
+    }
+    l.index(&obj, key)
+}
+
+// The name after a dot is a string key
+fn eval_key(l: &mut LuaState, key: &Rule) -> Result<Value, LuaError> {
+    match key {
+        Rule::Symbol(name) => Ok(Value::LuaString(name.as_str().into())),
+        key => eval_exp(l, key),
+    }
+}
+
+// Where an indexed value came from, for error messages
+fn describe_var(l: &LuaState, prefix: &Rule) -> String {
+    let var = match prefix {
+        Rule::Prefixexp(p) => match p.as_ref() {
+            Rule::Var(var) => var.as_ref(),
+            _ => return String::new(),
+        },
+        _ => return String::new(),
+    };
+    match var {
+        Rule::Name(name, _) if l.get_local(name).is_some() => format!(" (local '{}')", name),
+        Rule::Name(name, _) => format!(" (global '{}')", name),
+        Rule::Index(_, key) => match key.as_ref() {
+            Rule::Symbol(name) => format!(" (field '{}')", name),
+            _ => String::new(),
+        },
+        _ => String::new(),
+    }
 }
 
 pub fn eval_prefixexp(l: &mut LuaState, pexp: &Rule) -> Result<Value, LuaError> {
-    let value: &Box<Rule> = is_exact_rule1!(Rule::Prefixexp, pexp)?;
-    let value = value.as_ref();
+    let value = is_exact_rule1!(Rule::Prefixexp, pexp)?.as_ref();
     match value {
         Rule::FunctionCall(_, _) => eval_funcall(l, value),
+        Rule::MethodCall(_, _, _) => {
+            let ret = eval_methodcall(l, value)?;
+            Ok(ret.into_iter().next().unwrap_or(Value::Nil))
+        }
         Rule::Var(_) => eval_get_var(l, value),
         Rule::Exp(_) => eval_exp(l, value),
         _ => Err(LuaError {
@@ -113,22 +240,51 @@ pub fn eval_prefixexp(l: &mut LuaState, pexp: &Rule) -> Result<Value, LuaError>
     }
 }
 
+// The call an expression consists of, if it is an unparenthesized one
+fn call_in_exp(exp: &Rule) -> Option<&Rule> {
+    match exp {
+        Rule::Exp(e) => match e.as_ref() {
+            Rule::Prefixexp(p) => match p.as_ref() {
+                Rule::FunctionCall(_, _) | Rule::MethodCall(_, _, _) => Some(p.as_ref()),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
 pub fn eval_tableconst(l: &mut LuaState, exp: &Rule) -> Result<Value, LuaError> {
-    let list: &Box<Rule> = is_exact_rule1!(Rule::TableConst, exp)?;
-    let list: &Vec<Box<Rule>> = is_exact_rule1!(Rule::FieldList, list.as_ref())?;
+    let list = is_exact_rule1!(Rule::TableConst, exp)?.as_ref();
+    let list: &Vec<Box<Rule>> = is_exact_rule1!(Rule::FieldList, list)?;
 
     let v = Value::newtable();
     let t = v.ensure_table()?;
 
-    for field in list.iter() {
+    // Positional fields are numbered 1, 2, ... regardless of any
+    // `[k] = v` fields, and are stored last so they win on the same
+    // index, as in Lua: {[1] = 10, 20} has t[1] == 20
+    let mut positional = vec![];
+    for (i, field) in list.iter().enumerate() {
         let (key, value) = is_exact_rule2!(Rule::Field, field.as_ref())?;
         match key.as_ref() {
-            Rule::Symbol(_n) => {
-                unimplemented!("TODO: table");
+            Rule::Symbol(name) => {
+                let value = eval_exp(l, value.as_ref())?;
+                t.set_str(name.as_str(), value);
+            }
+            // a call as the last field fills in all of its results
+            Rule::Nop if i + 1 == list.len() && call_in_exp(value).is_some() => {
+                let values = eval_funcall_multi(l, call_in_exp(value).unwrap())?;
+                positional.extend(values);
             }
             Rule::Nop => {
-                let mut t = t.vec.borrow_mut();
-                t.push(eval_exp(l, value.as_ref())?);
+                let value = eval_exp(l, value.as_ref())?;
+                positional.push(value);
+            }
+            Rule::Exp(_) => {
+                let key = eval_exp(l, key.as_ref())?;
+                let value = eval_exp(l, value.as_ref())?;
+                t.set(key, value)?;
             }
             _ => {
                 return Err(LuaError {
@@ -137,50 +293,111 @@ pub fn eval_tableconst(l: &mut LuaState, exp: &Rule) -> Result<Value, LuaError>
             }
         }
     }
+    for (i, value) in positional.into_iter().enumerate() {
+        t.set_int(i as i64 + 1, value)?;
+    }
 
     Ok(v)
 }
 
+fn eval_callee(l: &mut LuaState, name: &str, slot: &GlobalSlot) -> Result<Value, LuaError> {
+    let (func, kind) = match l.get_local(name) {
+        Some(v) => (v, "local"),
+        None => (l.read_global_slot(name, slot)?, "global"),
+    };
+    match func {
+        Value::Function(_) => Ok(func),
+        _ => Err(l.error(format!(
+            "attempt to call a {} value ({} '{}')",
+            func.type_name(),
+            kind,
+            name
+        ))),
+    }
+}
+
+// A call target is a name, or any prefix expression such as
+// `(getfn())` or the result of another call
+fn eval_call_target(l: &mut LuaState, callee: &Rule) -> Result<Value, LuaError> {
+    if let Rule::Name(name, slot) = callee {
+        return eval_callee(l, name, slot);
+    }
+    let func = eval_prefixexp(l, callee)?;
+    match func {
+        Value::Function(_) => Ok(func),
+        _ => Err(l.error(format!("attempt to call a {} value", func.type_name()))),
+    }
+}
+
 pub fn eval_funcall(l: &mut LuaState, fc: &Rule) -> Result<Value, LuaError> {
-    let (name, args) = is_exact_rule2!(Rule::FunctionCall, fc)?;
-    let name = is_exact_rule1!(Rule::Symbol, name.as_ref())?;
-    let exp = is_exact_rule1!(Rule::Args, args.as_ref())?.as_ref();
-    match exp {
-        Rule::Exp(_) => {
-            let arg1v = eval_exp(l, exp)?;
-            debug!("get param {} {:?}", name, &arg1v);
-            let ret = l.global_funcall1(name, arg1v)?;
-            Ok(ret)
-        }
-        Rule::Nop => {
-            let ret = l.global_funcall1(name, Value::Nil)?;
-            Ok(ret)
+    let (callee, args) = is_exact_rule2!(Rule::FunctionCall, fc)?;
+    let func = eval_call_target(l, callee.as_ref())?;
+    let params = eval_args(l, args.as_ref())?;
+
+    let ret = l.funcall(func, params)?;
+    Ok(ret.into_iter().next().unwrap_or(Value::Nil))
+}
+
+fn eval_args(l: &mut LuaState, args: &Rule) -> Result<Vec<Value>, LuaError> {
+    let exps = is_exact_rule1!(Rule::Args, args)?;
+    eval_exps(l, exps)
+}
+
+// Expressions left to right; a call as the last one passes on all of
+// its results, as in `f(1, g())` or `return 1, g()`
+pub fn eval_exps(l: &mut LuaState, exps: &[Box<Rule>]) -> Result<Vec<Value>, LuaError> {
+    let mut values = Vec::with_capacity(exps.len());
+    for (i, exp) in exps.iter().enumerate() {
+        match call_in_exp(exp) {
+            Some(fc) if i + 1 == exps.len() => values.extend(eval_funcall_multi(l, fc)?),
+            _ => values.push(eval_exp(l, exp.as_ref())?),
         }
-        _ => Err(l.error("Invalid rule")),
     }
+    Ok(values)
 }
 
-pub fn eval_funcall_multi(l: &mut LuaState, fc: &Rule) -> Result<Vec<Value>, LuaError> {
-    let (name, args) = is_exact_rule2!(Rule::FunctionCall, fc)?;
+// `recv:name(args)` looks name up through recv, then passes
+// recv itself as the first argument
+pub fn eval_methodcall(l: &mut LuaState, mc: &Rule) -> Result<Vec<Value>, LuaError> {
+    let (recv, name, args) = match mc {
+        Rule::MethodCall(recv, name, args) => (recv, name, args),
+        _ => return Err(l.error(format!("Expected MethodCall, got {:?}", mc))),
+    };
     let name = is_exact_rule1!(Rule::Symbol, name.as_ref())?;
-    let exp = is_exact_rule1!(Rule::Args, args.as_ref())?.as_ref();
+    let recv = eval_prefixexp(l, recv.as_ref())?;
+    let method = l.index_str(&recv, name)?;
+    if !matches!(method, Value::Function(_)) {
+        return Err(l.error(format!(
+            "attempt to call a {} value (method '{}')",
+            method.type_name(),
+            name
+        )));
+    }
 
-    let func = l
-        .get_global(name)
-        .ok_or(l.error("Please specify func name"))?;
+    let mut params = vec![recv];
+    params.extend(eval_args(l, args.as_ref())?);
+    l.funcall(method, params)
+}
 
-    match exp {
-        Rule::Exp(_) => {
-            let arg1v = eval_exp(l, exp)?;
-            let ret = l.funcall(func, vec![arg1v])?;
-            Ok(ret)
+// A call statement may also be a method call, as in `obj:m()`
+fn eval_call_stat(l: &mut LuaState, fc: &Rule) -> Result<Value, LuaError> {
+    match fc {
+        Rule::MethodCall(_, _, _) => {
+            let ret = eval_methodcall(l, fc)?;
+            Ok(ret.into_iter().next().unwrap_or(Value::Nil))
         }
-        Rule::Nop => {
-            let ret = l.funcall(func, vec![])?;
-            Ok(ret)
-        }
-        _ => Err(l.error("Invalid rule")),
+        _ => eval_funcall(l, fc),
+    }
+}
+
+pub fn eval_funcall_multi(l: &mut LuaState, fc: &Rule) -> Result<Vec<Value>, LuaError> {
+    if let Rule::MethodCall(_, _, _) = fc {
+        return eval_methodcall(l, fc);
     }
+    let (callee, args) = is_exact_rule2!(Rule::FunctionCall, fc)?;
+    let func = eval_call_target(l, callee.as_ref())?;
+    let params = eval_args(l, args.as_ref())?;
+    l.funcall(func, params)
 }
 
 pub fn eval_ifthen(l: &mut LuaState, stat: &Rule) -> Result<Value, LuaError> {
@@ -198,18 +415,18 @@ pub fn eval_ifthen(l: &mut LuaState, stat: &Rule) -> Result<Value, LuaError> {
                     }
                     Value::Bool(b) => {
                         if b {
-                            return eval_block(l, &blocks[i - 1]);
+                            return eval_scoped_block(l, &blocks[i - 1]);
                         } else {
                             continue;
                         }
                     }
                     _ => {
-                        return eval_block(l, &blocks[i - 1]);
+                        return eval_scoped_block(l, &blocks[i - 1]);
                     }
                 }
             }
             Rule::Nop => {
-                return eval_block(l, &blocks[i - 1]);
+                return eval_scoped_block(l, &blocks[i - 1]);
             }
             _ => return Err(l.error("Invalid rule")),
         }
@@ -217,52 +434,133 @@ pub fn eval_ifthen(l: &mut LuaState, stat: &Rule) -> Result<Value, LuaError> {
     Ok(Value::Nil)
 }
 
-pub fn process_funcname(_l: &mut LuaState, fname: &Rule) -> Result<String, LuaError> {
-    let name = is_exact_rule1!(Rule::FuncName, fname)?;
-    let name = is_exact_rule1!(Rule::Symbol, name.as_ref())?;
-    Ok(name.to_string())
+pub fn symbol_names(rule: &Rule) -> Result<Vec<String>, LuaError> {
+    match rule {
+        Rule::Symbol(name) => Ok(vec![name.to_string()]),
+        Rule::SymbolList(list) => list
+            .iter()
+            .map(|sym| match sym.as_ref() {
+                Rule::Symbol(name) => Ok(name.to_string()),
+                _ => Err(LuaError {
+                    message: format!("Expected a name in name list: {:?}", sym),
+                }),
+            })
+            .collect(),
+        _ => Err(LuaError {
+            message: format!("Expected a name or name list: {:?}", rule),
+        }),
+    }
 }
 
-pub fn process_params(_l: &mut LuaState, params: &Rule) -> Result<Vec<String>, LuaError> {
-    let name = is_exact_rule1!(Rule::ParList1, params)?;
-    let name = is_exact_rule1!(Rule::Symbol, name.as_ref())?;
-    Ok(vec![name.to_string()])
+// The names of `a.b.c:m`, and m when it is a method
+pub fn process_funcname(
+    _l: &mut LuaState,
+    fname: &Rule,
+) -> Result<(Vec<String>, Option<String>), LuaError> {
+    let (path, method) = match fname {
+        Rule::FuncName(path, method) => (path, method),
+        _ => {
+            return Err(LuaError {
+                message: format!("Expected FuncName, got {:?}", fname),
+            })
+        }
+    };
+    let method = match method.as_deref() {
+        Some(m) => Some(is_exact_rule1!(Rule::Symbol, m)?.to_string()),
+        None => None,
+    };
+    Ok((symbol_names(path)?, method))
+}
+
+// `function a.b.c()` stores into the field c of a.b, and the method
+// form `function a.b:c()` takes self as a hidden first parameter
+fn declare_field_function(
+    l: &mut LuaState,
+    path: &[String],
+    method: Option<String>,
+    mut params: Vec<String>,
+    block: &Rule,
+) -> Result<(), LuaError> {
+    let mut keys: Vec<&str> = path[1..].iter().map(|k| k.as_str()).collect();
+    if let Some(m) = &method {
+        keys.push(m);
+        params.insert(0, "self".to_string());
+    }
+    let mut target = match l.get_local(&path[0]) {
+        Some(v) => v,
+        None => l.read_global(&path[0])?,
+    };
+    let (last, keys) = keys.split_last().unwrap();
+    for key in keys {
+        target = l.index_str(&target, key)?;
+    }
+    match target {
+        Value::Table(t) => {
+            t.set_str(
+                *last,
+                Value::Function(LuaFunction::from_code(params, block)),
+            );
+            Ok(())
+        }
+        _ => Err(l.error(format!("attempt to index a {} value", target.type_name()))),
+    }
 }
 
+pub fn process_params(l: &mut LuaState, params: &Rule) -> Result<Vec<String>, LuaError> {
+    match params {
+        Rule::ParList1(names) => symbol_names(names.as_ref())
+            .map_err(|e| l.error(format!("Invalid function parameters: {}", e.message))),
+        _ => Err(l.error(format!(
+            "Invalid function parameters: expected a parameter list, got {:?}",
+            params
+        ))),
+    }
+}
+
+// The parameter names and the block of a function. `function f()`
+// has no ParList1 at all, which is zero parameters
 pub fn eval_funcbody<'a>(
     l: &mut LuaState,
     fb: &'a Rule,
 ) -> Result<(Vec<String>, &'a Rule), LuaError> {
-    if let Rule::FuncBody(params, body) = fb {
-        let body = body.as_ref();
-        if let Rule::Block(_) = body {
-            let params = if params.is_some() {
-                process_params(l, params.as_ref().unwrap())?
-            } else {
-                vec![]
-            };
-            return Ok((params, body));
-        }
+    let (params, body) = match fb {
+        Rule::FuncBody(params, body) => (params, body.as_ref()),
+        _ => return Err(l.error(format!("Invalid composite of funcbody: {:?}", fb))),
+    };
+    if !matches!(body, Rule::Block(_)) {
+        return Err(l.error(format!(
+            "Invalid function body: expected a block, got {:?}",
+            body
+        )));
     }
-    Err(l.error("Invalid composite of funcbody"))
+    let params = match params {
+        Some(params) => process_params(l, params)?,
+        None => vec![],
+    };
+    Ok((params, body))
 }
 
 pub fn eval_chunk(l: &mut LuaState, chunk: &Rule) -> Result<Value, LuaError> {
     match chunk {
-        Rule::Chunk(stats, last) => {
-            for stat in stats.into_iter() {
-                let ret = eval_stat(l, stat.as_ref())?;
-                if l.to_return() {
+        Rule::Chunk(stats, last, lines) => {
+            let mut i = 0;
+            while i < stats.len() {
+                l.line = lines.get(i).copied().unwrap_or(0);
+                let ret = eval_stat(l, stats[i].as_ref())?;
+                if l.to_return() || l.breaking() {
                     return Ok(ret);
                 }
+                if l.pending_goto().is_some() {
+                    match goto_target(l, stats)? {
+                        Some(j) => i = j,
+                        None => return Ok(Value::Nil),
+                    }
+                }
+                i += 1;
             }
             if let Some(stat) = last {
-                let exp = is_exact_rule1!(Rule::LastStat, stat.as_ref())?;
-                let ret = eval_exp(l, exp.as_ref())?;
-                if let Some(_) = l.current_frame() {
-                    l.set_to_return(true);
-                }
-                Ok(ret)
+                l.line = lines.last().copied().unwrap_or(0);
+                eval_return(l, stat)
             } else {
                 Ok(Value::Nil)
             }
@@ -271,74 +569,119 @@ pub fn eval_chunk(l: &mut LuaState, chunk: &Rule) -> Result<Value, LuaError> {
     }
 }
 
+// All the values go to the function being returned from; the first
+// one is also the value of the chunk
+fn eval_return(l: &mut LuaState, stat: &Rule) -> Result<Value, LuaError> {
+    let exps = is_exact_rule1!(Rule::LastStat, stat)?;
+    let exps = is_exact_rule1!(Rule::ExpList, exps.as_ref())?;
+    let values = eval_exps(l, exps)?;
+    let ret = values.first().cloned().unwrap_or(Value::Nil);
+    if l.current_frame().is_some() {
+        l.set_to_return(true);
+        l.set_return_values(values);
+    }
+    Ok(ret)
+}
+
+pub enum AssignTarget<'a> {
+    Name(&'a str, &'a GlobalSlot),
+    Index(Rc<LuaTable>, Value),
+}
+
+pub fn eval_assign_target<'a>(
+    l: &mut LuaState,
+    var: &'a Rule,
+) -> Result<AssignTarget<'a>, LuaError> {
+    let var = is_exact_rule1!(Rule::Var, var)?;
+    if let Rule::Index(prefix, key) = var.as_ref() {
+        let obj = eval_prefixexp(l, prefix)?;
+        let key = eval_key(l, key)?;
+        return match obj {
+            Value::Table(t) => Ok(AssignTarget::Index(t, key)),
+            obj => Err(l.error(format!(
+                "attempt to index a {} value{}",
+                obj.type_name(),
+                describe_var(l, prefix)
+            ))),
+        };
+    }
+    let (name, slot) = is_exact_rule2!(Rule::Name, var.as_ref())?;
+    Ok(AssignTarget::Name(name, slot))
+}
+
+pub fn eval_explist(l: &mut LuaState, explist: &Rule) -> Result<Vec<Value>, LuaError> {
+    let exps = is_exact_rule1!(Rule::ExpList, explist)?;
+    eval_exps(l, exps)
+}
+
+// All targets and values are evaluated before any store happens,
+// so that `a, b = b, a` swaps
+pub fn eval_assign(l: &mut LuaState, varlist: &Rule, explist: &Rule) -> Result<(), LuaError> {
+    let vars = is_exact_rule1!(Rule::VarList, varlist)?;
+    let targets = vars
+        .iter()
+        .map(|var| eval_assign_target(l, var.as_ref()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut values = eval_explist(l, explist)?.into_iter();
+
+    for target in targets.into_iter() {
+        let value = values.next().unwrap_or(Value::Nil);
+        match target {
+            AssignTarget::Name(name, slot) => {
+                if l.has_local_name(name) {
+                    l.set_local(name, value);
+                } else {
+                    l.write_global_slot(name, slot, value)?;
+                }
+            }
+            AssignTarget::Index(t, key) => t.set(key, value)?,
+        }
+    }
+    Ok(())
+}
+
 pub fn eval_stat(l: &mut LuaState, stat: &Rule) -> Result<Value, LuaError> {
+    l.count_step()?;
     match stat {
-        Rule::Stat(kind, a, b, c, _d, _e) => {
+        Rule::Stat(kind, a, b, c, d, e) => {
             let v = match kind {
                 StatKind::Sep => Value::Nil,
                 StatKind::VarAssign => {
-                    let var = is_exact_rule1!(Rule::Var, a.as_ref().unwrap().as_ref())?;
-                    let name = is_exact_rule1!(Rule::Symbol, var.as_ref())?;
-                    let value = eval_exp(l, b.as_ref().unwrap())?;
-
-                    if l.has_local_name(name) {
-                        l.assign_local(name, value);
-                    } else {
-                        l.assign_global(name, value);
-                    }
+                    eval_assign(l, a.as_ref().unwrap(), b.as_ref().unwrap())?;
                     Value::Nil
                 }
-                StatKind::FunctionCall => eval_funcall(l, a.as_ref().unwrap())?,
+                StatKind::FunctionCall => eval_call_stat(l, a.as_ref().unwrap())?,
                 StatKind::DeclareFunction => {
-                    let name = process_funcname(l, a.as_ref().unwrap())?;
-                    let (params, block) = eval_funcbody(l, b.as_ref().unwrap())?;
-
-                    l.register_global_code(name, params, block);
+                    eval_declare_function(l, a.as_ref().unwrap(), b.as_ref().unwrap())?;
                     Value::Nil
                 }
                 StatKind::IfThen => eval_ifthen(l, a.as_ref().unwrap())?,
+                StatKind::Do => eval_scoped_block(l, a.as_ref().unwrap())?,
                 StatKind::LocalVar => {
+                    eval_local(l, a.as_ref().unwrap(), b.as_deref())?;
+                    Value::Nil
+                }
+                StatKind::ForIn => eval_generic_for(
+                    l,
+                    a.as_ref().unwrap(),
+                    b.as_ref().unwrap(),
+                    c.as_ref().unwrap(),
+                )?,
+                StatKind::For => {
                     let name = is_exact_rule1!(Rule::Symbol, a.as_ref().unwrap().as_ref())?;
-                    let exp = b.as_ref().unwrap().as_ref();
-                    let value = match exp {
-                        Rule::Exp(_) => eval_exp(l, exp)?,
-                        _ => {
-                            return Err(l.error("Expected exp"));
-                        }
-                    };
-                    if l.current_frame().is_some() {
-                        l.assign_local(name, value);
-                    } else {
-                        return Err(l.error("Expected in function def"));
-                    }
+                    let step = d.as_deref().unwrap_or(&Rule::Nop);
+                    let exps = [b.as_ref().unwrap().as_ref(), c.as_ref().unwrap(), step];
+                    eval_numeric_for(l, name, exps, e.as_ref().unwrap())?
+                }
+                StatKind::Repeat => eval_repeat(l, a.as_ref().unwrap(), b.as_ref().unwrap())?,
+                StatKind::GoTo => {
+                    let label = is_exact_rule1!(Rule::Symbol, a.as_ref().unwrap().as_ref())?;
+                    l.set_goto(Some(label.to_string()));
                     Value::Nil
                 }
-                StatKind::ForIn => {
-                    let vars = is_exact_rule1!(Rule::SymbolList, a.as_ref().unwrap().as_ref())?;
-                    let funex = is_exact_rule1!(Rule::Exp, b.as_ref().unwrap().as_ref())?;
-                    let fc = is_exact_rule1!(Rule::Prefixexp, funex.as_ref())?;
-                    let mut loop_params = eval_funcall_multi(l, fc)?;
-                    let mut key = loop_params.pop().unwrap();
-                    let collction = loop_params.pop().unwrap();
-                    let next = loop_params.pop().unwrap();
-                    loop {
-                        let mut values =
-                            l.funcall(next.clone(), vec![collction.clone(), key.clone()])?;
-                        match values[0] {
-                            Value::Nil => {
-                                break;
-                            }
-                            _ => {}
-                        }
-                        key = values[0].to_owned();
-                        let oldtop = l.start_block_raw();
-                        for name in vars.iter().rev() {
-                            let name = is_exact_rule1!(Rule::Symbol, name.as_ref())?;
-                            l.assign_local(name, values.pop().unwrap());
-                        }
-                        eval_block(l, c.as_ref().unwrap().as_ref())?;
-                        l.end_block_raw(oldtop)?;
-                    }
+                StatKind::Label => Value::Nil,
+                StatKind::Break => {
+                    l.set_breaking()?;
                     Value::Nil
                 }
                 _ => unimplemented!("{:?}: Pull request is welcomed!", kind),
@@ -349,7 +692,263 @@ pub fn eval_stat(l: &mut LuaState, stat: &Rule) -> Result<Value, LuaError> {
     }
 }
 
+// The statements with more to do than fit in eval_stat, whose frame
+// is on the path of every call
+
+fn eval_declare_function(l: &mut LuaState, name: &Rule, body: &Rule) -> Result<(), LuaError> {
+    let (path, method) = process_funcname(l, name)?;
+    let (params, block) = eval_funcbody(l, body)?;
+
+    if path.len() == 1 && method.is_none() {
+        l.register_global_code(&path[0], params, block);
+        Ok(())
+    } else {
+        declare_field_function(l, &path, method, params, block)
+    }
+}
+
+// `local a, b = 1`: names past the values are nil, and all the values
+// are evaluated before any name is declared
+fn eval_local(l: &mut LuaState, names: &Rule, exps: Option<&Rule>) -> Result<(), LuaError> {
+    let names = symbol_names(names)?;
+    let mut values = match exps {
+        Some(exps) => eval_explist(l, exps)?,
+        None => vec![],
+    };
+    values.resize(names.len(), Value::Nil);
+    if l.current_frame().is_none() {
+        return Err(l.error("Expected in function def"));
+    }
+    for (name, value) in names.iter().zip(values) {
+        l.assign_local(name, value)?;
+    }
+    Ok(())
+}
+
+fn eval_generic_for(
+    l: &mut LuaState,
+    names: &Rule,
+    exp: &Rule,
+    body: &Rule,
+) -> Result<Value, LuaError> {
+    let names = symbol_names(names)?;
+    let mut loop_params = match call_in_exp(exp) {
+        Some(fc) => eval_funcall_multi(l, fc)?,
+        None => vec![eval_exp(l, exp)?],
+    };
+    // iterator, state and control: a bare iterator
+    // such as gmatch's leaves the other two nil
+    loop_params.resize(3, Value::Nil);
+    let mut key = loop_params.pop().unwrap();
+    let collction = loop_params.pop().unwrap();
+    let next = loop_params.pop().unwrap();
+    loop {
+        let values = l.funcall(next.clone(), vec![collction.clone(), key.clone()])?;
+        if let Some(Value::Nil) | None = values.first() {
+            break;
+        }
+        key = values[0].to_owned();
+        let locals = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name, values.get(i).cloned().unwrap_or(Value::Nil)));
+        match eval_loop_body(l, body, locals)? {
+            Flow::Next => {}
+            Flow::Break => break,
+            Flow::Return(ret) => return Ok(ret),
+        }
+    }
+    Ok(Value::Nil)
+}
+
 pub fn eval_block(l: &mut LuaState, block: &Rule) -> Result<Value, LuaError> {
     let chunk = is_exact_rule1!(Rule::Block, block)?;
     eval_chunk(l, chunk)
 }
+
+// A goto resumes after its label when this block has it, or leaves
+// the block to look further out
+fn goto_target(l: &mut LuaState, stats: &[Box<Rule>]) -> Result<Option<usize>, LuaError> {
+    let label = l.pending_goto().unwrap_or_default();
+    match find_label(stats, label) {
+        Some(j) => {
+            l.set_goto(None);
+            Ok(Some(j))
+        }
+        None if l.current_frame().is_some_and(|f| !f.is_block) => {
+            let msg = format!("no visible label '{}' for goto", label);
+            Err(l.error(msg))
+        }
+        None => Ok(None),
+    }
+}
+
+fn find_label(stats: &[Box<Rule>], label: &str) -> Option<usize> {
+    stats.iter().position(|stat| match stat.as_ref() {
+        Rule::Stat(StatKind::Label, Some(name), _, _, _, _) => {
+            matches!(name.as_ref(), Rule::Symbol(name) if name == label)
+        }
+        _ => false,
+    })
+}
+
+// How a run of a loop body ended
+pub enum Flow {
+    Next,
+    Break,
+    // a `return`, which ends the loop and the enclosing function
+    Return(Value),
+}
+
+// One iteration of a loop, with the loop variables as fresh locals
+pub fn eval_loop_body<'a>(
+    l: &mut LuaState,
+    body: &Rule,
+    locals: impl Iterator<Item = (&'a String, Value)>,
+) -> Result<Flow, LuaError> {
+    let oldtop = l.start_loop_raw();
+    for (name, value) in locals {
+        l.assign_local(name, value)?;
+    }
+    let ret = eval_block(l, body)?;
+    let flow = loop_flow(l, ret);
+    l.end_block_raw(oldtop)?;
+    Ok(flow)
+}
+
+fn loop_flow(l: &mut LuaState, ret: Value) -> Flow {
+    if l.to_return() {
+        Flow::Return(ret)
+    } else if l.breaking() || l.pending_goto().is_some() {
+        Flow::Break
+    } else {
+        Flow::Next
+    }
+}
+
+// The body runs at least once, and the `until` condition is tested
+// while its locals are still in scope
+fn eval_repeat(l: &mut LuaState, body: &Rule, cond: &Rule) -> Result<Value, LuaError> {
+    loop {
+        let oldtop = l.start_loop_raw();
+        let ret = eval_block(l, body)?;
+        let flow = loop_flow(l, ret);
+        let done = match flow {
+            Flow::Next => eval_exp(l, cond)?.is_truthy(),
+            _ => true,
+        };
+        l.end_block_raw(oldtop)?;
+        match flow {
+            Flow::Return(ret) => return Ok(ret),
+            _ if done => return Ok(Value::Nil),
+            _ => {}
+        }
+    }
+}
+
+// `for name = start, limit, step`: an integer loop when start and
+// step are integers, a float loop otherwise, as in Lua 5.4
+fn eval_numeric_for(
+    l: &mut LuaState,
+    name: &String,
+    exps: [&Rule; 3],
+    body: &Rule,
+) -> Result<Value, LuaError> {
+    let [start, limit, step] = exps;
+    let start = eval_exp(l, start)?;
+    let limit = eval_exp(l, limit)?;
+    let step = match step {
+        Rule::Nop => Value::Number(1),
+        step => eval_exp(l, step)?,
+    };
+    let check = |l: &LuaState, v: &Value, what: &str| match v {
+        Value::Number(_) | Value::Float(_) => Ok(()),
+        _ => Err(l.error(format!("'for' {} must be a number", what))),
+    };
+    check(l, &start, "initial value")?;
+    check(l, &limit, "limit")?;
+    check(l, &step, "step")?;
+
+    if let (Value::Number(start), Value::Number(step)) = (&start, &step) {
+        let (start, step) = (*start, *step);
+        if step == 0 {
+            return Err(l.error("'for' step is zero"));
+        }
+        let limit = match for_int_limit(&limit, step) {
+            Some(limit) => limit,
+            None => return Ok(Value::Nil),
+        };
+        if (step > 0 && start > limit) || (step < 0 && start < limit) {
+            return Ok(Value::Nil);
+        }
+        // the iteration count is computed up front, so the control
+        // variable never overflows near the ends of the i64 range
+        let count = if step > 0 {
+            (limit as u64).wrapping_sub(start as u64) / step as u64
+        } else {
+            (start as u64).wrapping_sub(limit as u64) / (step as u64).wrapping_neg()
+        };
+        let mut i = start;
+        for n in 0..=count {
+            if n > 0 {
+                i = i.wrapping_add(step);
+            }
+            let locals = std::iter::once((name, Value::Number(i)));
+            match eval_loop_body(l, body, locals)? {
+                Flow::Next => {}
+                Flow::Break => break,
+                Flow::Return(ret) => return Ok(ret),
+            }
+        }
+        return Ok(Value::Nil);
+    }
+
+    let (start, limit, step) = (
+        start.to_float().unwrap(),
+        limit.to_float().unwrap(),
+        step.to_float().unwrap(),
+    );
+    if step == 0.0 {
+        return Err(l.error("'for' step is zero"));
+    }
+    let mut x = start;
+    while (step > 0.0 && x <= limit) || (step < 0.0 && x >= limit) {
+        let locals = std::iter::once((name, Value::Float(x)));
+        match eval_loop_body(l, body, locals)? {
+            Flow::Next => {}
+            Flow::Break => break,
+            Flow::Return(ret) => return Ok(ret),
+        }
+        x += step;
+    }
+    Ok(Value::Nil)
+}
+
+// The integer limit of an integer loop: a float limit is floored, or
+// ceiled when counting down, and clipped to the i64 range. None when
+// the loop cannot run at all
+fn for_int_limit(limit: &Value, step: i64) -> Option<i64> {
+    let f = match limit {
+        Value::Number(n) => return Some(*n),
+        Value::Float(f) => *f,
+        _ => return None,
+    };
+    if f.is_nan() {
+        return None;
+    }
+    let f = if step < 0 { f.ceil() } else { f.floor() };
+    match float_to_int(f) {
+        Some(n) => Some(n),
+        // beyond the range: only the loop heading towards it runs
+        None if f > 0.0 => (step > 0).then_some(i64::MAX),
+        None => (step < 0).then_some(i64::MIN),
+    }
+}
+
+// Evaluates the block in its own scope, so its locals end with it
+pub fn eval_scoped_block(l: &mut LuaState, block: &Rule) -> Result<Value, LuaError> {
+    let oldtop = l.start_block_raw();
+    let ret = eval_block(l, block)?;
+    l.end_block_raw(oldtop)?;
+    Ok(ret)
+}
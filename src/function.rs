@@ -1,16 +1,18 @@
 // use log::*;
-use std::collections::HashMap;
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
 use crate::eval::eval_block;
 use crate::parser::Rule;
 use crate::state::{LuaError, LuaState};
+use crate::value::Value;
 pub type LuaFn = fn(&mut LuaState) -> Result<i32, LuaError>;
+pub type LuaClosure = Rc<RefCell<dyn FnMut(&mut LuaState) -> Result<i32, LuaError>>>;
 
 #[derive(Clone)]
 pub struct FunctionProto {
     pub parameters: Vec<String>,
     pub params_nr: i32,
-    pub code: Box<Rule>,
+    pub code: Rc<Rule>,
 }
 
 #[derive(Clone)]
@@ -19,14 +21,24 @@ pub struct CallFrame {
     pub to_return: bool,
     pub args_nr: usize,
     pub ret_nr: usize,
-    pub local_base: usize,
+    pub base: usize,
+    pub is_block: bool,
+    // the body of a loop, where a `break` stops
+    pub is_loop: bool,
+    pub breaking: bool,
+    // a `goto` still looking for its label
+    pub goto_label: Option<String>,
+    // what a `return` in the function body handed back
+    pub ret_values: Vec<Value>,
 }
 
 #[derive(Clone)]
 pub struct LuaFunction {
+    #[allow(dead_code)]
     is_global: bool,
     pub proto: FunctionProto,
     pub luafn: Option<LuaFn>,
+    pub closure: Option<LuaClosure>,
 }
 
 impl LuaFunction {
@@ -34,13 +46,32 @@ impl LuaFunction {
         let proto = FunctionProto {
             params_nr: -1,
             parameters: vec![],
-            code: Box::new(Rule::Nop),
+            code: Rc::new(Rule::Nop),
         };
 
         LuaFunction {
             is_global: true,
-            proto: proto,
+            proto,
             luafn: Some(func),
+            closure: None,
+        }
+    }
+
+    pub fn from_closure<F>(func: F) -> Self
+    where
+        F: FnMut(&mut LuaState) -> Result<i32, LuaError> + 'static,
+    {
+        let proto = FunctionProto {
+            params_nr: -1,
+            parameters: vec![],
+            code: Rc::new(Rule::Nop),
+        };
+
+        LuaFunction {
+            is_global: true,
+            proto,
+            luafn: None,
+            closure: Some(Rc::new(RefCell::new(func))),
         }
     }
 
@@ -48,56 +79,91 @@ impl LuaFunction {
         let proto = FunctionProto {
             params_nr: params.len() as i32,
             parameters: params,
-            code: Box::new(block.to_owned()),
+            code: Rc::new(block.to_owned()),
         };
 
         LuaFunction {
             is_global: true,
-            proto: proto,
+            proto,
             luafn: None,
+            closure: None,
+        }
+    }
+
+    // Identity of the function: the builtin, the closure or the
+    // body it runs. Copies of one function value share it
+    pub fn addr(&self) -> usize {
+        if let Some(luafn) = self.luafn {
+            luafn as usize
+        } else if let Some(closure) = &self.closure {
+            Rc::as_ptr(closure) as *const () as usize
+        } else {
+            Rc::as_ptr(&self.proto.code) as usize
         }
     }
 
     pub fn do_call(&self, args: (&mut LuaState,)) -> Result<i32, LuaError> {
         let l = args.0;
 
+        // Arguments are the topmost params_nr values; the call frame
+        // starts at the first of them
+        let args_nr = self.proto.params_nr as usize;
+        let mut frame = CallFrame {
+            args_nr,
+            ret_nr: 1,
+            env: Default::default(),
+            to_return: false,
+            base: l.reg.top - args_nr,
+            is_block: false,
+            is_loop: false,
+            breaking: false,
+            goto_label: None,
+            ret_values: vec![],
+        };
+
         if let Some(luafn) = self.luafn {
             // Use fn_traits in the future
-            let args_nr = self.proto.params_nr as usize;
-            let frame = CallFrame {
-                args_nr: args_nr,
-                ret_nr: 1,
-                env: Default::default(),
-                to_return: false,
-                local_base: l.reg.top - args_nr,
-            };
             l.frame_stack.push(frame);
             let rn = luafn(l)?;
             l.frame_stack.pop();
             Ok(rn)
+        } else if let Some(closure) = &self.closure {
+            let mut closure = closure
+                .try_borrow_mut()
+                .map_err(|_| l.error("cannot re-enter a running closure"))?;
+            l.frame_stack.push(frame);
+            let rn = (*closure)(l)?;
+            l.frame_stack.pop();
+            Ok(rn)
         } else {
-            let args_nr = self.proto.params_nr as usize;
-            let mut frame = CallFrame {
-                args_nr: args_nr,
-                ret_nr: 1,
-                env: Default::default(),
-                to_return: false,
-                local_base: l.reg.top - args_nr,
-            };
-
+            let base = frame.base;
+            // parameters without a passed argument start as nil
+            while l.reg.top < base + self.proto.parameters.len() {
+                l.reg.push(Value::Nil);
+            }
             for (i, name) in self.proto.parameters.iter().enumerate() {
-                let i = i + 1;
-                let idx = l.reg.top - i;
-                frame.env.insert(name.to_string(), idx);
+                frame.env.insert(name.to_string(), base + i);
             }
             l.frame_stack.push(frame);
 
-            let v = eval_block(l, self.proto.code.as_ref())?;
+            // expression nesting is counted per function body
+            // and the caller's line comes back after the call
+            let depth = std::mem::replace(&mut l.depth, 0);
+            let line = l.line;
+            let v = eval_block(l, self.proto.code.as_ref());
+            l.depth = depth;
+            l.line = line;
+            v?;
 
-            l.frame_stack.pop();
+            // nothing comes back when the body ends without `return`
+            let values = l
+                .frame_stack
+                .pop()
+                .map(|f| f.ret_values)
+                .unwrap_or_default();
+            l.reg.truncate(base);
 
-            l.returns(v);
-            Ok(1)
+            l.returns_all(values)
         }
     }
 }
@@ -0,0 +1,301 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::rc::Rc;
+
+use crate::function::{LuaFn, LuaFunction};
+use crate::state::{LuaResult, LuaState};
+use crate::table::LuaTable;
+use crate::userdata::LuaUserData;
+use crate::value::Value;
+
+fn open_options(mode: &str) -> Option<OpenOptions> {
+    let mut opts = OpenOptions::new();
+    match mode.trim_end_matches('b') {
+        "r" => opts.read(true),
+        "w" => opts.write(true).create(true).truncate(true),
+        "a" => opts.append(true).create(true),
+        "r+" => opts.read(true).write(true),
+        "w+" => opts.read(true).write(true).create(true).truncate(true),
+        "a+" => opts.read(true).append(true).create(true),
+        _ => return None,
+    };
+    Some(opts)
+}
+
+// Lua style message of an io error, without Rust's "(os error N)" suffix
+fn io_message(e: &io::Error) -> String {
+    let msg = e.to_string();
+    match msg.find(" (os error") {
+        Some(at) => msg[..at].to_string(),
+        None => msg,
+    }
+}
+
+fn io_fail(l: &mut LuaState, path: &str, e: io::Error) -> LuaResult<i32> {
+    let errno = e.raw_os_error().unwrap_or(0) as i64;
+    let message = format!("{}: {}", path, io_message(&e));
+    l.returns_all(vec![
+        Value::Nil,
+        Value::LuaString(message.into()),
+        Value::Number(errno),
+    ])
+}
+
+fn read_line(reader: &mut BufReader<File>, keep_newline: bool) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if !keep_newline && line.ends_with('\n') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+// Consumes the next byte when it is one of `set`, appending it to `token`
+fn read_char_in(reader: &mut BufReader<File>, set: &str, token: &mut String) -> io::Result<bool> {
+    let next = reader.fill_buf()?.first().copied();
+    match next {
+        Some(b) if set.as_bytes().contains(&b) => {
+            token.push(b as char);
+            reader.consume(1);
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+fn read_digits(reader: &mut BufReader<File>, hex: bool, token: &mut String) -> io::Result<()> {
+    let set = if hex {
+        "0123456789abcdefABCDEF"
+    } else {
+        "0123456789"
+    };
+    while read_char_in(reader, set, token)? {}
+    Ok(())
+}
+
+// Reads a numeral the way Lua's l_getn does, yielding nil
+// when the consumed text is not a valid number
+fn read_number(reader: &mut BufReader<File>) -> io::Result<Value> {
+    loop {
+        let buf = reader.fill_buf()?;
+        let skip = buf.iter().take_while(|b| b.is_ascii_whitespace()).count();
+        let done = skip < buf.len() || buf.is_empty();
+        reader.consume(skip);
+        if done {
+            break;
+        }
+    }
+
+    let mut token = String::new();
+    read_char_in(reader, "+-", &mut token)?;
+    let mut hex = false;
+    if read_char_in(reader, "0", &mut token)? {
+        hex = read_char_in(reader, "xX", &mut token)?;
+    }
+    read_digits(reader, hex, &mut token)?;
+    if read_char_in(reader, ".", &mut token)? {
+        read_digits(reader, hex, &mut token)?;
+    }
+    if read_char_in(reader, if hex { "pP" } else { "eE" }, &mut token)? {
+        read_char_in(reader, "+-", &mut token)?;
+        read_digits(reader, false, &mut token)?;
+    }
+    Ok(Value::LuaString(token.into())
+        .to_arith()
+        .unwrap_or(Value::Nil))
+}
+
+fn read_format(l: &LuaState, reader: &mut BufReader<File>, fmt: &Value) -> LuaResult<Value> {
+    let io_err = |e: io::Error| l.error(io_message(&e));
+    if let Value::Number(n) = fmt {
+        let mut buf = vec![];
+        reader
+            .by_ref()
+            .take(*n as u64)
+            .read_to_end(&mut buf)
+            .map_err(io_err)?;
+        if buf.is_empty() && *n > 0 {
+            return Ok(Value::Nil);
+        }
+        return Ok(Value::LuaString(String::from_utf8_lossy(&buf).into()));
+    }
+
+    let fmt = fmt
+        .to_string()
+        .ok_or_else(|| l.error("bad argument #1 to 'read' (invalid format)"))?;
+    match fmt.trim_start_matches('*').chars().next() {
+        Some('l') => Ok(read_line(reader, false)
+            .map_err(io_err)?
+            .map_or(Value::Nil, |s| Value::LuaString(s.into()))),
+        Some('L') => Ok(read_line(reader, true)
+            .map_err(io_err)?
+            .map_or(Value::Nil, |s| Value::LuaString(s.into()))),
+        Some('a') => {
+            let mut s = String::new();
+            reader.read_to_string(&mut s).map_err(io_err)?;
+            Ok(Value::LuaString(s.into()))
+        }
+        Some('n') => read_number(reader).map_err(io_err),
+        _ => Err(l.error("bad argument #1 to 'read' (invalid format)")),
+    }
+}
+
+const FILE_HANDLE: &str = "FILE*";
+
+// The Rust side of a FILE* userdata; None once closed
+struct LuaFile {
+    reader: Option<BufReader<File>>,
+}
+
+// Runs f against the open file; a closed handle is a Lua error,
+// never a read from a dropped file
+fn with_file<T>(
+    l: &LuaState,
+    ud: &LuaUserData,
+    f: impl FnOnce(&mut BufReader<File>) -> LuaResult<T>,
+) -> LuaResult<T> {
+    let mut file = ud
+        .borrow_mut::<LuaFile>()
+        .ok_or_else(|| l.error("bad argument #1 (FILE* expected, got userdata)"))?;
+    match file.reader.as_mut() {
+        Some(reader) => f(reader),
+        None => Err(l.error("attempt to use a closed file")),
+    }
+}
+
+fn lines_iterator(ud: Rc<LuaUserData>) -> Value {
+    Value::Function(LuaFunction::from_closure(move |l| {
+        if ud
+            .borrow::<LuaFile>()
+            .is_none_or(|file| file.reader.is_none())
+        {
+            return Err(l.error("file is already closed"));
+        }
+        let line = with_file(l, &ud, |reader| {
+            read_line(reader, false).map_err(|e| l.error(io_message(&e)))
+        })?;
+        l.returns(line.map_or(Value::Nil, |s| Value::LuaString(s.into())));
+        Ok(1)
+    }))
+}
+
+fn new_handle(l: &LuaState, file: File) -> Rc<LuaUserData> {
+    let ud = LuaUserData::new(LuaFile {
+        reader: Some(BufReader::new(file)),
+    });
+    ud.set_metatable(l.get_metatable_named(FILE_HANDLE));
+    Rc::new(ud)
+}
+
+fn file_read(l: &mut LuaState) -> LuaResult<i32> {
+    let ud = l.arg_userdata(1, FILE_HANDLE)?;
+    let mut fmts = l.args()?.into_iter().skip(1).collect::<Vec<_>>();
+    if fmts.is_empty() {
+        fmts.push(Value::LuaString("l".into()));
+    }
+    let values = with_file(l, &ud, |reader| {
+        let mut values = vec![];
+        for fmt in fmts.iter() {
+            let v = read_format(l, reader, fmt)?;
+            let eof = matches!(v, Value::Nil);
+            values.push(v);
+            if eof {
+                break;
+            }
+        }
+        Ok(values)
+    })?;
+    l.returns_all(values)
+}
+
+fn file_write(l: &mut LuaState) -> LuaResult<i32> {
+    let ud = l.arg_userdata(1, FILE_HANDLE)?;
+    let mut out = String::new();
+    for pos in 2..=l.arg_count()? {
+        let v = l.arg_value(pos)?;
+        let s = v.to_string().ok_or_else(|| {
+            l.error(format!(
+                "bad argument #{} to 'write' (string expected, got {})",
+                pos - 1,
+                v.type_name()
+            ))
+        })?;
+        out.push_str(&s);
+    }
+    with_file(l, &ud, |reader| {
+        reader
+            .get_mut()
+            .write_all(out.as_bytes())
+            .map_err(|e| l.error(io_message(&e)))
+    })?;
+    l.returns(Value::UserData(ud));
+    Ok(1)
+}
+
+fn file_lines(l: &mut LuaState) -> LuaResult<i32> {
+    let ud = l.arg_userdata(1, FILE_HANDLE)?;
+    with_file(l, &ud, |_| Ok(()))?;
+    l.returns_all(vec![lines_iterator(ud), Value::Nil, Value::Nil])
+}
+
+fn file_close(l: &mut LuaState) -> LuaResult<i32> {
+    let ud = l.arg_userdata(1, FILE_HANDLE)?;
+    with_file(l, &ud, |reader| {
+        reader
+            .get_mut()
+            .flush()
+            .map_err(|e| l.error(io_message(&e)))
+    })?;
+    // dropping the file closes it; the handle stays closed for good
+    if let Some(mut file) = ud.borrow_mut::<LuaFile>() {
+        file.reader.take();
+    }
+    l.returns(Value::Bool(true));
+    Ok(1)
+}
+
+fn io_open(l: &mut LuaState) -> LuaResult<i32> {
+    let path = l.arg_string(1)?;
+    let mode = if l.arg_count()? >= 2 {
+        l.arg_string(2)?
+    } else {
+        "r".to_string()
+    };
+    let opts =
+        open_options(&mode).ok_or_else(|| l.error("bad argument #2 to 'open' (invalid mode)"))?;
+
+    match opts.open(&path) {
+        Ok(file) => {
+            let handle = new_handle(l, file);
+            l.returns(Value::UserData(handle));
+            Ok(1)
+        }
+        Err(e) => io_fail(l, &path, e),
+    }
+}
+
+fn io_lines(l: &mut LuaState) -> LuaResult<i32> {
+    let path = l.arg_string(1)?;
+    let file = File::open(&path).map_err(|e| l.error(format!("{}: {}", path, io_message(&e))))?;
+    let iter = lines_iterator(new_handle(l, file));
+    l.returns_all(vec![iter, Value::Nil, Value::Nil])
+}
+
+pub fn open_io(l: &mut LuaState) {
+    let methods = LuaTable::empty();
+    let funcs: &[(&str, LuaFn)] = &[
+        ("read", file_read),
+        ("write", file_write),
+        ("lines", file_lines),
+        ("close", file_close),
+    ];
+    for (name, func) in funcs.iter() {
+        methods.set_str(*name, Value::Function(LuaFunction::from_fn(*func)));
+    }
+    let mt = l.new_metatable(FILE_HANDLE);
+    mt.set_str("__index", Value::Table(Rc::new(methods)));
+
+    l.register_global_lib("io", &[("open", io_open), ("lines", io_lines)]);
+}
@@ -1,8 +1,18 @@
+pub mod analyze;
 pub mod calc;
 pub mod eval;
 pub mod function;
+pub mod iolib;
+pub mod mathlib;
+pub mod oslib;
 pub mod parser;
+pub mod pattern;
 pub mod prelude;
 pub mod state;
+pub mod strlib;
 pub mod table;
+pub mod tablib;
+pub mod unparse;
+pub mod userdata;
+pub mod utf8lib;
 pub mod value;
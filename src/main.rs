@@ -7,15 +7,11 @@ extern crate purua;
 use std::fs::File;
 use std::io::{self, Read};
 
-use combine::parser::char::spaces;
-use combine::stream::position;
-use combine::EasyParser;
-
-use env_logger;
 use log::*;
 use structopt::StructOpt;
 
 use purua::state::LuaState;
+use purua::value::{float_to_int, Value};
 
 #[derive(StructOpt)]
 #[structopt(about)]
@@ -36,22 +32,25 @@ fn main() {
     let args: Command = Command::from_args();
 
     let ret = if let Some(eval) = args.eval {
-        do_main(eval.as_bytes())
+        do_main(eval.as_bytes(), "(command line)")
     } else if let Some(file) = args.file {
-        let f = File::open(file).expect("Cannot open file");
-        do_main(f)
+        let f = File::open(&file).expect("Cannot open file");
+        do_main(f, &file)
     } else if atty::isnt(atty::Stream::Stdin) {
-        do_main(io::stdin())
+        do_main(io::stdin(), "stdin")
     } else {
         Command::clap()
             .write_help(&mut io::stdout())
             .expect("somewhat failed to show help");
-        println!("");
+        println!();
         std::process::exit(1);
     };
 
     match ret {
-        Ok(_) => info!("Purua exited successfully"),
+        Ok(code) => {
+            info!("Purua exited successfully");
+            std::process::exit(code);
+        }
         Err(err) => {
             eprintln!("{}", err);
             std::process::exit(1);
@@ -59,30 +58,22 @@ fn main() {
     };
 }
 
-fn do_main<R>(mut read: R) -> Result<(), purua::state::LuaError>
+fn do_main<R>(mut read: R, chunk_name: &str) -> Result<i32, purua::state::LuaError>
 where
     R: Read,
 {
     let mut l = LuaState::new(65535);
+    l.chunk_name = chunk_name.to_string();
 
     let mut text = String::new();
     read.read_to_string(&mut text)
-        .map_err(|e| l.error(format!("Reading text error: {}", e.to_string())))?;
+        .map_err(|e| l.error(format!("Reading text error: {}", e)))?;
 
     //let mut parser = myparser();
     purua::prelude::prelude(&mut l);
 
-    let mut parser = (spaces(), purua::parser::chunk());
-
-    let pos = position::Stream::new(text.as_str());
-    let res = parser
-        .easy_parse(pos)
-        .map_err(|e| l.error(format!("Parse error: {}", e.to_string())))?
-        .0;
-    let chunk = res.1;
-    debug!("parsed: {:?}", &chunk);
-
-    purua::eval::eval_chunk(&mut l, chunk.as_ref())?;
+    let ret = l.run_string(&text)?;
+    debug!("returned: {:?}", &ret);
     //l.assign_global("foo", Value::LuaString("buz".to_string()));
 
     // // calling print()
@@ -105,5 +96,15 @@ where
     // let ret = l.global_funcall1("fib", Value::Number(30))?;
     // eprintln!("return value of fib(30): {:?}", ret);
 
-    Ok(())
+    Ok(exit_code(&ret))
+}
+
+// A number returned from the main chunk is the exit status; returning
+// nothing or nil exits 0
+fn exit_code(ret: &Value) -> i32 {
+    match ret {
+        Value::Number(n) => *n as i32,
+        Value::Float(f) => float_to_int(*f).map_or(0, |n| n as i32),
+        _ => 0,
+    }
 }
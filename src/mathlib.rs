@@ -0,0 +1,46 @@
+use crate::state::{LuaResult, LuaState};
+use crate::value::Value;
+
+fn check_number(l: &LuaState, pos: usize, fname: &str) -> LuaResult<Value> {
+    match l.arg_value(pos)? {
+        v @ Value::Number(_) | v @ Value::Float(_) => Ok(v),
+        v => Err(l.error(format!(
+            "bad argument #{} to '{}' (number expected, got {})",
+            pos,
+            fname,
+            v.type_name()
+        ))),
+    }
+}
+
+// Picks the argument winning the comparison, returning it as is so
+// that its integer or float subtype is kept
+fn select_number(l: &mut LuaState, fname: &str, op: char) -> LuaResult<i32> {
+    if l.arg_count()? == 0 {
+        return Err(l.error(format!(
+            "bad argument #1 to '{}' (number expected, got no value)",
+            fname
+        )));
+    }
+    let mut chosen = check_number(l, 1, fname)?;
+    for pos in 2..=l.arg_count()? {
+        let v = check_number(l, pos, fname)?;
+        if let Value::Bool(true) = l.process_op_numeric(&op, &v, &chosen)? {
+            chosen = v;
+        }
+    }
+    l.returns(chosen);
+    Ok(1)
+}
+
+fn math_max(l: &mut LuaState) -> LuaResult<i32> {
+    select_number(l, "max", '>')
+}
+
+fn math_min(l: &mut LuaState) -> LuaResult<i32> {
+    select_number(l, "min", '<')
+}
+
+pub fn open_math(l: &mut LuaState) {
+    l.register_global_lib("math", &[("max", math_max), ("min", math_min)]);
+}
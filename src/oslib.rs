@@ -0,0 +1,23 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::state::{LuaResult, LuaState};
+use crate::value::Value;
+
+fn os_time(l: &mut LuaState) -> LuaResult<i32> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| l.error(format!("Cannot get current time: {}", e)))?;
+    l.returns(Value::Number(now.as_secs() as i64));
+    Ok(1)
+}
+
+fn os_difftime(l: &mut LuaState) -> LuaResult<i32> {
+    let t2 = l.arg_float(1)?;
+    let t1 = l.arg_float(2)?;
+    l.returns(Value::Float(t2 - t1));
+    Ok(1)
+}
+
+pub fn open_os(l: &mut LuaState) {
+    l.register_global_lib("os", &[("time", os_time), ("difftime", os_difftime)]);
+}
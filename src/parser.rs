@@ -1,19 +1,29 @@
 extern crate combine;
 
+use combine::error::{Commit, StreamError};
 use combine::parser::char::*;
+use combine::stream::position::{self, SourcePosition};
+use combine::stream::StreamErrorFor;
 use combine::*;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use crate::state::{GlobalSlot, LuaError};
 
 #[allow(dead_code)]
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Rule {
     Nil,
     Reserved(&'static str),
     Bool(bool),
-    Numeral(i32),
-    LiteralString(String),
+    Numeral(i64),
+    Float(f64),
+    LiteralString(Rc<str>),
     Symbol(String),
+    Name(String, GlobalSlot), // a variable read or assigned
     SymbolList(Vec<Box<Rule>>),
-    Chunk(Vec<Box<Rule>>, Option<Box<Rule>>), // vec<stat>, laststat
+    Chunk(Vec<Box<Rule>>, Option<Box<Rule>>, Vec<usize>), // vec<stat>, laststat, lines
     Block(Box<Rule>),
     Stat(
         StatKind,
@@ -23,16 +33,20 @@ pub enum Rule {
         Option<Box<Rule>>,
         Option<Box<Rule>>,
     ),
-    LastStat(Box<Rule>),
+    LastStat(Box<Rule>), // explist
     IfStat(Vec<Box<Rule>>, Vec<Box<Rule>>),
-    FuncName(Box<Rule>),
-    Var(Box<Rule>),
+    FuncName(Box<Rule>, Option<Box<Rule>>), // symbol or dotted symbols, method
+    Var(Box<Rule>),                         // symbol or index
+    Index(Box<Rule>, Box<Rule>),            // prefixexp, symbol or exp
+    VarList(Vec<Box<Rule>>),                // vec<var>
     Exp(Box<Rule>),
-    Prefixexp(Box<Rule>),               // (fc|var|exp)
-    FunctionCall(Box<Rule>, Box<Rule>), // symbol, args
-    Args(Box<Rule>),
-    FuncBody(Option<Box<Rule>>, Box<Rule>), // params, block
-    ParList1(Box<Rule>),                    // symbol(s)
+    ExpList(Vec<Box<Rule>>),                     // vec<exp>
+    Prefixexp(Box<Rule>),                        // (fc|var|exp)
+    FunctionCall(Box<Rule>, Box<Rule>),          // symbol or prefixexp, args
+    MethodCall(Box<Rule>, Box<Rule>, Box<Rule>), // prefixexp, symbol, args
+    Args(Vec<Box<Rule>>),                        // vec<exp>
+    FuncBody(Option<Box<Rule>>, Box<Rule>),      // params, block
+    ParList1(Box<Rule>),                         // symbol(s)
     TableConst(Box<Rule>),
     FieldList(Vec<Box<Rule>>), // vec<field>
     Field(Box<Rule>, Box<Rule>),
@@ -61,23 +75,153 @@ pub enum StatKind {
     LocalVar,
 }
 
+type RulePair = (Box<Rule>, Box<Rule>);
+
+// Nesting of expressions and blocks the parser accepts; deeper
+// input is an error instead of a native stack overflow
+pub const MAX_SYNTAX_LEVELS: usize = 100;
+
+thread_local! {
+    static SYNTAX_LEVEL: Cell<usize> = const { Cell::new(0) };
+    // accept `!=` for `~=`, see parse_with
+    static LENIENT: Cell<bool> = const { Cell::new(false) };
+    // string literals seen so far in this parse, see intern
+    static LITERALS: RefCell<HashSet<Rc<str>>> = RefCell::new(HashSet::new());
+}
+
+// The same text written twice as a literal shares one allocation,
+// which also lets string equality stop at comparing pointers
+fn intern(s: String) -> Rc<str> {
+    LITERALS.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if let Some(rc) = pool.get(s.as_str()) {
+            return rc.clone();
+        }
+        let rc: Rc<str> = s.into();
+        pool.insert(rc.clone());
+        rc
+    })
+}
+
+// Runs p one syntax level deeper, failing once the input
+// nests beyond MAX_SYNTAX_LEVELS
+pub fn nested<Input, P>(mut p: P) -> impl Parser<Input, Output = P::Output>
+where
+    Input: Stream<Token = char, Position = SourcePosition>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    P: Parser<Input>,
+{
+    combine::parser(move |input: &mut Input| {
+        let level = SYNTAX_LEVEL.with(|l| l.get());
+        if level >= MAX_SYNTAX_LEVELS {
+            let mut err = Input::Error::empty(input.position());
+            err.add(StreamErrorFor::<Input>::message_static_message(
+                "chunk has too many syntax levels",
+            ));
+            return Err(Commit::Commit(err.into()));
+        }
+        SYNTAX_LEVEL.with(|l| l.set(level + 1));
+        let res = p.parse_stream(input).into_result();
+        SYNTAX_LEVEL.with(|l| l.set(level));
+        res
+    })
+}
+
+// Whitespace and comments between tokens: `-- to end of line` and
+// long comments `--[[ ... ]]`, `--[==[ ... ]==]`
+pub fn sp<Input>() -> impl Parser<Input, Output = ()>
+where
+    Input: Stream<Token = char, Position = SourcePosition>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    skip_many(space().map(|_| ()).or(comment())).silent()
+}
+
+fn comment<Input>() -> impl Parser<Input, Output = ()>
+where
+    Input: Stream<Token = char, Position = SourcePosition>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    (
+        attempt(string("--")),
+        attempt((char('['), many(char('=')), char('[')))
+            .then(|(_, level, _): (_, String, _)| long_comment_rest(level.len()))
+            .or(skip_many(satisfy(|c| c != '\n'))),
+    )
+        .map(|_| ())
+}
+
+// Everything up to the `]=*]` closing a long bracket of this level
+fn long_comment_rest<Input>(level: usize) -> impl Parser<Input, Output = ()>
+where
+    Input: Stream<Token = char, Position = SourcePosition>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    combine::parser(move |input: &mut Input| {
+        // '=' signs seen since the last ']', if in a closing bracket
+        let mut closing: Option<usize> = None;
+        loop {
+            match input.uncons() {
+                Ok(']') if closing == Some(level) => return Ok(((), Commit::Commit(()))),
+                Ok(']') => closing = Some(0),
+                Ok('=') => closing = closing.map(|n| n + 1),
+                Ok(_) => closing = None,
+                Err(_) => {
+                    let mut err = Input::Error::empty(input.position());
+                    err.add(StreamErrorFor::<Input>::message_static_message(
+                        "unfinished long comment",
+                    ));
+                    return Err(Commit::Commit(err.into()));
+                }
+            }
+        }
+    })
+}
+
 pub fn nop() -> Box<Rule> {
     Box::new(Rule::Nop)
 }
 
 pub fn reserved<Input>(word: &'static str) -> impl Parser<Input, Output = Box<Rule>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    string(word)
-        .skip(spaces())
+    whole_word(word)
+        .skip(sp())
         .map(|s| Box::new(Rule::Reserved(s)))
 }
 
+// The word itself and not the start of a longer name
+fn whole_word<Input>(word: &'static str) -> impl Parser<Input, Output = &'static str>
+where
+    Input: Stream<Token = char, Position = SourcePosition>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    attempt(string(word).skip(not_followed_by(alpha_num().or(char('_')))))
+}
+
+// The keyword ending a construct; when it is missing the error
+// tells which construct was left open
+pub fn closing<Input>(
+    word: &'static str,
+    construct: &'static str,
+) -> impl Parser<Input, Output = Box<Rule>>
+where
+    Input: Stream<Token = char, Position = SourcePosition>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    reserved(word).silent().expected(construct)
+}
+
+const KEYWORDS: [&str; 22] = [
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
 pub fn nil<Input>() -> impl Parser<Input, Output = Box<Rule>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
     reserved("nil").map(|_| Box::new(Rule::Nil))
@@ -85,7 +229,7 @@ where
 
 pub fn boolean<Input>() -> impl Parser<Input, Output = Box<Rule>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
     reserved("true")
@@ -95,164 +239,357 @@ where
 
 pub fn numeral<Input>() -> impl Parser<Input, Output = Box<Rule>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    many1(digit())
-        .skip(spaces())
-        .map(|d: String| Box::new(Rule::Numeral(d.parse().unwrap())))
+    // "1." is a float, but "1.." must leave the dots to concatenation
+    let fraction = attempt(char('.').skip(not_followed_by(char('.')))).with(many(digit()));
+    let decimal = (many1(digit()), optional(fraction), optional(exponent())).map(
+        |(int, frac, exp): (String, Option<String>, Option<String>)| {
+            if frac.is_none() && exp.is_none() {
+                return decimal_numeral(&int);
+            }
+            let text = format!(
+                "{}.{}{}",
+                int,
+                frac.unwrap_or_default(),
+                exp.unwrap_or_default()
+            );
+            Rule::Float(text.parse().unwrap())
+        },
+    );
+    let leading_dot = (
+        attempt(char('.').with(many1(digit()))),
+        optional(exponent()),
+    )
+        .map(|(frac, exp): (String, Option<String>)| {
+            let text = format!("0.{}{}", frac, exp.unwrap_or_default());
+            Rule::Float(text.parse().unwrap())
+        });
+    let hex = attempt((char('0'), one_of("xX".chars())))
+        .with(many1(hex_digit()))
+        .map(|h: String| Rule::Numeral(hex_numeral(&h)));
+
+    choice((hex, decimal, leading_dot)).skip(sp()).map(Box::new)
 }
 
-pub fn literal_string<Input>() -> impl Parser<Input, Output = Box<Rule>>
+fn exponent<Input>() -> impl Parser<Input, Output = String>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    between(token('"'), token('"'), many(satisfy(|c| c != '"')))
-        .skip(spaces())
-        .then(|s: String| {
-            let s = s.replace("\\n", "\n");
-            value(s)
+    (
+        one_of("eE".chars()),
+        optional(one_of("+-".chars())),
+        many1(digit()),
+    )
+        .map(|(e, sign, digits): (char, Option<char>, String)| {
+            format!(
+                "{}{}{}",
+                e,
+                sign.map(String::from).unwrap_or_default(),
+                digits
+            )
         })
-        .map(|s: String| Box::new(Rule::LiteralString(s)))
+}
+
+// An integer literal too large for i64 becomes a float, as in Lua
+fn decimal_numeral(digits: &str) -> Rule {
+    match digits.parse() {
+        Ok(n) => Rule::Numeral(n),
+        Err(_) => Rule::Float(digits.parse().unwrap()),
+    }
+}
+
+// Hexadecimal literals wrap around modulo 2^64
+fn hex_numeral(digits: &str) -> i64 {
+    digits.chars().fold(0u64, |acc, c| {
+        acc.wrapping_mul(16)
+            .wrapping_add(c.to_digit(16).unwrap() as u64)
+    }) as i64
+}
+
+pub fn literal_string<Input>() -> impl Parser<Input, Output = Box<Rule>>
+where
+    Input: Stream<Token = char, Position = SourcePosition>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    // a string may not run past the end of its line
+    between(
+        token('"'),
+        token('"').expected("closing '\"' of the string"),
+        many(satisfy(|c| c != '"' && c != '\n')),
+    )
+    .skip(sp())
+    .then(|s: String| {
+        let s = s.replace("\\n", "\n");
+        value(s)
+    })
+    .map(|s: String| Box::new(Rule::LiteralString(intern(s))))
 }
 
 pub fn symbol<Input>() -> impl Parser<Input, Output = Box<Rule>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    (letter(), many(alpha_num()))
-        .skip(spaces())
-        .map(|(c, v): (char, String)| Box::new(Rule::Symbol(format!("{}{}", c, v))))
+    attempt(
+        (letter().or(char('_')), many(alpha_num().or(char('_')))).and_then(
+            |(c, v): (char, String)| {
+                let name = format!("{}{}", c, v);
+                if KEYWORDS.contains(&name.as_str()) {
+                    Err(StreamErrorFor::<Input>::unexpected_static_message(
+                        "reserved word",
+                    ))
+                } else {
+                    Ok(name)
+                }
+            },
+        ),
+    )
+    .skip(sp())
+    .map(|name| Box::new(Rule::Symbol(name)))
+}
+
+// A name used as a variable, with room for the global slot it
+// resolves to, so that evaluating it again never hashes the name
+fn variable(sym: Rule) -> Box<Rule> {
+    match sym {
+        Rule::Symbol(name) => Box::new(Rule::Name(name, GlobalSlot::default())),
+        sym => Box::new(sym),
+    }
 }
 
 pub fn symbollist<Input>() -> impl Parser<Input, Output = Box<Rule>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    sep_by1(symbol(), token(',').skip(spaces()))
+    sep_by1(symbol(), token(',').skip(sp()))
         .map(|vec: Vec<Box<Rule>>| Box::new(Rule::SymbolList(vec)))
-        .skip(spaces())
+        .skip(sp())
 }
 
 pub fn var<Input>() -> impl Parser<Input, Output = Box<Rule>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    // choice((
-    //     symbol(),
-    //     (prefixexp(), char('['), exp(), char(']')),
-    //     (prefixexp(), char('.'), symbol()),
-    // ))
-    symbol().map(|sym| Box::new(Rule::Var(sym)))
+    // a prefix expression ending in a name or an index, as in `a`,
+    // `a.b` or `f().c[1]`
+    prefixexp().and_then(|p| match *p {
+        Rule::Prefixexp(var) if matches!(var.as_ref(), Rule::Var(_)) => Ok(var),
+        _ => Err(StreamErrorFor::<Input>::unexpected_static_message(
+            "expression that cannot be assigned to",
+        )),
+    })
 }
 
-pub fn args<Input>() -> impl Parser<Input, Output = Box<Rule>>
+pub fn varlist<Input>() -> impl Parser<Input, Output = Box<Rule>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    let nop = Box::new(Rule::Nop);
-    between(token('('), token(')'), exp().or(value(nop))).map(|exp| Box::new(Rule::Args(exp)))
+    sep_by1(var(), token(',').skip(sp())).map(|vec: Vec<Box<Rule>>| Box::new(Rule::VarList(vec)))
+}
+
+pub fn explist<Input>() -> impl Parser<Input, Output = Box<Rule>>
+where
+    Input: Stream<Token = char, Position = SourcePosition>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    sep_by1(exp(), token(',').skip(sp())).map(|vec: Vec<Box<Rule>>| Box::new(Rule::ExpList(vec)))
+}
+
+parser! {
+    pub fn args[Input]() (Input) -> Box<Rule>
+    where [
+        Input: Stream<Token = char, Position = SourcePosition>,
+        Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    ] {
+        between(
+            token('(').skip(sp()),
+            token(')').expected("')' to close the arguments").skip(sp()),
+            sep_by(exp(), token(',').skip(sp())),
+        )
+        .map(|exps| Box::new(Rule::Args(exps)))
+    }
 }
 
 pub fn functioncall<Input>() -> impl Parser<Input, Output = Box<Rule>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    (symbol(), args()).map(|(name, args)| Box::new(Rule::FunctionCall(name, args)))
+    // only the name and the opening paren are backtracked over
+    (
+        attempt((symbol(), look_ahead(token('(')))).map(|(name, _)| name),
+        args(),
+    )
+        .map(|(name, args)| Box::new(Rule::FunctionCall(variable(*name), args)))
 }
 
 pub fn binop1<Input>() -> impl Parser<Input, Output = Box<Rule>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    let token = choice((
-        attempt(string("and").map(|_| '&')),
-        attempt(string("or").map(|_| '|')),
-    ))
-    .skip(spaces())
-    .map(|tok| move |d1, d2| Box::new(Rule::Exp(Box::new(Rule::BinOp(tok, d1, d2)))));
+    // whole words only, so that `order` is not `or der`
+    let token = whole_word("or")
+        .skip(sp())
+        .map(|_| |d1, d2| Box::new(Rule::Exp(Box::new(Rule::BinOp('|', d1, d2)))));
+    chainl1(binop_and(), token)
+}
+
+// `and` binds tighter than `or`
+pub fn binop_and<Input>() -> impl Parser<Input, Output = Box<Rule>>
+where
+    Input: Stream<Token = char, Position = SourcePosition>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    let token = whole_word("and")
+        .skip(sp())
+        .map(|_| |d1, d2| Box::new(Rule::Exp(Box::new(Rule::BinOp('&', d1, d2)))));
     chainl1(binop2(), token)
 }
 
 pub fn binop2<Input>() -> impl Parser<Input, Output = Box<Rule>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
     let token = choice((
-        attempt(string("<=").map(|_| 'g')),
-        attempt(string(">=").map(|_| 'l')),
+        attempt(string("<=").map(|_| 'l')),
+        attempt(string(">=").map(|_| 'g')),
         char('<'),
         char('>'),
-        char('-'),
         attempt(string("==").map(|_| 'e')),
         attempt(string("~=").map(|_| 'n')),
+        // `!` starts nothing else, so this may commit
+        string("!=").then(|_| {
+            if LENIENT.with(|l| l.get()) {
+                value('n').left()
+            } else {
+                unexpected_any("'!=', use '~='").map(|_: ()| 'n').right()
+            }
+        }),
     ))
-    .skip(spaces())
+    .skip(sp())
     .map(|tok| move |d1, d2| Box::new(Rule::Exp(Box::new(Rule::BinOp(tok, d1, d2)))));
-    chainl1(binop3(), token)
+    chainl1(binop_concat(), token)
+}
+
+// `..` binds looser than `+` and is right associative:
+// a .. b .. c is a .. (b .. c)
+pub fn binop_concat<Input>() -> impl Parser<Input, Output = Box<Rule>>
+where
+    Input: Stream<Token = char, Position = SourcePosition>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    // folding a list keeps the parser smaller than chainr1, whose
+    // frames count against the syntax level stack budget
+    let token = attempt(string("..").skip(not_followed_by(char('.')))).skip(sp());
+    sep_by1(binop3(), token).map(|operands: Vec<Box<Rule>>| {
+        let mut operands = operands.into_iter().rev();
+        let last = operands.next().unwrap();
+        operands.fold(last, |rhs, lhs| {
+            Box::new(Rule::Exp(Box::new(Rule::BinOp('c', lhs, rhs))))
+        })
+    })
 }
 
 pub fn binop3<Input>() -> impl Parser<Input, Output = Box<Rule>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
     let token = char('+')
         .or(char('-'))
-        .skip(spaces())
+        .skip(sp())
         .map(|tok| move |d1, d2| Box::new(Rule::Exp(Box::new(Rule::BinOp(tok, d1, d2)))));
     chainl1(binop4(), token)
 }
 
 pub fn binop4<Input>() -> impl Parser<Input, Output = Box<Rule>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    let token = char('*')
-        .or(char('/'))
-        .skip(spaces())
-        .map(|tok| move |d1, d2| Box::new(Rule::Exp(Box::new(Rule::BinOp(tok, d1, d2)))));
-    chainl1(exp_(), token)
+    let token = choice((
+        char('*'),
+        attempt(string("//").map(|_| 'd')),
+        char('/'),
+        char('%'),
+    ))
+    .skip(sp())
+    .map(|tok| move |d1, d2| Box::new(Rule::Exp(Box::new(Rule::BinOp(tok, d1, d2)))));
+    chainl1(binop_pow(), token)
+}
+
+parser! {
+    // `^` is right associative and binds tighter than a unary
+    // operator on its left: -2^2 is -(2^2), and 2^-1 is 2^(-1)
+    pub fn binop_pow[Input]() (Input) -> Box<Rule>
+    where [
+        Input: Stream<Token = char, Position = SourcePosition>,
+        Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    ] {
+        (exp_(), optional(token('^').skip(sp()).with(binop_pow())))
+            .map(|(base, exp)| match exp {
+                Some(exp) => Box::new(Rule::Exp(Box::new(Rule::BinOp('^', base, exp)))),
+                None => base,
+            })
+    }
 }
 
 pub fn unop<Input>() -> impl Parser<Input, Output = Box<Rule>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    (
+    (unop_token(), nested(binop_pow())).map(|(op, e)| Box::new(negate_constant(op, e)))
+}
+
+parser! {
+    // `not` as a whole word, so that `notes` stays a name
+    fn unop_token[Input]() (Input) -> char
+    where [
+        Input: Stream<Token = char, Position = SourcePosition>,
+        Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    ] {
         choice((
-            attempt(string("not").map(|_| '!')).skip(spaces()),
+            whole_word("not").map(|_| '!'),
             char('-'),
             char('#'),
             char('~'),
-        )),
-        exp_(),
-    )
-        .map(|(op, e)| Box::new(Rule::UnOp(op, e)))
+        ))
+        .skip(sp())
+    }
+}
+
+// `-3` folds into the constant instead of negating it at runtime
+fn negate_constant(op: char, e: Box<Rule>) -> Rule {
+    if op == '-' {
+        if let Rule::Exp(inner) = e.as_ref() {
+            match inner.as_ref() {
+                Rule::Numeral(n) => return Rule::Numeral(n.wrapping_neg()),
+                Rule::Float(f) => return Rule::Float(-f),
+                _ => {}
+            }
+        }
+    }
+    Rule::UnOp(op, e)
 }
 
 parser! {
     // For binop loop
     pub fn exp_[Input]() (Input) -> Box<Rule>
     where [
-        Input: Stream<Token = char>,
+        Input: Stream<Token = char, Position = SourcePosition>,
         Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
     ] {
         choice((
-            attempt(nil()),
-            attempt(boolean()),
-            numeral(),
-            literal_string(),
+            constant(),
             unop(),
             prefixexp(),
             tableconstructor(),
@@ -261,46 +598,123 @@ parser! {
     }
 }
 
+parser! {
+    // The leaves of exp_ and function literals, kept out of its
+    // choice so that the frames of recursive expressions stay small
+    pub fn constant[Input]() (Input) -> Box<Rule>
+    where [
+        Input: Stream<Token = char, Position = SourcePosition>,
+        Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    ] {
+        choice((
+            attempt(nil()),
+            attempt(boolean()),
+            numeral(),
+            literal_string(),
+            function_def(),
+        ))
+    }
+}
+
+parser! {
+    // An anonymous function, `function (params) block end`
+    pub fn function_def[Input]() (Input) -> Box<Rule>
+    where [
+        Input: Stream<Token = char, Position = SourcePosition>,
+        Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    ] {
+        (
+            reserved("function"),
+            funcbody(),
+            closing("end", "'end' to close 'function'"),
+        )
+            .map(|(_, body, _)| body)
+    }
+}
+
 parser! {
     pub fn exp[Input]() (Input) -> Box<Rule>
     where [
-        Input: Stream<Token = char>,
+        Input: Stream<Token = char, Position = SourcePosition>,
         Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
     ] {
-        binop1()
+        nested(binop1())
     }
 }
 
+// What may follow a prefix expression
+enum Suffix {
+    Call(Box<Rule>),
+    Method(Box<Rule>, Box<Rule>),
+    Index(Box<Rule>),
+}
+
 parser! {
     pub fn prefixexp[Input]() (Input) -> Box<Rule>
     where [
-        Input: Stream<Token = char>,
+        Input: Stream<Token = char, Position = SourcePosition>,
         Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
     ] {
-        choice((
-            attempt(functioncall()),
-            attempt(var()),
-            between(token('('), token(')'), exp()),
-        )).skip(spaces())
-            .map(|e| Box::new(Rule::Prefixexp(e)))
+        (
+            choice((
+                functioncall(),
+                symbol().map(|sym| Box::new(Rule::Var(variable(*sym)))),
+                between(token('(').skip(sp()), token(')'), exp()),
+            )).skip(sp()),
+            many(choice((
+                // `::` after a call statement starts a label
+                (attempt(token(':').skip(not_followed_by(token(':')))).skip(sp()), symbol(), args())
+                    .map(|(_, name, args)| Suffix::Method(name, args)),
+                args().map(Suffix::Call),
+                (attempt(token('.').skip(not_followed_by(token('.')))).skip(sp()), symbol())
+                    .map(|(_, name)| Suffix::Index(name)),
+                between(token('[').skip(sp()), token(']').skip(sp()), exp()).map(Suffix::Index),
+            ))),
+        )
+            .map(|(e, suffixes): (_, Vec<Suffix>)| {
+                // `a.b:c(x)(y)` nests each suffix on the previous result
+                suffixes
+                    .into_iter()
+                    .fold(Box::new(Rule::Prefixexp(e)), |prefix, suffix| {
+                        let rule = match suffix {
+                            Suffix::Call(args) => Rule::FunctionCall(prefix, args),
+                            Suffix::Method(name, args) => Rule::MethodCall(prefix, name, args),
+                            Suffix::Index(key) => Rule::Var(Box::new(Rule::Index(prefix, key))),
+                        };
+                        Box::new(Rule::Prefixexp(Box::new(rule)))
+                    })
+            })
     }
 }
 
 pub fn funcname<Input>() -> impl Parser<Input, Output = Box<Rule>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    symbol().map(|name| Box::new(Rule::FuncName(name)))
+    // `a.b.c:m`: a plain name stays a Symbol, a dotted path becomes
+    // a SymbolList
+    (
+        sep_by1(symbol(), token('.').skip(sp())),
+        optional(token(':').skip(sp()).with(symbol())),
+    )
+        .map(|(mut path, method): (Vec<Box<Rule>>, _)| {
+            let name = if path.len() == 1 {
+                path.pop().unwrap()
+            } else {
+                Box::new(Rule::SymbolList(path))
+            };
+            Box::new(Rule::FuncName(name, method))
+        })
 }
 
 pub fn funcbody<Input>() -> impl Parser<Input, Output = Box<Rule>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
     (
-        between(token('('), token(')'), parlist1()).skip(spaces()),
+        between(token('(').skip(sp()), token(')'), parlist1()).skip(sp()),
         block(),
     )
         .map(|(params, block)| Box::new(Rule::FuncBody(params, block)))
@@ -308,36 +722,38 @@ where
 
 pub fn parlist1<Input>() -> impl Parser<Input, Output = Option<Box<Rule>>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    symbol()
-        .map(|name| Some(Box::new(Rule::ParList1(name))))
+    symbollist()
+        .map(|names| Some(Box::new(Rule::ParList1(names))))
         .or(value(None))
 }
 
 pub fn tableconstructor<Input>() -> impl Parser<Input, Output = Box<Rule>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
     between(
-        token('{').skip(spaces()),
-        token('}'),
-        fieldlist().skip(spaces()),
+        token('{').skip(sp()),
+        token('}').expected("'}' to close the table"),
+        fieldlist()
+            .or(value(Box::new(Rule::FieldList(vec![]))))
+            .skip(sp()),
     )
-    .skip(spaces())
+    .skip(sp())
     .map(|l| Box::new(Rule::TableConst(l)))
 }
 
 pub fn fieldlist<Input>() -> impl Parser<Input, Output = Box<Rule>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
     (
         field(),
-        many((fieldsep(), field())),
+        many(attempt((fieldsep(), field()))),
         fieldsep().or(value(())),
     )
         .map(|(head, tail, _): (Box<Rule>, Vec<((), Box<Rule>)>, _)| {
@@ -349,17 +765,17 @@ where
 
 pub fn field<Input>() -> impl Parser<Input, Output = Box<Rule>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
     choice((
         (
-            between(token('['), token(']'), exp()),
-            token('=').skip(spaces()),
+            between(token('[').skip(sp()), token(']').skip(sp()), exp()),
+            token('=').skip(sp()),
             exp(),
         )
             .map(|(e1, _, e2)| Box::new(Rule::Field(e1, e2))),
-        (symbol(), token('=').skip(spaces()), exp())
+        attempt((symbol(), token('=').skip(sp()), exp()))
             .map(|(e1, _, e2)| Box::new(Rule::Field(e1, e2))),
         exp().map(|e1| Box::new(Rule::Field(Box::new(Rule::Nop), e1))),
     ))
@@ -367,25 +783,116 @@ where
 
 pub fn fieldsep<Input>() -> impl Parser<Input, Output = ()>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    token(',').or(token(';')).skip(spaces()).map(|_| ())
+    token(',').or(token(';')).skip(sp()).map(|_| ())
 }
 
+// The larger statements are parser! functions of their own: stat's
+// choice is on the path of every nested block, and in debug builds
+// its frame holds the state of all of its alternatives
 pub fn stat<Input>() -> impl Parser<Input, Output = Box<Rule>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
     choice((
         token(';').map(|_| Box::new(Rule::Stat(StatKind::Sep, None, None, None, None, None))),
-        attempt(
+        if_stat(),
+        jump_stat(),
+        (
+            reserved("do"),
+            block(),
+            closing("end", "'end' to close 'do'"),
+        )
+            .map(|(_, blk, _)| {
+                Box::new(Rule::Stat(StatKind::Do, blk.into(), None, None, None, None))
+            }),
+        local_stat(),
+        for_stat(),
+        repeat_stat(),
+        function_stat(),
+        exp_stat(),
+    ))
+}
+
+parser! {
+    // names without values are nil
+    fn local_stat[Input]() (Input) -> Box<Rule>
+    where [
+        Input: Stream<Token = char, Position = SourcePosition>,
+        Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    ] {
+        (
+            reserved("local"),
+            symbollist(),
+            optional(token('=').skip(sp()).with(explist())),
+        )
+            .map(|(_, names, vals)| {
+                Box::new(Rule::Stat(StatKind::LocalVar, names.into(), vals, None, None, None))
+            })
+    }
+}
+
+parser! {
+    // An assignment or a call: both start with a prefix expression,
+    // which is parsed once and then decides which one this is
+    fn exp_stat[Input]() (Input) -> Box<Rule>
+    where [
+        Input: Stream<Token = char, Position = SourcePosition>,
+        Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    ] {
+        prefixexp().then(|p| {
+            let p = match *p {
+                Rule::Prefixexp(p) => p,
+                p => Box::new(p),
+            };
+            match p.as_ref() {
+                Rule::Var(_) => (
+                    value(p),
+                    many(token(',').skip(sp()).with(var())),
+                    token('=').skip(sp()),
+                    explist(),
+                )
+                    .map(|(first, rest, _, e): (_, Vec<Box<Rule>>, _, _)| {
+                        let mut vars = vec![first];
+                        vars.extend(rest);
+                        let v = Box::new(Rule::VarList(vars));
+                        Box::new(Rule::Stat(StatKind::VarAssign, v.into(), e.into(), None, None, None))
+                    })
+                    .left(),
+                Rule::FunctionCall(_, _) | Rule::MethodCall(_, _, _) => value(Box::new(Rule::Stat(
+                    StatKind::FunctionCall,
+                    p.into(),
+                    None,
+                    None,
+                    None,
+                    None,
+                )))
+                .left()
+                .right(),
+                _ => unexpected_any("parenthesized expression as a statement")
+                    .map(|_: ()| Box::new(Rule::Nop))
+                    .right()
+                    .right(),
+            }
+        })
+    }
+}
+
+parser! {
+    fn if_stat[Input]() (Input) -> Box<Rule>
+    where [
+        Input: Stream<Token = char, Position = SourcePosition>,
+        Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    ] {
+        (
             (
                 reserved("if"),
                 exp(),
                 reserved("then"),
-                block().skip(spaces()),
+                block().skip(sp()),
                 many(
                     (
                         attempt(reserved("elseif")),
@@ -394,108 +901,166 @@ where
                         block(),
                     )
                         .map(|(_, exp, _, blk)| (exp, blk)),
-                )
-                .or(value(vec![]))
-                .skip(spaces()),
-                (attempt(reserved("else")), block())
-                    .or(value((Box::new(Rule::Nop), Box::new(Rule::Nop))))
-                    .skip(spaces()),
-                reserved("end"),
-            )
-                .map(
-                    |(_, ifexp, _, thenblk, elifpairs, elsepair, _): (
-                        _,
-                        _,
-                        _,
-                        _,
-                        Vec<(Box<Rule>, Box<Rule>)>,
-                        (Box<Rule>, Box<Rule>),
-                        _,
-                    )| {
-                        let mut vec0 = vec![ifexp];
-                        let mut vec1 = vec![thenblk];
-                        for (exp, blk) in elifpairs.into_iter() {
-                            vec0.push(exp);
-                            vec1.push(blk);
-                        }
-                        if let Rule::Block(_) = elsepair.1.as_ref() {
-                            vec0.push(Box::new(Rule::Nop));
-                            vec1.push(elsepair.1);
-                        };
-                        let ifst = Rule::IfStat(vec0, vec1);
-                        Box::new(Rule::Stat(
-                            StatKind::IfThen,
-                            Box::new(ifst).into(),
-                            None,
-                            None,
-                            None,
-                            None,
-                        ))
-                    },
                 ),
-        ),
-        attempt(
-            reserved("break")
-                .map(|_| Box::new(Rule::Stat(StatKind::Break, None, None, None, None, None))),
-        ),
-        attempt((reserved("do"), block(), reserved("end"))).map(|(_, blk, _)| {
-            Box::new(Rule::Stat(StatKind::Do, blk.into(), None, None, None, None))
-        }),
-        attempt(
-            (
-                reserved("local"),
-                symbol(),
-                (token('=').skip(spaces()), exp())
-                    .map(|(_, e)| e)
-                    .or(value(Box::new(Rule::Exp(Box::new(Rule::Nil))))),
-            )
-                .map(|(_, name, val)| {
+                (attempt(reserved("else")), block())
+                    .or(value((Box::new(Rule::Nop), Box::new(Rule::Nop)))),
+            ),
+            closing("end", "'end' to close 'if'"),
+        )
+            .map(
+                |((_, ifexp, _, thenblk, elifpairs, elsepair), _): (
+                    (_, _, _, _, Vec<RulePair>, RulePair),
+                    _,
+                )| {
+                    let mut vec0 = vec![ifexp];
+                    let mut vec1 = vec![thenblk];
+                    for (exp, blk) in elifpairs.into_iter() {
+                        vec0.push(exp);
+                        vec1.push(blk);
+                    }
+                    if let Rule::Block(_) = elsepair.1.as_ref() {
+                        vec0.push(Box::new(Rule::Nop));
+                        vec1.push(elsepair.1);
+                    };
+                    let ifst = Rule::IfStat(vec0, vec1);
                     Box::new(Rule::Stat(
-                        StatKind::LocalVar,
-                        name.into(),
-                        val.into(),
+                        StatKind::IfThen,
+                        Box::new(ifst).into(),
+                        None,
                         None,
                         None,
                         None,
                     ))
-                }),
-        ),
-        attempt(
-            (
-                reserved("for"),
-                symbol(),
-                token('=').skip(spaces()),
-                exp(),
-                token(',').skip(spaces()),
-                exp(),
-                (token(',').skip(spaces()), exp())
-                    .map(|(_, ex)| ex)
-                    .or(value(nop())),
-                reserved("do"),
-                block(),
-                reserved("end"),
+                },
             )
-                .map(|(_, name, _, ex1, _, ex2, ex3, _, blk, _)| {
-                    Box::new(Rule::Stat(
-                        StatKind::For,
-                        name.into(),
-                        ex1.into(),
-                        ex2.into(),
-                        ex3.into(),
-                        blk.into(),
-                    ))
-                }),
-        ),
-        attempt((
-            reserved("for"),
-            symbollist(),
-            reserved("in"),
-            exp(), // TODO: explist?
-            reserved("do"),
+    }
+}
+
+parser! {
+    fn for_stat[Input]() (Input) -> Box<Rule>
+    where [
+        Input: Stream<Token = char, Position = SourcePosition>,
+        Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    ] {
+        (reserved("for"), choice((numeric_for(), generic_for()))).map(|(_, st)| st)
+    }
+}
+
+parser! {
+    fn repeat_stat[Input]() (Input) -> Box<Rule>
+    where [
+        Input: Stream<Token = char, Position = SourcePosition>,
+        Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    ] {
+        (
+            reserved("repeat"),
             block(),
-            reserved("end"),
+            closing("until", "'until' to close 'repeat'"),
+            exp(),
+        )
+            .map(|(_, blk, _, cond)| {
+                Box::new(Rule::Stat(
+                    StatKind::Repeat,
+                    blk.into(),
+                    cond.into(),
+                    None,
+                    None,
+                    None,
+                ))
+            })
+    }
+}
+
+parser! {
+    fn function_stat[Input]() (Input) -> Box<Rule>
+    where [
+        Input: Stream<Token = char, Position = SourcePosition>,
+        Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    ] {
+        (
+            reserved("function"),
+            funcname(),
+            funcbody(),
+            closing("end", "'end' to close 'function'"),
+        )
+            .map(|(_, name, body, _)| {
+                Box::new(Rule::Stat(
+                    StatKind::DeclareFunction,
+                    name.into(),
+                    body.into(),
+                    None,
+                    None,
+                    None,
+                ))
+            })
+    }
+}
+
+parser! {
+    // break, goto and labels
+    fn jump_stat[Input]() (Input) -> Box<Rule>
+    where [
+        Input: Stream<Token = char, Position = SourcePosition>,
+        Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    ] {
+        let stat = |kind, name: Option<Box<Rule>>| {
+            Box::new(Rule::Stat(kind, name, None, None, None, None))
+        };
+        choice((
+            attempt(reserved("break")).map(move |_| stat(StatKind::Break, None)),
+            (attempt(reserved("goto")), symbol())
+                .map(move |(_, name)| stat(StatKind::GoTo, Some(name))),
+            (attempt(string("::")).skip(sp()), symbol(), string("::").skip(sp()))
+                .map(move |(_, name, _)| stat(StatKind::Label, Some(name))),
         ))
-        .map(|(_, nl, _, ex, _, blk, _)| {
+    }
+}
+
+// The rest of `for name = e1, e2[, e3] do ... end`
+fn numeric_for<Input>() -> impl Parser<Input, Output = Box<Rule>>
+where
+    Input: Stream<Token = char, Position = SourcePosition>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    (
+        attempt((symbol(), token('=').skip(sp()))).map(|(name, _)| name),
+        exp(),
+        token(',').skip(sp()),
+        exp(),
+        (token(',').skip(sp()), exp())
+            .map(|(_, ex)| ex)
+            .or(value(nop())),
+        reserved("do"),
+        block(),
+        closing("end", "'end' to close 'for'"),
+    )
+        .map(|(name, ex1, _, ex2, ex3, _, blk, _)| {
+            Box::new(Rule::Stat(
+                StatKind::For,
+                name.into(),
+                ex1.into(),
+                ex2.into(),
+                ex3.into(),
+                blk.into(),
+            ))
+        })
+}
+
+// The rest of `for names in exp do ... end`
+fn generic_for<Input>() -> impl Parser<Input, Output = Box<Rule>>
+where
+    Input: Stream<Token = char, Position = SourcePosition>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    (
+        symbollist(),
+        reserved("in"),
+        exp(), // TODO: explist?
+        reserved("do"),
+        block(),
+        closing("end", "'end' to close 'for'"),
+    )
+        .map(|(nl, _, ex, _, blk, _)| {
             Box::new(Rule::Stat(
                 StatKind::ForIn,
                 nl.into(),
@@ -504,79 +1069,104 @@ where
                 None,
                 None,
             ))
-        }),
-        attempt((var(), token('=').skip(spaces()), exp())).map(|(v, _, e)| {
-            Box::new(Rule::Stat(
-                StatKind::VarAssign,
-                v.into(),
-                e.into(),
-                None,
-                None,
-                None,
-            ))
-        }),
-        attempt(functioncall()).map(|fc| {
-            Box::new(Rule::Stat(
-                StatKind::FunctionCall,
-                fc.into(),
-                None,
-                None,
-                None,
-                None,
-            ))
-        }),
-        attempt(
-            (
-                reserved("function"),
-                funcname(),
-                funcbody(),
-                reserved("end"),
-            )
-                .map(|(_, name, body, _)| {
-                    Box::new(Rule::Stat(
-                        StatKind::DeclareFunction,
-                        name.into(),
-                        body.into(),
-                        None,
-                        None,
-                        None,
-                    ))
-                }),
-        ),
-    ))
+        })
 }
 
 pub fn laststat<Input>() -> impl Parser<Input, Output = Option<Box<Rule>>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    attempt(
-        (
-            reserved("return"),
-            exp()
-                .map(|v| Some(Box::new(Rule::LastStat(v))))
-                .or(value(None)),
-        )
-            .map(|(_, v)| v),
+    // a bare `return` still ends the function, returning nothing;
+    // one `;` may follow, as in `return x;`
+    (
+        reserved("return"),
+        sep_by(exp(), token(',').skip(sp())),
+        optional(token(';').skip(sp())),
     )
+        .map(|(_, v, _)| Some(Box::new(Rule::LastStat(Box::new(Rule::ExpList(v))))))
 }
 
 pub fn chunk<Input>() -> impl Parser<Input, Output = Box<Rule>>
 where
-    Input: Stream<Token = char>,
+    Input: Stream<Token = char, Position = SourcePosition>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    (many(stat().skip(spaces())), laststat().or(value(None)))
-        .map(|(ss, last): (Vec<Box<Rule>>, Option<Box<Rule>>)| Box::new(Rule::Chunk(ss, last)))
+    // the line each statement starts on, the last statement's included
+    (
+        many((position(), stat()).skip(sp())),
+        (position(), laststat()).or(position().map(|pos| (pos, None))),
+    )
+        .map(
+            |(ss, (pos, last)): (Vec<(SourcePosition, Box<Rule>)>, (SourcePosition, _))| {
+                let mut lines: Vec<usize> = ss.iter().map(|(p, _)| p.line as usize).collect();
+                lines.push(pos.line as usize);
+                let stats = ss.into_iter().map(|(_, st)| st).collect();
+                Box::new(Rule::Chunk(stats, last, lines))
+            },
+        )
+}
+
+// Parses a whole source text into its Chunk, without running it.
+// "\r\n" and a lone "\r" end a line just like "\n", and a first
+// line starting with '#' (a "#!" line) is skipped
+pub fn parse(src: &str) -> Result<Box<Rule>, LuaError> {
+    parse_with(src, false)
+}
+
+// Lenient parsing also takes `!=` as not-equal, for people used to
+// other languages. Standard Lua code parses the same either way
+pub fn parse_with(src: &str, lenient: bool) -> Result<Box<Rule>, LuaError> {
+    let outer = LENIENT.with(|l| l.replace(lenient));
+    let literals = LITERALS.with(|pool| pool.take());
+    let res = parse_chunk(src);
+    LENIENT.with(|l| l.set(outer));
+    LITERALS.with(|pool| pool.replace(literals));
+    res
+}
+
+fn parse_chunk(src: &str) -> Result<Box<Rule>, LuaError> {
+    let mut src = src.replace("\r\n", "\n").replace('\r', "\n");
+    if src.starts_with('#') {
+        let end = src.find('\n').unwrap_or(src.len());
+        src.replace_range(..end, "");
+    }
+    let mut parser = (sp(), chunk(), eof());
+    parser
+        .easy_parse(position::Stream::new(src.as_str()))
+        .map(|((_, chunk, _), _)| chunk)
+        .map_err(|e| LuaError {
+            message: format!("Parse error: {}", e),
+        })
+}
+
+// VM options a script asks for in `--!name` comments at its top
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pragma {
+    Strict,
+}
+
+// The pragmas in the leading comment lines; unknown names are just
+// comments
+pub fn pragmas(src: &str) -> Vec<Pragma> {
+    src.lines()
+        .enumerate()
+        .skip_while(|(i, line)| *i == 0 && line.starts_with('#'))
+        .map(|(_, line)| line.trim())
+        .take_while(|line| line.is_empty() || line.starts_with("--"))
+        .filter_map(|line| match line.strip_prefix("--!")?.trim() {
+            "strict" => Some(Pragma::Strict),
+            _ => None,
+        })
+        .collect()
 }
 
 parser! {
     pub fn block[Input]()(Input) -> Box<Rule>
     where [
-        Input: Stream<Token = char>,
+        Input: Stream<Token = char, Position = SourcePosition>,
         Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
     ] {
-        chunk().map(|blk| Box::new(Rule::Block(blk)))
+        nested(chunk()).map(|blk| Box::new(Rule::Block(blk)))
     }
 }
@@ -0,0 +1,362 @@
+// Lua pattern matching over bytes, following lstrlib.c: classes
+// (%a, %S, ...), sets, the * + - ? quantifiers, anchors, captures,
+// back references, %b and %f
+
+const MAX_CALLS: usize = 200;
+const CAP_UNFINISHED: isize = -1;
+const CAP_POSITION: isize = -2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capture {
+    // byte range into the subject
+    Str(usize, usize),
+    // the 1-based position of an empty `()` capture
+    Position(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Match {
+    pub start: usize,
+    pub end: usize,
+    pub captures: Vec<Capture>,
+}
+
+// First match at or after init. A leading '^' anchors the match to init
+pub fn find(src: &[u8], pat: &[u8], init: usize) -> Result<Option<Match>, String> {
+    let anchor = pat.first() == Some(&b'^');
+    let p = if anchor { 1 } else { 0 };
+    let mut s = init;
+    while s <= src.len() {
+        if let Some(m) = try_match(src, pat, p, s)? {
+            return Ok(Some(m));
+        }
+        if anchor {
+            break;
+        }
+        s += 1;
+    }
+    Ok(None)
+}
+
+// Match starting exactly at s; '^' is an ordinary character here,
+// as string.gmatch wants
+pub fn match_at(src: &[u8], pat: &[u8], s: usize) -> Result<Option<Match>, String> {
+    try_match(src, pat, 0, s)
+}
+
+fn try_match(src: &[u8], pat: &[u8], p: usize, s: usize) -> Result<Option<Match>, String> {
+    let mut ms = MatchState {
+        src,
+        pat,
+        depth: 0,
+        captures: vec![],
+    };
+    match ms.do_match(s, p)? {
+        Some(end) => Ok(Some(Match {
+            start: s,
+            end,
+            captures: ms.finished_captures()?,
+        })),
+        None => Ok(None),
+    }
+}
+
+fn match_class(c: u8, class: u8) -> bool {
+    let res = match class.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'g' => c.is_ascii_graphic(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        // C's isspace also counts the vertical tab
+        b's' => c.is_ascii_whitespace() || c == 0x0b,
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        _ => return class == c,
+    };
+    // an upper-case class is the complement: %S, %D, %A, ...
+    if class.is_ascii_uppercase() {
+        !res
+    } else {
+        res
+    }
+}
+
+struct MatchState<'a> {
+    src: &'a [u8],
+    pat: &'a [u8],
+    depth: usize,
+    // start and length, or one of the CAP_ markers
+    captures: Vec<(usize, isize)>,
+}
+
+impl<'a> MatchState<'a> {
+    fn finished_captures(&self) -> Result<Vec<Capture>, String> {
+        self.captures
+            .iter()
+            .map(|&(start, len)| match len {
+                CAP_UNFINISHED => Err("unfinished capture".to_string()),
+                CAP_POSITION => Ok(Capture::Position(start + 1)),
+                len => Ok(Capture::Str(start, start + len as usize)),
+            })
+            .collect()
+    }
+
+    // Index just past the single-character class starting at p
+    fn class_end(&self, p: usize) -> Result<usize, String> {
+        let pat = self.pat;
+        let mut p = p + 1;
+        match pat[p - 1] {
+            b'%' => {
+                if p >= pat.len() {
+                    return Err("malformed pattern (ends with '%')".to_string());
+                }
+                Ok(p + 1)
+            }
+            b'[' => {
+                if pat.get(p) == Some(&b'^') {
+                    p += 1;
+                }
+                // the first character is never the closing ']'
+                loop {
+                    if p >= pat.len() {
+                        return Err("malformed pattern (missing ']')".to_string());
+                    }
+                    let c = pat[p];
+                    p += 1;
+                    if c == b'%' {
+                        p += 1;
+                    }
+                    match pat.get(p) {
+                        Some(b']') => return Ok(p + 1),
+                        Some(_) => {}
+                        None => return Err("malformed pattern (missing ']')".to_string()),
+                    }
+                }
+            }
+            _ => Ok(p),
+        }
+    }
+
+    // p is the '[' of the set and ec its closing ']'
+    fn match_bracket(&self, c: u8, p: usize, ec: usize) -> bool {
+        let pat = self.pat;
+        let mut p = p + 1;
+        let mut found = true;
+        if pat[p] == b'^' {
+            found = false;
+            p += 1;
+        }
+        while p < ec {
+            if pat[p] == b'%' {
+                p += 1;
+                if match_class(c, pat[p]) {
+                    return found;
+                }
+                p += 1;
+            } else if pat[p + 1] == b'-' && p + 2 < ec {
+                if pat[p] <= c && c <= pat[p + 2] {
+                    return found;
+                }
+                p += 3;
+            } else {
+                if pat[p] == c {
+                    return found;
+                }
+                p += 1;
+            }
+        }
+        !found
+    }
+
+    fn single_match(&self, s: usize, p: usize, ep: usize) -> bool {
+        let c = match self.src.get(s) {
+            Some(c) => *c,
+            None => return false,
+        };
+        match self.pat[p] {
+            b'.' => true,
+            b'%' => match_class(c, self.pat[p + 1]),
+            b'[' => self.match_bracket(c, p, ep - 1),
+            pc => pc == c,
+        }
+    }
+
+    fn do_match(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        self.depth += 1;
+        if self.depth > MAX_CALLS {
+            return Err("pattern too complex".to_string());
+        }
+        let res = self.match_here(s, p);
+        self.depth -= 1;
+        res
+    }
+
+    fn match_here(&mut self, mut s: usize, mut p: usize) -> Result<Option<usize>, String> {
+        let pat = self.pat;
+        loop {
+            if p == pat.len() {
+                return Ok(Some(s));
+            }
+            match (pat[p], pat.get(p + 1)) {
+                (b'(', Some(b')')) => return self.start_capture(s, p + 2, CAP_POSITION),
+                (b'(', _) => return self.start_capture(s, p + 1, CAP_UNFINISHED),
+                (b')', _) => return self.end_capture(s, p + 1),
+                (b'$', None) => {
+                    return Ok(if s == self.src.len() { Some(s) } else { None });
+                }
+                (b'%', Some(b'b')) => match self.match_balance(s, p + 2)? {
+                    Some(end) => {
+                        s = end;
+                        p += 4;
+                    }
+                    None => return Ok(None),
+                },
+                (b'%', Some(b'f')) => {
+                    p += 2;
+                    if pat.get(p) != Some(&b'[') {
+                        return Err("missing '[' after '%f' in pattern".to_string());
+                    }
+                    let ep = self.class_end(p)?;
+                    let prev = if s == 0 { 0 } else { self.src[s - 1] };
+                    let cur = self.src.get(s).copied().unwrap_or(0);
+                    if self.match_bracket(prev, p, ep - 1) || !self.match_bracket(cur, p, ep - 1) {
+                        return Ok(None);
+                    }
+                    p = ep;
+                }
+                (b'%', Some(d)) if d.is_ascii_digit() => match self.match_capture(s, *d)? {
+                    Some(end) => {
+                        s = end;
+                        p += 2;
+                    }
+                    None => return Ok(None),
+                },
+                _ => {
+                    let ep = self.class_end(p)?;
+                    let matched = self.single_match(s, p, ep);
+                    match pat.get(ep) {
+                        Some(b'?') => {
+                            if matched {
+                                if let Some(end) = self.do_match(s + 1, ep + 1)? {
+                                    return Ok(Some(end));
+                                }
+                            }
+                            p = ep + 1;
+                        }
+                        Some(b'+') => {
+                            return if matched {
+                                self.max_expand(s + 1, p, ep)
+                            } else {
+                                Ok(None)
+                            };
+                        }
+                        Some(b'*') => return self.max_expand(s, p, ep),
+                        Some(b'-') => return self.min_expand(s, p, ep),
+                        _ => {
+                            if !matched {
+                                return Ok(None);
+                            }
+                            s += 1;
+                            p = ep;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> Result<Option<usize>, String> {
+        let mut i = 0;
+        while self.single_match(s + i, p, ep) {
+            i += 1;
+        }
+        // back off one repetition at a time
+        loop {
+            if let Some(end) = self.do_match(s + i, ep + 1)? {
+                return Ok(Some(end));
+            }
+            if i == 0 {
+                return Ok(None);
+            }
+            i -= 1;
+        }
+    }
+
+    fn min_expand(&mut self, mut s: usize, p: usize, ep: usize) -> Result<Option<usize>, String> {
+        loop {
+            if let Some(end) = self.do_match(s, ep + 1)? {
+                return Ok(Some(end));
+            }
+            if !self.single_match(s, p, ep) {
+                return Ok(None);
+            }
+            s += 1;
+        }
+    }
+
+    fn start_capture(&mut self, s: usize, p: usize, what: isize) -> Result<Option<usize>, String> {
+        self.captures.push((s, what));
+        let res = self.do_match(s, p)?;
+        if res.is_none() {
+            self.captures.pop();
+        }
+        Ok(res)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        let open = self
+            .captures
+            .iter()
+            .rposition(|&(_, len)| len == CAP_UNFINISHED)
+            .ok_or_else(|| "invalid pattern capture".to_string())?;
+        self.captures[open].1 = (s - self.captures[open].0) as isize;
+        let res = self.do_match(s, p)?;
+        if res.is_none() {
+            self.captures[open].1 = CAP_UNFINISHED;
+        }
+        Ok(res)
+    }
+
+    fn match_balance(&self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        if p + 1 >= self.pat.len() {
+            return Err("malformed pattern (missing arguments to '%b')".to_string());
+        }
+        let (open, close) = (self.pat[p], self.pat[p + 1]);
+        if self.src.get(s) != Some(&open) {
+            return Ok(None);
+        }
+        let mut depth = 1;
+        for (i, &c) in self.src.iter().enumerate().skip(s + 1) {
+            if c == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(Some(i + 1));
+                }
+            } else if c == open {
+                depth += 1;
+            }
+        }
+        Ok(None)
+    }
+
+    // %1 .. %9: the text of an earlier, closed capture
+    fn match_capture(&self, s: usize, digit: u8) -> Result<Option<usize>, String> {
+        let index = (digit as usize).wrapping_sub(b'1' as usize);
+        let (start, len) = match self.captures.get(index) {
+            Some(&(_, CAP_UNFINISHED)) | None => {
+                return Err(format!("invalid capture index %{}", digit as char));
+            }
+            Some(&(_, CAP_POSITION)) => return Ok(None),
+            Some(&(start, len)) => (start, len as usize),
+        };
+        let captured = &self.src[start..start + len];
+        if self.src.len() - s >= len && &self.src[s..s + len] == captured {
+            Ok(Some(s + len))
+        } else {
+            Ok(None)
+        }
+    }
+}
@@ -1,50 +1,129 @@
 use crate::state::{LuaError, LuaResult, LuaState};
 use crate::value::Value;
 
+use std::io::Write;
+
+// Writes the arguments separated by tabs. Unlike Lua's print, no
+// newline is added
 fn lua_print(l: &mut LuaState) -> Result<i32, LuaError> {
-    let v = l.arg_string(1)?;
-    print!("{}", v);
+    let mut texts = vec![];
+    for v in l.args()?.iter() {
+        texts.push(l.value_to_display(v)?);
+    }
+    write!(l.stdout, "{}", texts.join("\t")).map_err(|e| l.error(format!("print: {}", e)))?;
     Ok(0)
 }
 
+fn lua_tostring(l: &mut LuaState) -> LuaResult<i32> {
+    let v = l.arg_value(1)?;
+    let s = l.value_to_display(&v)?;
+    l.returns(Value::LuaString(s.into()));
+    Ok(1)
+}
+
+// Passes all its arguments through when the first is truthy, so
+// that `local f = assert(io.open(path))` works
+fn lua_assert(l: &mut LuaState) -> LuaResult<i32> {
+    if l.arg_count()? == 0 {
+        return Err(l.error("bad argument #1 to 'assert' (value expected)"));
+    }
+    let args = l.args()?;
+    if args[0].is_truthy() {
+        return l.returns_all(args);
+    }
+    match args.get(1) {
+        Some(msg) => {
+            let msg = l.value_to_display(msg)?;
+            Err(l.error(msg))
+        }
+        None => Err(l.error("assertion failed!")),
+    }
+}
+
+// Raises the message, prefixed with the line of the calling
+// statement unless level is 0. Positions are only known for the
+// running statement, so any other level prefixes that line too
+fn lua_error(l: &mut LuaState) -> LuaResult<i32> {
+    let msg = match l.arg_count()? {
+        0 => Value::Nil,
+        _ => l.arg_value(1)?,
+    };
+    let level = l.arg_opt_int(2, 1)?;
+    let text = l.value_to_display(&msg)?;
+    match msg {
+        Value::LuaString(_) if level > 0 && l.line > 0 => {
+            Err(l.error(format!("{}:{}: {}", l.chunk_name, l.line, text)))
+        }
+        _ => Err(l.error(text)),
+    }
+}
+
 fn lua_pairs(l: &mut LuaState) -> LuaResult<i32> {
     let tbl = l.arg_value(1)?;
+    let next = l.get_global("next").unwrap();
 
-    l.returns(l.get_global("next").unwrap());
-    l.returns(tbl);
-    l.returns(Value::Nil);
-    Ok(3)
+    l.returns_all(vec![next, tbl, Value::Nil])
 }
 
 fn lua_next(l: &mut LuaState) -> LuaResult<i32> {
-    let tbl = l.arg_value(1)?;
-    let t = tbl.ensure_table()?;
-    let index = l.arg_value(2)?;
-    match index {
-        Value::Nil => {
-            l.returns(Value::Number(1));
-            l.returns(t.vec.borrow()[0].clone());
+    let t = l.arg_table(1)?;
+    let index = match l.arg_count()? {
+        1 => Value::Nil,
+        _ => l.arg_value(2)?,
+    };
+    match t.next(&index)? {
+        Some((k, v)) => {
+            l.returns(k);
+            l.returns(v);
             Ok(2)
         }
-        Value::Number(i) => {
-            if t.vec.borrow().len() as i64 <= i {
-                l.returns(Value::Nil);
-                Ok(1)
-            } else {
-                l.returns(Value::Number(i + 1));
-                let index = i as usize;
-                l.returns(t.vec.borrow()[index].clone());
-                Ok(2)
-            }
+        None => {
+            l.returns(Value::Nil);
+            Ok(1)
+        }
+    }
+}
+
+// Sets, or with nil clears, the metatable of a table and returns
+// the table. A metatable with a __metatable field is protected
+fn lua_setmetatable(l: &mut LuaState) -> LuaResult<i32> {
+    let t = l.arg_table(1)?;
+    let mt = match l.arg_count()? {
+        1 => Value::Nil,
+        _ => l.arg_value(2)?,
+    };
+    let mt = match mt {
+        Value::Nil => None,
+        Value::Table(mt) => Some(mt),
+        _ => return Err(l.error("bad argument #2 (nil or table expected)")),
+    };
+    if let Some(old) = t.get_metatable() {
+        if !matches!(old.get_str("__metatable"), Value::Nil) {
+            return Err(l.error("cannot change a protected metatable"));
         }
-        _ => Err(l.error(format!("invalid argument {:?}", index))),
     }
+    t.set_metatable(mt);
+    l.returns(Value::Table(t));
+    Ok(1)
+}
+
+fn lua_getmetatable(l: &mut LuaState) -> LuaResult<i32> {
+    let v = l.arg_value(1)?;
+    let ret = match l.metatable_of(&v) {
+        Some(mt) => match mt.get_str("__metatable") {
+            Value::Nil => Value::Table(mt),
+            protected => protected,
+        },
+        None => Value::Nil,
+    };
+    l.returns(ret);
+    Ok(1)
 }
 
 fn lua_global_set(l: &mut LuaState) -> Result<i32, LuaError> {
     let v = l.arg_string(1)?;
     println!("set foo={}", v);
-    l.assign_global("foo", Value::LuaString(v));
+    l.assign_global("foo", Value::LuaString(v.into()));
     Ok(0)
 }
 
@@ -117,10 +196,35 @@ fn lua_print_array(l: &mut LuaState) -> LuaResult<i32> {
 }
 
 pub fn prelude(l: &mut LuaState) {
+    open_base(l);
+    crate::iolib::open_io(l);
+    crate::oslib::open_os(l);
+    open_pure_libs(l);
+}
+
+// Everything but io and os, for scripts that must not reach the host
+pub fn open_sandboxed(l: &mut LuaState) {
+    open_base(l);
+    open_pure_libs(l);
+}
+
+fn open_pure_libs(l: &mut LuaState) {
+    crate::mathlib::open_math(l);
+    crate::strlib::open_string(l);
+    crate::tablib::open_table(l);
+    crate::utf8lib::open_utf8(l);
+}
+
+pub fn open_base(l: &mut LuaState) {
     // register fn
     l.register_global_fn("print", lua_print);
+    l.register_global_fn("tostring", lua_tostring);
+    l.register_global_fn("assert", lua_assert);
+    l.register_global_fn("error", lua_error);
     l.register_global_fn("pairs", lua_pairs);
     l.register_global_fn("next", lua_next);
+    l.register_global_fn("setmetatable", lua_setmetatable);
+    l.register_global_fn("getmetatable", lua_getmetatable);
 
     l.register_global_fn("fib", lua_fib);
     l.register_global_fn("globalset", lua_global_set);
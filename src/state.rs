@@ -1,6 +1,12 @@
 use crate::value::*;
-use crate::{function::*, parser::Rule};
-use std::collections::HashMap;
+use crate::{function::*, parser::Rule, table::LuaTable, userdata::LuaUserData};
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    io::Write,
+    rc::Rc,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 #[derive(Debug)]
 pub struct LuaError {
@@ -15,10 +21,43 @@ impl std::fmt::Display for LuaError {
 }
 impl std::error::Error for LuaError {}
 
+// Tells states apart in a GlobalSlot
+static NEXT_STATE_ID: AtomicUsize = AtomicUsize::new(0);
+
+// The global slot a variable name resolved to, filled in the first
+// time a state runs the name as a global. The state's id is kept with
+// it, since a function may be called from a state other than the one
+// that resolved it; that state then resolves the name again
+#[derive(Debug, Clone, Default)]
+pub struct GlobalSlot(Cell<Option<(usize, usize)>>);
+
+// Only a cache, so two names are the same syntax whatever they hold
+impl PartialEq for GlobalSlot {
+    fn eq(&self, _: &GlobalSlot) -> bool {
+        true
+    }
+}
+
 pub struct Global {
-    pub global: HashMap<String, Value>,
+    // unique to this state, see GlobalSlot
+    pub id: usize,
+    // Globals live in slots; a name is hashed only to find its slot,
+    // and a host can keep the slot id to skip even that. Scripts
+    // remember the slot in each name after running it once
+    pub global_ids: HashMap<String, usize>,
+    pub globals: Vec<Option<Value>>,
+    // metatables shared by every userdata of a kind, e.g. "FILE*"
+    pub metatables: HashMap<String, Rc<LuaTable>>,
+    // shared by all strings, so that `s:len()` finds the string library
+    pub string_metatable: Option<Rc<LuaTable>>,
 }
 
+// Slots a call may use beyond its arguments, like LUA_MINSTACK
+const MIN_STACK: usize = 20;
+
+// Longest __index chain followed before giving up, like MAXTAGLOOP
+const MAX_TAG_LOOP: usize = 2000;
+
 pub struct Registry {
     pub array: Vec<Value>,
     pub top: usize,
@@ -32,14 +71,44 @@ impl Registry {
         self.top
     }
 
+    // The array grows on push as needed; this is what keeps
+    // it below max_size
+    pub fn check_stack(&self, n: usize) -> LuaResult<()> {
+        if self.top + n > self.max_size {
+            return Err(LuaError {
+                message: "stack overflow".to_string(),
+            });
+        }
+        Ok(())
+    }
+
     #[allow(dead_code)]
     pub fn last(&self) -> Option<&Value> {
-        self.array.get(self.top - 1)
+        self.array.get(self.top.checked_sub(1)?)
     }
 
     pub fn pop(&mut self) -> Option<Value> {
+        let v = self.array.pop()?;
         self.top -= 1;
-        self.array.pop()
+        Some(v)
+    }
+
+    // Drops every value above the given height
+    pub fn truncate(&mut self, top: usize) {
+        self.array.truncate(top);
+        self.top = self.array.len();
+    }
+
+    // Takes the topmost n values, keeping their order
+    pub fn pop_n(&mut self, n: usize) -> Result<Vec<Value>, LuaError> {
+        if n > self.top {
+            return Err(LuaError {
+                message: "Cannot find value from regisrty, maybe empty".to_string(),
+            });
+        }
+        let ret = self.array.split_off(self.top - n);
+        self.top -= n;
+        Ok(ret)
     }
 
     pub fn ensure_pop(&mut self) -> Result<Value, LuaError> {
@@ -49,21 +118,42 @@ impl Registry {
     }
 
     pub fn to_int(&self, pos: usize) -> Result<i64, LuaError> {
-        let value = &self.array[pos];
+        let value = self.value_at(pos)?;
+        let message = match value {
+            Value::Float(_) => "number has no integer representation",
+            _ => "TypeError: cannot cast into int",
+        };
         value.to_int().ok_or(LuaError {
-            message: "TypeError: cannot cast into int".to_string(),
+            message: message.to_string(),
+        })
+    }
+
+    pub fn to_float(&self, pos: usize) -> Result<f64, LuaError> {
+        let value = self.value_at(pos)?;
+        value.to_float().ok_or(LuaError {
+            message: format!("number expected, got {}", value.type_name()),
         })
     }
 
     pub fn to_string(&self, pos: usize) -> Result<String, LuaError> {
-        let value = &self.array[pos];
+        let value = self.value_at(pos)?;
         value.to_string().ok_or(LuaError {
             message: "TypeError: cannot cast into str".to_string(),
         })
     }
 
-    pub fn to_value(&self, pos: usize) -> Result<Value, LuaError> {
-        Ok((&self.array[pos]).to_owned())
+    fn value_at(&self, pos: usize) -> Result<&Value, LuaError> {
+        match self.array.get(pos) {
+            Some(v) if pos < self.top => Ok(v),
+            _ => Err(LuaError {
+                message: format!("bad argument: no value at position {}", pos),
+            }),
+        }
+    }
+
+    // A clone of the slot; slots at or above top are not live
+    pub fn get(&self, pos: usize) -> Result<Value, LuaError> {
+        self.value_at(pos).cloned()
     }
 }
 
@@ -71,80 +161,513 @@ pub struct LuaState {
     pub g: Global,
     pub reg: Registry,
     pub frame_stack: Vec<CallFrame>,
+    pub steps: usize,
+    pub step_limit: Option<usize>,
+    pub depth: usize,
+    pub max_depth: usize,
+    pub call_depth: usize,
+    pub max_call_depth: usize,
+    pub stdout: Box<dyn Write>,
+    pub strict_globals: bool,
+    pub declared_globals: HashSet<String>,
+    // scripts may write `!=` for `~=`
+    pub lenient: bool,
+    // source line of the statement running, 0 when unknown
+    pub line: usize,
+    // names the script in error positions, like Lua's chunkname
+    pub chunk_name: String,
 }
 
-impl LuaState {
-    pub fn new(reg_size: usize) -> Self {
-        let global = HashMap::new();
-        let g = Global { global };
+pub struct LuaStateBuilder {
+    initial_capacity: usize,
+    max_size: usize,
+    open_libs: bool,
+    sandbox: bool,
+    step_limit: Option<usize>,
+    max_depth: usize,
+    max_call_depth: usize,
+    stdout: Option<Box<dyn Write>>,
+    strict_globals: bool,
+    lenient: bool,
+    chunk_name: String,
+}
+
+impl Default for LuaStateBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Global {
+    // The slot of a name, allocated on first use. Slots are never
+    // freed, so an id stays valid as long as the state lives
+    pub fn global_id(&mut self, name: &str) -> usize {
+        if let Some(id) = self.global_ids.get(name) {
+            return *id;
+        }
+        let id = self.globals.len();
+        self.globals.push(None);
+        self.global_ids.insert(name.to_string(), id);
+        id
+    }
+
+    pub fn get(&self, name: &str) -> Option<Value> {
+        let id = *self.global_ids.get(name)?;
+        self.globals[id].clone()
+    }
+
+    pub fn set(&mut self, name: &str, value: Value) {
+        let id = self.global_id(name);
+        self.globals[id] = Some(value);
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.global_ids
+            .get(name)
+            .is_some_and(|id| self.globals[*id].is_some())
+    }
+
+    // Names of the globals that have been set
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.global_ids
+            .iter()
+            .filter(move |(_, id)| self.globals[**id].is_some())
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+impl LuaStateBuilder {
+    pub fn new() -> Self {
+        LuaStateBuilder {
+            initial_capacity: 256,
+            max_size: 65535,
+            open_libs: false,
+            sandbox: false,
+            step_limit: None,
+            max_depth: 200,
+            max_call_depth: 200,
+            stdout: None,
+            strict_globals: false,
+            lenient: false,
+            chunk_name: "input".to_string(),
+        }
+    }
+
+    // Registry slots allocated up front
+    pub fn initial_capacity(mut self, n: usize) -> Self {
+        self.initial_capacity = n;
+        self
+    }
+
+    // Registry slots usable at most before "stack overflow"
+    pub fn max_size(mut self, n: usize) -> Self {
+        self.max_size = n;
+        self
+    }
+
+    // Registers the builtins and standard libraries on build
+    pub fn open_libs(mut self, open: bool) -> Self {
+        self.open_libs = open;
+        self
+    }
+
+    // Leaves out the libraries reaching the host, io and os
+    pub fn sandbox(mut self, sandbox: bool) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    // Statements run at most before "step limit exceeded"
+    pub fn step_limit(mut self, n: usize) -> Self {
+        self.step_limit = Some(n);
+        self
+    }
+
+    // Expressions nested deeper than this are "expression too complex"
+    pub fn max_depth(mut self, n: usize) -> Self {
+        self.max_depth = n;
+        self
+    }
+
+    // Calls nested deeper than this are "stack overflow"; each one
+    // takes native stack, so keep it within the thread's stack size
+    pub fn max_call_depth(mut self, n: usize) -> Self {
+        self.max_call_depth = n;
+        self
+    }
+
+    // Where print writes to, instead of the process's stdout
+    pub fn output(mut self, out: impl Write + 'static) -> Self {
+        self.stdout = Some(Box::new(out));
+        self
+    }
+
+    // Scripts may only touch globals that are set or declared, see
+    // LuaState::declare_global
+    pub fn strict_globals(mut self, strict: bool) -> Self {
+        self.strict_globals = strict;
+        self
+    }
+
+    // Parses scripts leniently, see parser::parse_with
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    // What error positions call the script, e.g. its file name
+    pub fn chunk_name(mut self, name: impl Into<String>) -> Self {
+        self.chunk_name = name.into();
+        self
+    }
+
+    pub fn build(self) -> LuaState {
+        let metatables = HashMap::new();
+        let g = Global {
+            id: NEXT_STATE_ID.fetch_add(1, Ordering::Relaxed),
+            global_ids: HashMap::new(),
+            globals: Vec::new(),
+            metatables,
+            string_metatable: None,
+        };
         let reg = Registry {
-            array: Vec::with_capacity(reg_size),
+            array: Vec::with_capacity(self.initial_capacity.min(self.max_size)),
             top: 0,
-            max_size: reg_size,
+            max_size: self.max_size,
         };
         let frame_stack = Vec::new();
+        let stdout = self.stdout.unwrap_or_else(|| Box::new(std::io::stdout()));
 
-        Self {
+        let mut l = LuaState {
             g,
             reg,
             frame_stack,
+            steps: 0,
+            step_limit: self.step_limit,
+            depth: 0,
+            max_depth: self.max_depth,
+            call_depth: 0,
+            max_call_depth: self.max_call_depth,
+            stdout,
+            strict_globals: self.strict_globals,
+            declared_globals: HashSet::new(),
+            lenient: self.lenient,
+            line: 0,
+            chunk_name: self.chunk_name,
+        };
+        if self.open_libs {
+            if self.sandbox {
+                crate::prelude::open_sandboxed(&mut l);
+            } else {
+                crate::prelude::prelude(&mut l);
+            }
         }
+        l
+    }
+}
+
+impl LuaState {
+    pub fn new(reg_size: usize) -> Self {
+        LuaStateBuilder::new()
+            .initial_capacity(reg_size)
+            .max_size(reg_size)
+            .build()
+    }
+
+    pub fn builder() -> LuaStateBuilder {
+        LuaStateBuilder::new()
     }
 
     pub fn arg_index2pos(&self, pos: usize) -> LuaResult<usize> {
-        Ok(self.ensure_current_frame()?.local_base + pos - 1)
+        let frame = self.ensure_current_frame()?;
+        if pos == 0 || pos > frame.args_nr {
+            return Err(self.error(format!("bad argument #{}: value expected", pos)));
+        }
+        Ok(frame.base + pos - 1)
+    }
+
+    pub fn arg_count(&self) -> LuaResult<usize> {
+        Ok(self.ensure_current_frame()?.args_nr)
+    }
+
+    pub fn args(&self) -> LuaResult<Vec<Value>> {
+        (1..=self.arg_count()?).map(|i| self.arg_value(i)).collect()
     }
 
     pub fn arg_int(&self, pos: usize) -> Result<i64, LuaError> {
         self.reg.to_int(self.arg_index2pos(pos)?)
     }
 
+    pub fn arg_float(&self, pos: usize) -> Result<f64, LuaError> {
+        self.reg
+            .to_float(self.arg_index2pos(pos)?)
+            .map_err(|e| self.error(format!("bad argument #{} ({})", pos, e.message)))
+    }
+
+    // An absent or nil argument takes the default
+    pub fn arg_opt_int(&self, pos: usize, default: i64) -> Result<i64, LuaError> {
+        if pos > self.arg_count()? {
+            return Ok(default);
+        }
+        match self.arg_value(pos)? {
+            Value::Nil => Ok(default),
+            _ => self.arg_int(pos),
+        }
+    }
+
     pub fn arg_string(&self, pos: usize) -> Result<String, LuaError> {
         self.reg.to_string(self.arg_index2pos(pos)?)
     }
 
     pub fn arg_value(&self, pos: usize) -> Result<Value, LuaError> {
-        self.reg.to_value(self.arg_index2pos(pos)?)
+        self.reg.get(self.arg_index2pos(pos)?)
     }
 
-    pub fn assign_global(&mut self, name: impl Into<String>, value: Value) {
-        let name: String = name.into();
-        if self.g.global.contains_key(&name) {
-            self.g.global.remove(&name);
+    pub fn assign_global(&mut self, name: impl AsRef<str>, value: Value) {
+        self.g.set(name.as_ref(), value);
+    }
+
+    pub fn global_id(&mut self, name: impl AsRef<str>) -> usize {
+        self.g.global_id(name.as_ref())
+    }
+
+    // Reads through an id from global_id; nil while unset
+    pub fn get_global_by_id(&self, id: usize) -> Value {
+        self.g
+            .globals
+            .get(id)
+            .cloned()
+            .flatten()
+            .unwrap_or(Value::Nil)
+    }
+
+    pub fn set_global_by_id(&mut self, id: usize, value: Value) {
+        if let Some(slot) = self.g.globals.get_mut(id) {
+            *slot = Some(value);
+        }
+    }
+
+    // Allows scripts in strict mode to use the global before it is set
+    pub fn declare_global(&mut self, name: impl Into<String>) {
+        self.declared_globals.insert(name.into());
+    }
+
+    fn is_undeclared(&self, name: &str) -> bool {
+        self.strict_globals && !self.g.contains(name) && !self.declared_globals.contains(name)
+    }
+
+    // A global as read by a script: nil when unset, an error in
+    // strict mode unless it was declared
+    pub fn read_global(&self, name: &str) -> LuaResult<Value> {
+        if self.is_undeclared(name) {
+            return Err(self.error(format!("variable '{}' is not declared", name)));
         }
-        self.g.global.insert(name, value);
+        Ok(self.get_global(name).unwrap_or(Value::Nil))
     }
 
-    pub fn assign_local(&mut self, name: impl Into<String>, value: Value) {
-        if let Some(_) = self.current_frame() {
+    // The slot of a global a script names, looked up by name only
+    // the first time this state runs that name
+    fn resolve_slot(&mut self, name: &str, slot: &GlobalSlot) -> usize {
+        match slot.0.get() {
+            Some((state, id)) if state == self.g.id => id,
+            _ => {
+                let id = self.g.global_id(name);
+                slot.0.set(Some((self.g.id, id)));
+                id
+            }
+        }
+    }
+
+    // read_global through the slot the name resolves to
+    pub fn read_global_slot(&mut self, name: &str, slot: &GlobalSlot) -> LuaResult<Value> {
+        let id = self.resolve_slot(name, slot);
+        match &self.g.globals[id] {
+            Some(v) => Ok(v.clone()),
+            None => self.read_global(name),
+        }
+    }
+
+    // write_global through the slot the name resolves to
+    pub fn write_global_slot(
+        &mut self,
+        name: &str,
+        slot: &GlobalSlot,
+        value: Value,
+    ) -> LuaResult<()> {
+        let id = self.resolve_slot(name, slot);
+        match &mut self.g.globals[id] {
+            Some(v) => {
+                *v = value;
+                Ok(())
+            }
+            None => self.write_global(name, value),
+        }
+    }
+
+    // Assignment from a script; in strict mode it cannot create a
+    // global that was not declared
+    pub fn write_global(&mut self, name: impl AsRef<str>, value: Value) -> LuaResult<()> {
+        let name = name.as_ref();
+        if self.is_undeclared(name) {
+            return Err(self.error(format!("assign to undeclared variable '{}'", name)));
+        }
+        self.assign_global(name, value);
+        Ok(())
+    }
+
+    pub fn assign_local(&mut self, name: impl Into<String>, value: Value) -> LuaResult<()> {
+        if self.current_frame().is_some() {
+            self.reg.check_stack(1)?;
             let name: String = name.into();
             let idx = self.reg.push(value) - 1;
             self.frame_stack.last_mut().unwrap().env.insert(name, idx);
         }
+        Ok(())
     }
 
-    pub fn get_global(&self, name: impl Into<String>) -> Option<Value> {
-        let name: String = name.into();
-        self.g.global.get(&name).map(|v| v.clone())
+    pub fn get_global(&self, name: impl AsRef<str>) -> Option<Value> {
+        self.g.get(name.as_ref())
     }
 
-    pub fn register_global_fn(&mut self, name: impl Into<String>, func: LuaFn) {
-        let name: String = name.into();
+    pub fn register_global_fn(&mut self, name: impl AsRef<str>, func: LuaFn) {
+        self.assign_global(name, Value::Function(LuaFunction::from_fn(func)));
+    }
+
+    pub fn register_global_closure<F>(&mut self, name: impl AsRef<str>, func: F)
+    where
+        F: FnMut(&mut LuaState) -> Result<i32, LuaError> + 'static,
+    {
+        self.assign_global(name, Value::Function(LuaFunction::from_closure(func)));
+    }
+
+    pub fn register_global_lib(&mut self, name: impl AsRef<str>, funcs: &[(&str, LuaFn)]) {
+        let lib = Value::newtable();
+        if let Value::Table(t) = &lib {
+            for (fname, func) in funcs.iter() {
+                t.set_str(*fname, Value::Function(LuaFunction::from_fn(*func)));
+            }
+        }
+        self.assign_global(name, lib);
+    }
+
+    // Like luaL_newmetatable: the metatable registered under name,
+    // created with its __name on first use
+    pub fn new_metatable(&mut self, name: &str) -> Rc<LuaTable> {
         self.g
-            .global
-            .insert(name, Value::Function(LuaFunction::from_fn(func)));
+            .metatables
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                let mt = LuaTable::empty();
+                mt.set_str("__name", Value::LuaString(name.into()));
+                Rc::new(mt)
+            })
+            .clone()
+    }
+
+    pub fn get_metatable_named(&self, name: &str) -> Option<Rc<LuaTable>> {
+        self.g.metatables.get(name).cloned()
+    }
+
+    pub fn metatable_of(&self, v: &Value) -> Option<Rc<LuaTable>> {
+        match v {
+            Value::Table(t) => t.get_metatable(),
+            Value::UserData(ud) => ud.get_metatable(),
+            Value::LuaString(_) => self.g.string_metatable.clone(),
+            _ => None,
+        }
+    }
+
+    // The text print and tostring show: the result of the
+    // __tostring metamethod when there is one, the raw form otherwise
+    pub fn value_to_display(&mut self, v: &Value) -> LuaResult<String> {
+        let handler = match self.metatable_of(v) {
+            Some(mt) => mt.get_str("__tostring"),
+            None => Value::Nil,
+        };
+        if let Value::Nil = handler {
+            return Ok(v.to_display_string());
+        }
+        let ret = self.funcall(handler, vec![v.clone()])?;
+        match ret.first().and_then(Value::as_str) {
+            Some(s) => Ok(s.to_string()),
+            None => Err(self.error("'__tostring' must return a string")),
+        }
+    }
+
+    pub fn index_str(&mut self, obj: &Value, key: &str) -> LuaResult<Value> {
+        self.index(obj, Value::LuaString(key.into()))
+    }
+
+    // `obj[key]`: a table's own field first, then the metatable's
+    // __index, either a table to look in next or a function called
+    // as __index(obj, key)
+    pub fn index(&mut self, obj: &Value, key: Value) -> LuaResult<Value> {
+        let mut obj = obj.clone();
+        for _ in 0..MAX_TAG_LOOP {
+            if let Value::Table(t) = &obj {
+                let v = t.get(&key);
+                if !matches!(v, Value::Nil) {
+                    return Ok(v);
+                }
+            }
+            let index = match self.metatable_of(&obj).map(|mt| mt.get_str("__index")) {
+                Some(Value::Nil) | None => match obj {
+                    Value::Table(_) => return Ok(Value::Nil),
+                    _ => {
+                        return Err(
+                            self.error(format!("attempt to index a {} value", obj.type_name()))
+                        )
+                    }
+                },
+                Some(index) => index,
+            };
+            if let Value::Function(_) = index {
+                let ret = self.funcall(index, vec![obj, key])?;
+                return Ok(ret.into_iter().next().unwrap_or(Value::Nil));
+            }
+            obj = index;
+        }
+        Err(self.error("'__index' chain too long; possibly a loop"))
+    }
+
+    pub fn arg_table(&self, pos: usize) -> LuaResult<Rc<LuaTable>> {
+        match self.arg_value(pos)? {
+            Value::Table(t) => Ok(t),
+            v => Err(self.error(format!(
+                "bad argument #{} (table expected, got {})",
+                pos,
+                v.type_name()
+            ))),
+        }
+    }
+
+    // Like luaL_checkudata: the argument must be a userdata
+    // carrying the metatable registered under name
+    pub fn arg_userdata(&self, pos: usize, name: &str) -> LuaResult<Rc<LuaUserData>> {
+        let v = self.arg_value(pos)?;
+        if let (Value::UserData(ud), Some(expected)) = (&v, self.get_metatable_named(name)) {
+            if ud
+                .get_metatable()
+                .is_some_and(|mt| Rc::ptr_eq(&mt, &expected))
+            {
+                return Ok(ud.clone());
+            }
+        }
+        Err(self.error(format!(
+            "bad argument #{} ({} expected, got {})",
+            pos,
+            name,
+            v.type_name()
+        )))
     }
 
     pub fn register_global_code(
         &mut self,
-        name: impl Into<String>,
+        name: impl AsRef<str>,
         params: Vec<String>,
         block: &Rule,
     ) {
-        let name: String = name.into();
-        self.g
-            .global
-            .insert(name, Value::Function(LuaFunction::from_code(params, block)));
+        self.assign_global(name, Value::Function(LuaFunction::from_code(params, block)));
     }
 
     pub fn start_block_raw(&mut self) -> usize {
@@ -154,109 +677,249 @@ impl LuaState {
             ret_nr: 0,
             env: Default::default(),
             to_return: false,
-            local_base: oldtop,
+            base: oldtop,
+            is_block: true,
+            is_loop: false,
+            breaking: false,
+            goto_label: None,
+            ret_values: vec![],
         };
         self.frame_stack.push(frame);
         oldtop
     }
 
-    pub fn end_block_raw(&mut self, oldtop: usize) -> LuaResult<()> {
-        self.frame_stack.pop();
+    // Runs a whole script inside a base frame for the main chunk,
+    // so its locals and a top-level `return` behave as in a function.
+    // The value of that `return` is handed back, nil without one.
+    // A `--!strict` pragma turns strict globals on for this chunk only
+    pub fn run_string(&mut self, src: &str) -> LuaResult<Value> {
+        let chunk = crate::parser::parse_with(src, self.lenient)?;
+        let strict = self.strict_globals;
+        for pragma in crate::parser::pragmas(src) {
+            match pragma {
+                crate::parser::Pragma::Strict => self.strict_globals = true,
+            }
+        }
+        let oldtop = self.reg.top;
+        let depth = self.frame_stack.len();
+        self.frame_stack.push(CallFrame {
+            args_nr: 0,
+            ret_nr: 1,
+            env: Default::default(),
+            to_return: false,
+            base: oldtop,
+            is_block: false,
+            is_loop: false,
+            breaking: false,
+            goto_label: None,
+            ret_values: vec![],
+        });
+        let ret = crate::eval::eval_chunk(self, chunk.as_ref());
+        self.frame_stack.truncate(depth);
+        self.reg.truncate(oldtop);
+        self.strict_globals = strict;
+        ret
+    }
+
+    // A block frame for one run of a loop body
+    pub fn start_loop_raw(&mut self) -> usize {
+        let oldtop = self.start_block_raw();
+        self.frame_stack.last_mut().unwrap().is_loop = true;
+        oldtop
+    }
 
-        while oldtop < self.reg.top {
-            let _ = self.reg.ensure_pop()?;
+    pub fn end_block_raw(&mut self, oldtop: usize) -> LuaResult<()> {
+        let frame = self.frame_stack.pop();
+        if let Some(frame) = frame {
+            if self.current_frame().is_some() {
+                // a return inside the block leaves the enclosing
+                // function too, and a break the enclosing loop
+                if frame.to_return {
+                    self.set_to_return(true);
+                }
+                let parent = self.frame_stack.last_mut().unwrap();
+                if frame.breaking && !frame.is_loop {
+                    parent.breaking = true;
+                }
+                // a label outside the block is searched for outwards
+                if frame.goto_label.is_some() {
+                    parent.goto_label = frame.goto_label;
+                }
+            }
         }
+
+        self.reg.truncate(oldtop);
         Ok(())
     }
 
     pub fn funcall(&mut self, func: Value, params: Vec<Value>) -> LuaResult<Vec<Value>> {
-        let oldtop = self.reg.top;
-        let params_n = params.len();
-        for arg in params.into_iter().rev() {
-            self.reg.push(arg);
-        }
-
         let func = if let Value::Function(func) = func {
-            let mut f = func.clone();
-            f.proto.params_nr = params_n as i32;
+            let mut f = func;
+            f.proto.params_nr = params.len() as i32;
             f
         } else {
-            return Err(self.error(format!("Specified value is not func {:?}", func)));
+            return Err(self.error(format!("attempt to call a {} value", func.type_name())));
         };
-        let retnr = func.do_call((self,))?;
 
-        let mut ret = Vec::with_capacity(params_n);
-        if retnr > 0 {
-            for _ in 0..retnr {
-                ret.push(self.reg.ensure_pop()?);
-            }
-            ret = ret.into_iter().rev().collect();
+        // missing parameters are filled with nil, so reserve for them too
+        let slots = params.len().max(func.proto.parameters.len());
+        self.reg.check_stack(slots + MIN_STACK)?;
+        // Arguments go first to last, so argument i sits at
+        // base + i - 1 for both builtins and script parameters
+        let base = self.reg.top;
+        for arg in params.into_iter() {
+            self.reg.push(arg);
         }
-
-        while oldtop < self.reg.top {
-            let _ = self.reg.ensure_pop()?; // remove arg from stack - 1 time
+        if self.call_depth >= self.max_call_depth {
+            return Err(self.error("stack overflow"));
         }
+        self.call_depth += 1;
+        let retnr = func.do_call((self,));
+        self.call_depth -= 1;
+        let retnr = retnr?;
+
+        let ret = self.reg.pop_n(retnr as usize)?;
+        self.reg.truncate(base);
 
         Ok(ret)
     }
 
+    // Calls any function value from the host, builtin or defined
+    // in a script, and hands back everything it returned. On error
+    // the frames and registry slots of the failed call are dropped,
+    // so the state can keep being used
+    pub fn call(&mut self, func: Value, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+        let top = self.reg.top;
+        let frames = self.frame_stack.len();
+        let (depth, line) = (self.depth, self.line);
+        let ret = self.funcall(func, args);
+        if ret.is_err() {
+            self.frame_stack.truncate(frames);
+            self.reg.truncate(top);
+            self.depth = depth;
+            self.line = line;
+        }
+        ret
+    }
+
     pub fn global_funcall1(
         &mut self,
         name: impl Into<String>,
         arg1: Value,
     ) -> Result<Value, LuaError> {
         let name: String = name.into();
-        let oldtop = self.reg.top;
-        self.reg.push(arg1);
-        let func = {
-            let g = &self.g;
-            let val = g
-                .global
-                .get(&name)
-                .ok_or(self.error(format!("Specified func {} not found", name)))?;
-
-            if let Value::Function(func) = val {
-                let mut f = func.clone();
-                f.proto.params_nr = 1;
-                f
-            } else {
-                return Err(self.error(format!("Specified name {} is not func {:?}", name, val)));
-            }
-        };
-
-        let retnr = func.do_call((self,))?;
-        // if oldtop + params_n + retnr as usize != self.reg.top {
-        //     return Err(self.error(format!("func {} should be return {} values", name, retnr)));
-        // }
+        let func = self.get_global(&name).unwrap_or(Value::Nil);
+        if func.as_function().is_none() {
+            return Err(self.error(format!(
+                "attempt to call a {} value (global '{}')",
+                func.type_name(),
+                name
+            )));
+        }
 
         // TODO: multireturn
-        let vret = if retnr == 1 {
-            self.reg.ensure_pop()? // get function return value
+        let ret = self.funcall(func, vec![arg1])?;
+        Ok(ret.into_iter().next().unwrap_or(Value::Nil))
+    }
+
+    pub fn process_op(
+        &self,
+        op: &combine::lib::primitive::char,
+        lvalue: Value,
+        rvalue: Value,
+    ) -> Result<Value, LuaError> {
+        match op {
+            '+' | '-' | '*' | '/' | 'd' | '%' | '^' => self.process_op_arith(op, lvalue, rvalue),
+            '<' | 'l' | '>' | 'g' => self.process_op_compare(op, lvalue, rvalue),
+            'e' => Ok(Value::Bool(lvalue.raw_equal(&rvalue))),
+            'n' => Ok(Value::Bool(!lvalue.raw_equal(&rvalue))),
+            '&' | '|' => self.process_op_logical(op, lvalue, rvalue),
+            'c' => self.process_op_concat(lvalue, rvalue),
+            _ => Err(self.error("unsupported op")),
+        }
+    }
+
+    // Arithmetic accepts numbers, and strings convertible to numbers
+    pub fn process_op_arith(
+        &self,
+        op: &combine::lib::primitive::char,
+        lvalue: Value,
+        rvalue: Value,
+    ) -> Result<Value, LuaError> {
+        match (lvalue.to_arith(), rvalue.to_arith()) {
+            (Some(n), Some(m)) => self.process_op_numeric(op, &n, &m),
+            (None, _) => Err(self.error(format!(
+                "attempt to perform arithmetic on a {} value",
+                lvalue.type_name()
+            ))),
+            _ => Err(self.error(format!(
+                "attempt to perform arithmetic on a {} value",
+                rvalue.type_name()
+            ))),
+        }
+    }
+
+    // Ordering is defined only between two numbers or two strings
+    pub fn process_op_compare(
+        &self,
+        op: &combine::lib::primitive::char,
+        lvalue: Value,
+        rvalue: Value,
+    ) -> Result<Value, LuaError> {
+        if lvalue.to_float().is_some() && rvalue.to_float().is_some() {
+            return self.process_op_numeric(op, &lvalue, &rvalue);
+        }
+        if let (Some(n), Some(m)) = (lvalue.as_str(), rvalue.as_str()) {
+            return self.process_op_str(op, n, m);
+        }
+        let (lt, rt) = (lvalue.type_name(), rvalue.type_name());
+        if lt == rt {
+            Err(self.error(format!("attempt to compare two {} values", lt)))
         } else {
-            Value::Nil
-        };
-        while oldtop < self.reg.top {
-            let _ = self.reg.ensure_pop()?; // remove arg from stack - 1 time
+            Err(self.error(format!("attempt to compare {} with {}", lt, rt)))
         }
+    }
 
-        Ok(vret)
+    // Strings and numbers concatenate, numbers in their printed form
+    pub fn process_op_concat(&self, lvalue: Value, rvalue: Value) -> Result<Value, LuaError> {
+        match (lvalue.to_string(), rvalue.to_string()) {
+            (Some(n), Some(m)) => Ok(Value::LuaString((n + &m).into())),
+            (None, _) => Err(self.error(format!(
+                "attempt to concatenate a {} value",
+                lvalue.type_name()
+            ))),
+            _ => Err(self.error(format!(
+                "attempt to concatenate a {} value",
+                rvalue.type_name()
+            ))),
+        }
     }
 
-    pub fn process_op(
+    pub fn process_op_logical(
         &self,
         op: &combine::lib::primitive::char,
         lvalue: Value,
         rvalue: Value,
     ) -> Result<Value, LuaError> {
-        match (lvalue, rvalue) {
-            (Value::Number(n), Value::Number(m)) => {
-                self.process_op_number(op, n.to_owned(), m.to_owned())
-            }
-            (Value::Bool(n), Value::Bool(m)) => {
-                self.process_op_bool(op, n.to_owned(), m.to_owned())
-            }
-            (Value::LuaString(n), Value::LuaString(m)) => self.process_op_str(op, &n, &m),
-            _ => Err(self.error("type error")),
+        // operands of any type, as in `x = x or 10`
+        match op {
+            '&' if lvalue.is_truthy() => Ok(rvalue),
+            '|' if !lvalue.is_truthy() => Ok(rvalue),
+            _ => Ok(lvalue),
+        }
+    }
+
+    // Both values must already be numbers
+    pub fn process_op_numeric(
+        &self,
+        op: &combine::lib::primitive::char,
+        l: &Value,
+        r: &Value,
+    ) -> Result<Value, LuaError> {
+        match NumPair::promote(*op, l, r) {
+            Some(NumPair::Int(n, m)) => self.process_op_number(op, n, m),
+            Some(NumPair::Float(n, m)) => self.process_op_float(op, n, m),
+            None => Err(self.error("type error")),
         }
     }
 
@@ -266,31 +929,68 @@ impl LuaState {
         l: i64,
         r: i64,
     ) -> Result<Value, LuaError> {
+        // integers wrap around on overflow, as in Lua
         let ret = match op {
-            '+' => Value::Number(l + r),
-            '-' => Value::Number(l - r),
-            '*' => Value::Number(l * r),
-            '/' => Value::Number(l / r),
+            '+' => Value::Number(l.wrapping_add(r)),
+            '-' => Value::Number(l.wrapping_sub(r)),
+            '*' => Value::Number(l.wrapping_mul(r)),
+            'd' => Value::Number(self.floor_div(l, r)?),
+            '%' => Value::Number(self.floor_mod(l, r)?),
             'l' => Value::Bool(l <= r),
             '<' => Value::Bool(l < r),
             'g' => Value::Bool(l >= r),
             '>' => Value::Bool(l > r),
-            'e' => Value::Bool(l == r),
-            'n' => Value::Bool(l != r),
             _ => return Err(self.error("unsupported op")),
         };
         Ok(ret)
     }
 
-    pub fn process_op_bool(
+    // Integer `//` rounds towards minus infinity, unlike Rust's `/`
+    fn floor_div(&self, l: i64, r: i64) -> LuaResult<i64> {
+        match r {
+            0 => Err(self.error("attempt to perform 'n//0'")),
+            // i64::MIN // -1 wraps like the other integer operators
+            -1 => Ok(l.wrapping_neg()),
+            _ if l % r != 0 && (l ^ r) < 0 => Ok(l / r - 1),
+            _ => Ok(l / r),
+        }
+    }
+
+    // The remainder takes the sign of the divisor, as a - (a // b) * b
+    fn floor_mod(&self, l: i64, r: i64) -> LuaResult<i64> {
+        match r {
+            0 => Err(self.error("attempt to perform 'n%0'")),
+            // avoids the overflow of i64::MIN % -1
+            -1 => Ok(0),
+            _ => {
+                let m = l % r;
+                if m != 0 && (m ^ r) < 0 {
+                    Ok(m + r)
+                } else {
+                    Ok(m)
+                }
+            }
+        }
+    }
+
+    pub fn process_op_float(
         &self,
         op: &combine::lib::primitive::char,
-        l: bool,
-        r: bool,
+        l: f64,
+        r: f64,
     ) -> Result<Value, LuaError> {
         let ret = match op {
-            '&' => Value::Bool(l && r),
-            '|' => Value::Bool(l || r),
+            '+' => Value::Float(l + r),
+            '-' => Value::Float(l - r),
+            '*' => Value::Float(l * r),
+            '/' => Value::Float(l / r),
+            'd' => Value::Float((l / r).floor()),
+            '%' => Value::Float(float_mod(l, r)),
+            '^' => Value::Float(l.powf(r)),
+            'l' => Value::Bool(l <= r),
+            '<' => Value::Bool(l < r),
+            'g' => Value::Bool(l >= r),
+            '>' => Value::Bool(l > r),
             _ => return Err(self.error("unsupported op")),
         };
         Ok(ret)
@@ -303,8 +1003,10 @@ impl LuaState {
         r: &str,
     ) -> Result<Value, LuaError> {
         let ret = match op {
-            'e' => Value::Bool(l == r),
-            'n' => Value::Bool(l != r),
+            'l' => Value::Bool(l <= r),
+            '<' => Value::Bool(l < r),
+            'g' => Value::Bool(l >= r),
+            '>' => Value::Bool(l > r),
             _ => return Err(self.error("unsupported op")),
         };
         Ok(ret)
@@ -315,29 +1017,61 @@ impl LuaState {
         op: &combine::lib::primitive::char,
         v: Value,
     ) -> Result<Value, LuaError> {
-        let ret = match v {
-            Value::Number(n) => match op {
-                '-' => Value::Number(-n),
-                '~' => Value::Number(!n),
-                _ => {
-                    return Err(self.error("unsupported op"));
-                }
+        match op {
+            // numeric strings negate like numbers; negating i64::MIN
+            // wraps around to itself, as in Lua
+            '-' => match v.to_arith() {
+                Some(Value::Number(n)) => Ok(Value::Number(n.wrapping_neg())),
+                Some(Value::Float(f)) => Ok(Value::Float(-f)),
+                _ => Err(self.error(format!(
+                    "attempt to perform arithmetic on a {} value",
+                    v.type_name()
+                ))),
             },
-            Value::Bool(b) => match op {
-                '!' => Value::Bool(!b),
-                _ => {
-                    return Err(self.error("unsupported op"));
-                }
+            '!' => Ok(Value::Bool(!v.is_truthy())),
+            '#' => match v {
+                Value::LuaString(s) => Ok(Value::Number(s.len() as i64)),
+                Value::Table(t) => Ok(Value::Number(t.border() as i64)),
+                _ => Err(self.error(format!(
+                    "attempt to get length of a {} value",
+                    v.type_name()
+                ))),
             },
-            Value::LuaString(s) => match op {
-                '#' => Value::Number(s.len() as i64),
-                _ => {
-                    return Err(self.error("unsupported op"));
-                }
+            // floats with an exact integer value are accepted too
+            '~' => match v.to_arith() {
+                Some(Value::Number(n)) => Ok(Value::Number(!n)),
+                Some(Value::Float(f)) => match float_to_int(f) {
+                    Some(n) => Ok(Value::Number(!n)),
+                    None => Err(self.error("number has no integer representation")),
+                },
+                _ => Err(self.error(format!(
+                    "attempt to perform bitwise operation on a {} value",
+                    v.type_name()
+                ))),
             },
-            _ => return Err(self.error("type error")),
-        };
-        Ok(ret)
+            _ => Err(self.error("unsupported op")),
+        }
+    }
+
+    // Counts a statement against the configured step limit
+    pub fn count_step(&mut self) -> LuaResult<()> {
+        self.steps += 1;
+        match self.step_limit {
+            Some(limit) if self.steps > limit => Err(self.error("step limit exceeded")),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn enter_exp(&mut self) -> LuaResult<()> {
+        if self.depth >= self.max_depth {
+            return Err(self.error("expression too complex"));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    pub fn leave_exp(&mut self) {
+        self.depth -= 1;
     }
 
     pub fn current_frame(&self) -> Option<&CallFrame> {
@@ -349,23 +1083,80 @@ impl LuaState {
             .ok_or(self.error("not calledin function"))
     }
 
+    // Looks the name up from the innermost block outwards,
+    // stopping at the boundary of the current function
+    fn local_index(&self, name: &str) -> Option<usize> {
+        for frame in self.frame_stack.iter().rev() {
+            if let Some(idx) = frame.env.get(name) {
+                return Some(*idx);
+            }
+            if !frame.is_block {
+                break;
+            }
+        }
+        None
+    }
+
     pub fn has_local_name(&self, name: impl Into<String>) -> bool {
         let name: String = name.into();
-        self.current_frame()
-            .map_or(false, |f| f.env.contains_key(&name))
+        self.local_index(&name).is_some()
     }
 
     pub fn get_local(&self, name: impl Into<String>) -> Option<Value> {
         let name: String = name.into();
-        let idx = self.current_frame()?.env.get(&name)?.to_owned();
-        (&self.reg.array[idx]).clone().into()
+        let idx = self.local_index(&name)?;
+        self.reg.array[idx].clone().into()
+    }
+
+    pub fn set_local(&mut self, name: impl Into<String>, value: Value) {
+        let name: String = name.into();
+        if let Some(idx) = self.local_index(&name) {
+            self.reg.array[idx] = value;
+        }
     }
 
     pub fn set_to_return(&mut self, to_return: bool) {
-        let mut f = self.frame_stack.last_mut().unwrap();
+        let f = self.frame_stack.last_mut().unwrap();
         f.to_return = to_return;
     }
 
+    // Kept by the frame of the function the `return` is in, through
+    // any blocks it is nested in
+    pub fn set_return_values(&mut self, values: Vec<Value>) {
+        if let Some(f) = self.frame_stack.iter_mut().rev().find(|f| !f.is_block) {
+            f.ret_values = values;
+        }
+    }
+
+    // `break` is only valid when a loop body encloses it within the
+    // current function
+    pub fn set_breaking(&mut self) -> LuaResult<()> {
+        for frame in self.frame_stack.iter().rev() {
+            if frame.is_loop {
+                self.frame_stack.last_mut().unwrap().breaking = true;
+                return Ok(());
+            }
+            if !frame.is_block {
+                break;
+            }
+        }
+        Err(self.error("break outside a loop"))
+    }
+
+    pub fn breaking(&self) -> bool {
+        self.current_frame().is_some_and(|f| f.breaking)
+    }
+
+    pub fn set_goto(&mut self, label: Option<String>) {
+        if let Some(f) = self.frame_stack.last_mut() {
+            f.goto_label = label;
+        }
+    }
+
+    pub fn pending_goto(&self) -> Option<&str> {
+        self.current_frame()?.goto_label.as_deref()
+    }
+
     pub fn to_return(&mut self) -> bool {
         match self.current_frame() {
             Some(f) => f.to_return,
@@ -374,13 +1165,37 @@ impl LuaState {
     }
 
     // TODO: this should be called after params are all refered
+    // A call may push up to MIN_STACK values this way without
+    // checking; longer lists go through returns_all
     pub fn returns(&mut self, retval: Value) {
         self.reg.push(retval);
     }
 
+    // Pushes every value as a return value and reports the count,
+    // so a builtin can end with `l.returns_all(values)`. Fails
+    // instead of growing the registry past max_size
+    pub fn returns_all(&mut self, values: Vec<Value>) -> LuaResult<i32> {
+        self.reg.check_stack(values.len())?;
+        let n = values.len();
+        for v in values.into_iter() {
+            self.returns(v);
+        }
+        Ok(n as i32)
+    }
+
     pub fn error(&self, msg: impl Into<String>) -> LuaError {
         LuaError {
             message: msg.into(),
         }
     }
 }
+
+// Float modulo with the sign of the divisor, as Lua's luai_nummod
+fn float_mod(l: f64, r: f64) -> f64 {
+    let m = l % r;
+    if m != 0.0 && (m < 0.0) != (r < 0.0) {
+        m + r
+    } else {
+        m
+    }
+}
@@ -0,0 +1,117 @@
+use std::rc::Rc;
+
+use crate::function::LuaFunction;
+use crate::pattern::{self, Capture, Match};
+use crate::state::{LuaResult, LuaState};
+use crate::table::LuaTable;
+use crate::value::Value;
+
+// Lua strings are byte sequences: lengths are counted in bytes, as `#` does
+fn str_len(l: &mut LuaState) -> LuaResult<i32> {
+    let s = l.arg_string(1)?;
+    l.returns(Value::Number(s.len() as i64));
+    Ok(1)
+}
+
+// Byte range i..j with negative positions counted from the end,
+// clamped to the string as Lua's str_sub does
+fn str_sub(l: &mut LuaState) -> LuaResult<i32> {
+    let s = l.arg_string(1)?;
+    let len = s.len() as i64;
+    let i = match l.arg_opt_int(2, 1)? {
+        i if i > 0 => i,
+        0 => 1,
+        i if i < -len => 1,
+        i => len + i + 1,
+    };
+    let j = match l.arg_opt_int(3, -1)? {
+        j if j > len => len,
+        j if j >= 0 => j,
+        j if j < -len => 0,
+        j => len + j + 1,
+    };
+
+    // Indices count bytes; strings hold UTF-8 text, so a range that
+    // splits a character has no value to return
+    let sub = if i <= j {
+        s.get((i - 1) as usize..j as usize)
+            .ok_or_else(|| l.error("bad argument to 'sub' (range splits a UTF-8 character)"))?
+    } else {
+        ""
+    };
+    l.returns(Value::LuaString(sub.into()));
+    Ok(1)
+}
+
+fn str_upper(l: &mut LuaState) -> LuaResult<i32> {
+    let s = l.arg_string(1)?;
+    l.returns(Value::LuaString(s.to_ascii_uppercase().into()));
+    Ok(1)
+}
+
+fn str_lower(l: &mut LuaState) -> LuaResult<i32> {
+    let s = l.arg_string(1)?;
+    l.returns(Value::LuaString(s.to_ascii_lowercase().into()));
+    Ok(1)
+}
+
+fn byte_string(bytes: &[u8]) -> Value {
+    Value::LuaString(String::from_utf8_lossy(bytes).into())
+}
+
+// The captures of a match, or the whole match when there are none
+fn match_values(src: &[u8], m: &Match) -> Vec<Value> {
+    if m.captures.is_empty() {
+        return vec![byte_string(&src[m.start..m.end])];
+    }
+    m.captures
+        .iter()
+        .map(|c| match *c {
+            Capture::Str(start, end) => byte_string(&src[start..end]),
+            Capture::Position(pos) => Value::Number(pos as i64),
+        })
+        .collect()
+}
+
+// Returns an iterator over the matches; an empty match right where
+// the previous one ended is skipped so the iteration always advances
+fn str_gmatch(l: &mut LuaState) -> LuaResult<i32> {
+    let src = l.arg_string(1)?.into_bytes();
+    let pat = l.arg_string(2)?.into_bytes();
+    let mut pos = 0;
+    let mut last_match = None;
+    let iter = LuaFunction::from_closure(move |l| {
+        while pos <= src.len() {
+            match pattern::match_at(&src, &pat, pos).map_err(|e| l.error(e))? {
+                Some(m) if last_match != Some(m.end) => {
+                    pos = m.end;
+                    last_match = Some(m.end);
+                    return l.returns_all(match_values(&src, &m));
+                }
+                _ => pos += 1,
+            }
+        }
+        l.returns(Value::Nil);
+        Ok(1)
+    });
+    l.returns(Value::Function(iter));
+    Ok(1)
+}
+
+pub fn open_string(l: &mut LuaState) {
+    l.register_global_lib(
+        "string",
+        &[
+            ("len", str_len),
+            ("sub", str_sub),
+            ("upper", str_upper),
+            ("lower", str_lower),
+            ("gmatch", str_gmatch),
+        ],
+    );
+
+    // strings index the library through their shared metatable
+    let mt = LuaTable::empty();
+    mt.set_str("__index", l.get_global("string").unwrap_or(Value::Nil));
+    l.g.string_metatable = Some(Rc::new(mt));
+}
@@ -1,25 +1,201 @@
-use std::{cell::RefCell, collections::HashMap};
+use std::{cell::RefCell, rc::Rc};
 
-use crate::value::Value;
+use indexmap::IndexMap;
+
+use crate::state::{LuaError, LuaResult};
+use crate::value::{float_to_int, fmt_float, Value};
 
 #[derive(Debug)]
 pub struct LuaTable {
-    pub metatable: Option<RefCell<Box<LuaTable>>>,
+    pub metatable: RefCell<Option<Rc<LuaTable>>>,
 
     pub vec: RefCell<Vec<Value>>,
-    pub strdict: RefCell<HashMap<String, Value>>,
+    // integer keys that do not extend the array part: sparse, large
+    // or below 1
+    pub intdict: RefCell<IndexMap<i64, Value>>,
+    // string keys in the order they were first stored; clearing a
+    // key leaves a nil entry so a walk in progress keeps its place
+    pub strdict: RefCell<IndexMap<String, Value>>,
 }
 
 impl LuaTable {
     pub fn empty() -> Self {
-        let mt = None; // in the future...
+        let mt = None;
         let vec = Vec::new();
-        let strdict = HashMap::new();
+        let strdict = IndexMap::new();
 
         LuaTable {
-            metatable: mt,
+            metatable: RefCell::new(mt),
             vec: RefCell::new(vec),
+            intdict: RefCell::new(IndexMap::new()),
             strdict: RefCell::new(strdict),
         }
     }
+
+    pub fn get_str(&self, key: &str) -> Value {
+        self.strdict
+            .borrow()
+            .get(key)
+            .cloned()
+            .unwrap_or(Value::Nil)
+    }
+
+    pub fn set_str(&self, key: impl Into<String>, value: Value) {
+        self.strdict.borrow_mut().insert(key.into(), value);
+    }
+
+    // Array part, 1-based like Lua indices, then the integer keys
+    // outside it
+    pub fn get_int(&self, i: i64) -> Value {
+        let vec = self.vec.borrow();
+        if i >= 1 && i <= vec.len() as i64 {
+            return vec[(i - 1) as usize].clone();
+        }
+        self.intdict.borrow().get(&i).cloned().unwrap_or(Value::Nil)
+    }
+
+    // Only a store right after the end grows the array part, pulling
+    // in the keys that follow it; any other key outside the array
+    // goes to intdict, so a huge index costs one entry
+    pub fn set_int(&self, i: i64, value: Value) -> LuaResult<()> {
+        let mut vec = self.vec.borrow_mut();
+        let len = vec.len() as i64;
+        if i >= 1 && i <= len {
+            vec[(i - 1) as usize] = value;
+        } else if i == len + 1 {
+            vec.push(value);
+            let mut dict = self.intdict.borrow_mut();
+            while let Some(v) = dict.swap_remove(&(vec.len() as i64 + 1)) {
+                vec.push(v);
+            }
+        } else {
+            self.intdict.borrow_mut().insert(i, value);
+        }
+        Ok(())
+    }
+
+    // Look up any key value; keys that can never be stored read as nil
+    pub fn get(&self, key: &Value) -> Value {
+        match key {
+            Value::Number(i) => self.get_int(*i),
+            Value::Float(f) => float_to_int(*f).map_or(Value::Nil, |i| self.get_int(i)),
+            Value::LuaString(s) => self.get_str(s),
+            _ => Value::Nil,
+        }
+    }
+
+    // Store under any key value: integral numbers go to the integer
+    // keys and strings to the string keys
+    pub fn set(&self, key: Value, value: Value) -> LuaResult<()> {
+        match key {
+            Value::Number(i) => self.set_int(i, value),
+            Value::Float(f) => match float_to_int(f) {
+                Some(i) => self.set_int(i, value),
+                None if f.is_nan() => Err(LuaError {
+                    message: "table index is NaN".to_string(),
+                }),
+                None => Err(LuaError {
+                    message: format!("unsupported table key: {}", fmt_float(f)),
+                }),
+            },
+            Value::LuaString(s) => {
+                self.set_str(&*s, value);
+                Ok(())
+            }
+            Value::Nil => Err(LuaError {
+                message: "table index is nil".to_string(),
+            }),
+            key => Err(LuaError {
+                message: format!("unsupported table key: a {} value", key.type_name()),
+            }),
+        }
+    }
+
+    // A snapshot of the entries, array part first, then the other
+    // integer keys and the string keys; nil slots are skipped like
+    // pairs does
+    pub fn pairs(&self) -> Vec<(Value, Value)> {
+        let mut pairs: Vec<(Value, Value)> = self
+            .vec
+            .borrow()
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| !matches!(v, Value::Nil))
+            .map(|(i, v)| (Value::Number(i as i64 + 1), v.clone()))
+            .collect();
+        pairs.extend(
+            self.intdict
+                .borrow()
+                .iter()
+                .filter(|(_, v)| !matches!(v, Value::Nil))
+                .map(|(k, v)| (Value::Number(*k), v.clone())),
+        );
+        pairs.extend(
+            self.strdict
+                .borrow()
+                .iter()
+                .filter(|(_, v)| !matches!(v, Value::Nil))
+                .map(|(k, v)| (Value::LuaString(k.as_str().into()), v.clone())),
+        );
+        pairs
+    }
+
+    // The entry after key like Lua's next, nil starting the walk.
+    // None once every entry has been visited. String keys come in
+    // the order they were first stored, each step is a lookup of
+    // the key's position rather than a scan
+    pub fn next(&self, key: &Value) -> LuaResult<Option<(Value, Value)>> {
+        let len = self.vec.borrow().len() as i64;
+        let ints = self.intdict.borrow();
+        let dict = self.strdict.borrow();
+        let (from, int_start, str_start) = match key {
+            Value::Nil => (0, 0, 0),
+            Value::Number(i) if *i >= 1 && *i <= len => (*i, 0, 0),
+            Value::Number(i) if ints.contains_key(i) => (len, ints.get_index_of(i).unwrap() + 1, 0),
+            Value::LuaString(s) if dict.contains_key(&**s) => {
+                (len, ints.len(), dict.get_index_of(&**s).unwrap() + 1)
+            }
+            _ => {
+                return Err(LuaError {
+                    message: "invalid key to 'next'".to_string(),
+                })
+            }
+        };
+        for i in from..len {
+            let v = self.get_int(i + 1);
+            if !matches!(v, Value::Nil) {
+                return Ok(Some((Value::Number(i + 1), v)));
+            }
+        }
+
+        for pos in int_start..ints.len() {
+            let (k, v) = ints.get_index(pos).unwrap();
+            if !matches!(v, Value::Nil) {
+                return Ok(Some((Value::Number(*k), v.clone())));
+            }
+        }
+        for pos in str_start..dict.len() {
+            let (k, v) = dict.get_index(pos).unwrap();
+            if !matches!(v, Value::Nil) {
+                return Ok(Some((Value::LuaString(k.as_str().into()), v.clone())));
+            }
+        }
+        Ok(None)
+    }
+
+    // Border of the array part: trailing nils do not count
+    pub fn border(&self) -> usize {
+        let vec = self.vec.borrow();
+        vec.iter()
+            .rposition(|v| !matches!(v, Value::Nil))
+            .map_or(0, |i| i + 1)
+    }
+
+    pub fn get_metatable(&self) -> Option<Rc<LuaTable>> {
+        self.metatable.borrow().clone()
+    }
+
+    pub fn set_metatable(&self, mt: Option<Rc<LuaTable>>) {
+        *self.metatable.borrow_mut() = mt;
+    }
 }
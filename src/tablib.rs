@@ -0,0 +1,41 @@
+use std::rc::Rc;
+
+use crate::state::{LuaResult, LuaState};
+use crate::value::Value;
+
+// table.move(a1, f, e, t [,a2]): a2[t..] = a1[f..e], copying
+// backwards when the ranges overlap with the destination ahead
+fn tab_move(l: &mut LuaState) -> LuaResult<i32> {
+    let a1 = l.arg_table(1)?;
+    let f = l.arg_int(2)?;
+    let e = l.arg_int(3)?;
+    let t = l.arg_int(4)?;
+    let a2 = if l.arg_count()? >= 5 && !matches!(l.arg_value(5)?, Value::Nil) {
+        l.arg_table(5)?
+    } else {
+        a1.clone()
+    };
+
+    if e >= f {
+        if !(f > 0 || e < i64::MAX + f) {
+            return Err(l.error("bad argument #3 to 'move' (too many elements to move)"));
+        }
+        let n = e - f;
+        if t > i64::MAX - n {
+            return Err(l.error("bad argument #4 to 'move' (destination wrap around)"));
+        }
+        let forward = t > e || t <= f || !Rc::ptr_eq(&a1, &a2);
+        for k in 0..=n {
+            let i = if forward { k } else { n - k };
+            a2.set_int(t + i, a1.get_int(f + i))
+                .map_err(|err| l.error(err.message))?;
+        }
+    }
+
+    l.returns(Value::Table(a2));
+    Ok(1)
+}
+
+pub fn open_table(l: &mut LuaState) {
+    l.register_global_lib("table", &[("move", tab_move)]);
+}
@@ -0,0 +1,352 @@
+use crate::parser::{Rule, StatKind};
+
+impl Rule {
+    // Lua source for the tree. Statements land on the lines they were
+    // parsed from, so parsing the output gives back an equal tree
+    pub fn to_source(&self) -> String {
+        let mut u = Unparser {
+            out: String::new(),
+            line: 1,
+            indent: 0,
+        };
+        u.rule(self);
+        u.out
+    }
+}
+
+struct Unparser {
+    out: String,
+    line: usize,
+    indent: usize,
+}
+
+impl Unparser {
+    fn push(&mut self, s: &str) {
+        self.out.push_str(s);
+    }
+
+    // Starts new lines until reaching line; false when already there
+    fn pad(&mut self, line: usize) -> bool {
+        if self.line >= line {
+            return false;
+        }
+        while self.line < line {
+            self.out.push('\n');
+            self.line += 1;
+        }
+        true
+    }
+
+    // Moves down to line, or just past the last token when already
+    // there
+    fn at_line(&mut self, line: usize) {
+        if self.pad(line) {
+            self.out.push_str(&"  ".repeat(self.indent));
+        } else if !self.out.is_empty() && !self.out.ends_with(char::is_whitespace) {
+            self.out.push(' ');
+        }
+    }
+
+    fn rule(&mut self, rule: &Rule) {
+        match rule {
+            Rule::Nil => self.push("nil"),
+            Rule::Reserved(word) => self.push(word),
+            Rule::Bool(b) => self.push(if *b { "true" } else { "false" }),
+            // the one integer whose digits would read back as a float
+            Rule::Numeral(i64::MIN) => self.push("0x8000000000000000"),
+            Rule::Numeral(n) => self.push(&n.to_string()),
+            Rule::Float(f) => self.push(&float_source(*f)),
+            Rule::LiteralString(s) => {
+                self.push("\"");
+                self.push(&s.replace('\n', "\\n"));
+                self.push("\"");
+            }
+            Rule::Symbol(name) | Rule::Name(name, _) => self.push(name),
+            Rule::SymbolList(names) | Rule::ExpList(names) | Rule::VarList(names) => {
+                self.list(names, ", ")
+            }
+            Rule::Chunk(stats, last, lines) => {
+                self.chunk(stats, last, lines);
+                // a chunk ends on the line of what follows it
+                if last.is_none() {
+                    self.pad(*lines.last().unwrap_or(&1));
+                }
+            }
+            Rule::Block(chunk) => self.rule(chunk),
+            Rule::Stat(kind, a, b, c, d, e) => self.stat(kind, [a, b, c, d, e]),
+            Rule::LastStat(exps) => {
+                self.push("return");
+                if !matches!(exps.as_ref(), Rule::ExpList(v) if v.is_empty()) {
+                    self.push(" ");
+                    self.rule(exps);
+                }
+            }
+            Rule::IfStat(exps, blocks) => {
+                for (i, (exp, block)) in exps.iter().zip(blocks.iter()).enumerate() {
+                    match (i, exp.as_ref()) {
+                        (_, Rule::Nop) => self.push("else"),
+                        (0, exp) => self.then("if ", exp),
+                        (_, exp) => self.then("elseif ", exp),
+                    }
+                    self.block(block, "");
+                }
+                self.push("end");
+            }
+            Rule::FuncName(path, method) => {
+                match path.as_ref() {
+                    Rule::SymbolList(names) => self.list(names, "."),
+                    name => self.rule(name),
+                }
+                if let Some(method) = method {
+                    self.push(":");
+                    self.rule(method);
+                }
+            }
+            Rule::Var(name) => self.rule(name),
+            Rule::Index(prefix, key) => {
+                self.rule(prefix);
+                match key.as_ref() {
+                    Rule::Symbol(name) => {
+                        self.push(".");
+                        self.push(name);
+                    }
+                    key => {
+                        self.push("[");
+                        self.rule(key);
+                        self.push("]");
+                    }
+                }
+            }
+            Rule::Exp(exp) => self.rule(exp),
+            Rule::Prefixexp(exp) => match exp.as_ref() {
+                Rule::Exp(_) => {
+                    self.push("(");
+                    self.rule(exp);
+                    self.push(")");
+                }
+                exp => self.rule(exp),
+            },
+            Rule::FunctionCall(callee, args) => {
+                self.rule(callee);
+                self.rule(args);
+            }
+            Rule::MethodCall(recv, name, args) => {
+                self.rule(recv);
+                self.push(":");
+                self.rule(name);
+                self.rule(args);
+            }
+            Rule::Args(exps) => {
+                self.push("(");
+                self.list(exps, ", ");
+                self.push(")");
+            }
+            Rule::FuncBody(_, _) => {
+                self.push("function");
+                self.funcbody(rule);
+            }
+            Rule::ParList1(names) => self.rule(names),
+            Rule::TableConst(fields) => {
+                self.push("{");
+                self.rule(fields);
+                self.push("}");
+            }
+            Rule::FieldList(fields) => self.list(fields, ", "),
+            Rule::Field(key, value) => {
+                match key.as_ref() {
+                    Rule::Nop => {}
+                    Rule::Symbol(name) => {
+                        self.push(name);
+                        self.push(" = ");
+                    }
+                    key => {
+                        self.push("[");
+                        self.rule(key);
+                        self.push("] = ");
+                    }
+                }
+                self.rule(value);
+            }
+            Rule::BinOp(op, lhs, rhs) => {
+                self.rule(lhs);
+                self.push(&format!(" {} ", binop_source(*op)));
+                self.rule(rhs);
+            }
+            // a space keeps `- -1` from reading as a comment
+            Rule::UnOp(op, exp) => {
+                self.push(match op {
+                    '!' => "not ",
+                    '-' => "- ",
+                    '#' => "#",
+                    _ => "~ ",
+                });
+                self.rule(exp);
+            }
+            Rule::Nop => {}
+        }
+    }
+
+    fn list(&mut self, rules: &[Box<Rule>], sep: &str) {
+        for (i, rule) in rules.iter().enumerate() {
+            if i > 0 {
+                self.push(sep);
+            }
+            self.rule(rule);
+        }
+    }
+
+    fn chunk(&mut self, stats: &[Box<Rule>], last: &Option<Box<Rule>>, lines: &[usize]) {
+        for (stat, line) in stats.iter().zip(lines.iter()) {
+            self.at_line(*line);
+            self.rule(stat);
+        }
+        if let Some(last) = last {
+            self.at_line(*lines.last().unwrap_or(&1));
+            self.rule(last);
+        }
+    }
+
+    // An indented block and then the keyword closing it, on the line
+    // the block ended
+    fn block(&mut self, block: &Rule, closing: &str) {
+        let (stats, last, lines) = match block {
+            Rule::Block(chunk) => match chunk.as_ref() {
+                Rule::Chunk(stats, last, lines) => (stats, last, lines),
+                _ => return self.rule(block),
+            },
+            _ => return self.rule(block),
+        };
+        self.indent += 1;
+        self.chunk(stats, last, lines);
+        self.indent -= 1;
+        match last {
+            Some(_) => self.push(" "),
+            None => self.at_line(*lines.last().unwrap_or(&1)),
+        }
+        self.push(closing);
+    }
+
+    fn then(&mut self, keyword: &str, exp: &Rule) {
+        self.push(keyword);
+        self.rule(exp);
+        self.push(" then");
+    }
+
+    fn funcbody(&mut self, body: &Rule) {
+        if let Rule::FuncBody(params, block) = body {
+            self.push("(");
+            if let Some(params) = params {
+                self.rule(params);
+            }
+            self.push(")");
+            self.block(block, "end");
+        }
+    }
+
+    fn stat(&mut self, kind: &StatKind, args: [&Option<Box<Rule>>; 5]) {
+        let [a, b, c, d, e] = args.map(|r| r.as_deref().unwrap_or(&Rule::Nop));
+        match kind {
+            StatKind::Sep => self.push(";"),
+            StatKind::VarAssign => {
+                self.rule(a);
+                self.push(" = ");
+                self.rule(b);
+            }
+            StatKind::FunctionCall => self.rule(a),
+            StatKind::Label => {
+                self.push("::");
+                self.rule(a);
+                self.push("::");
+            }
+            StatKind::Break => self.push("break"),
+            StatKind::GoTo => {
+                self.push("goto ");
+                self.rule(a);
+            }
+            StatKind::Do => {
+                self.push("do");
+                self.block(a, "end");
+            }
+            StatKind::While => {
+                self.push("while ");
+                self.rule(a);
+                self.push(" do");
+                self.block(b, "end");
+            }
+            StatKind::Repeat => {
+                self.push("repeat");
+                self.block(a, "until ");
+                self.rule(b);
+            }
+            StatKind::IfThen => self.rule(a),
+            StatKind::For => {
+                self.push("for ");
+                self.rule(a);
+                self.push(" = ");
+                self.rule(b);
+                self.push(", ");
+                self.rule(c);
+                if !matches!(d, Rule::Nop) {
+                    self.push(", ");
+                    self.rule(d);
+                }
+                self.push(" do");
+                self.block(e, "end");
+            }
+            StatKind::ForIn => {
+                self.push("for ");
+                self.rule(a);
+                self.push(" in ");
+                self.rule(b);
+                self.push(" do");
+                self.block(c, "end");
+            }
+            StatKind::DeclareFunction => {
+                self.push("function ");
+                self.rule(a);
+                self.funcbody(b);
+            }
+            StatKind::LocalFunction => {
+                self.push("local function ");
+                self.rule(a);
+                self.funcbody(b);
+            }
+            StatKind::LocalVar => {
+                self.push("local ");
+                self.rule(a);
+                if !matches!(b, Rule::Nop) {
+                    self.push(" = ");
+                    self.rule(b);
+                }
+            }
+        }
+    }
+}
+
+fn binop_source(op: char) -> String {
+    match op {
+        '&' => "and".to_string(),
+        '|' => "or".to_string(),
+        'l' => "<=".to_string(),
+        'g' => ">=".to_string(),
+        'e' => "==".to_string(),
+        'n' => "~=".to_string(),
+        'c' => "..".to_string(),
+        'd' => "//".to_string(),
+        op => op.to_string(),
+    }
+}
+
+// Digits that read back as the same float, always with a '.' or an
+// exponent so that they stay a float
+fn float_source(f: f64) -> String {
+    if f.is_infinite() {
+        return if f > 0.0 { "1e999" } else { "-1e999" }.to_string();
+    }
+    let s = format!("{:?}", f);
+    if s.contains(['.', 'e']) {
+        s
+    } else {
+        format!("{}.0", s)
+    }
+}
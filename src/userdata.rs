@@ -0,0 +1,50 @@
+use std::{
+    any::Any,
+    cell::{Ref, RefCell, RefMut},
+    fmt,
+    rc::Rc,
+};
+
+use crate::table::LuaTable;
+
+// An opaque Rust value handed to Lua; scripts reach it only
+// through the metamethods of its metatable
+pub struct LuaUserData {
+    pub data: RefCell<Box<dyn Any>>,
+    pub metatable: RefCell<Option<Rc<LuaTable>>>,
+}
+
+impl LuaUserData {
+    pub fn new<T: Any>(data: T) -> Self {
+        LuaUserData {
+            data: RefCell::new(Box::new(data)),
+            metatable: RefCell::new(None),
+        }
+    }
+
+    pub fn is<T: Any>(&self) -> bool {
+        self.data.borrow().is::<T>()
+    }
+
+    pub fn borrow<T: Any>(&self) -> Option<Ref<'_, T>> {
+        Ref::filter_map(self.data.borrow(), |data| data.downcast_ref::<T>()).ok()
+    }
+
+    pub fn borrow_mut<T: Any>(&self) -> Option<RefMut<'_, T>> {
+        RefMut::filter_map(self.data.borrow_mut(), |data| data.downcast_mut::<T>()).ok()
+    }
+
+    pub fn get_metatable(&self) -> Option<Rc<LuaTable>> {
+        self.metatable.borrow().clone()
+    }
+
+    pub fn set_metatable(&self, mt: Option<Rc<LuaTable>>) {
+        *self.metatable.borrow_mut() = mt;
+    }
+}
+
+impl fmt::Debug for LuaUserData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LuaUserData({:p})", self)
+    }
+}
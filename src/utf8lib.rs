@@ -0,0 +1,115 @@
+use std::convert::TryFrom;
+
+use crate::state::{LuaResult, LuaState};
+use crate::value::Value;
+
+// Converts a relative string position (negative means from the end) into
+// a 1-based byte position, as Lua's u_posrelat does
+fn posrelat(pos: i64, len: usize) -> i64 {
+    if pos >= 0 {
+        pos
+    } else if (-pos) as usize > len {
+        0
+    } else {
+        len as i64 + pos + 1
+    }
+}
+
+// Decodes one UTF-8 sequence starting at byte offset `at`,
+// returning the codepoint and the sequence length
+fn decode(bytes: &[u8], at: usize) -> Option<(u32, usize)> {
+    let lead = *bytes.get(at)?;
+    let n = match lead {
+        0x00..=0x7f => 1,
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => return None,
+    };
+    let seq = bytes.get(at..at + n)?;
+    let c = std::str::from_utf8(seq).ok()?.chars().next()?;
+    Some((c as u32, n))
+}
+
+fn utf8_len(l: &mut LuaState) -> LuaResult<i32> {
+    let s = l.arg_string(1)?;
+    let bytes = s.as_bytes();
+    let len = bytes.len();
+    let i = posrelat(l.arg_opt_int(2, 1)?, len);
+    let j = posrelat(l.arg_opt_int(3, -1)?, len);
+    if i < 1 || i > len as i64 + 1 {
+        return Err(l.error("bad argument #2 to 'len' (initial position out of bounds)"));
+    }
+    if j > len as i64 {
+        return Err(l.error("bad argument #3 to 'len' (final position out of bounds)"));
+    }
+
+    let mut at = (i - 1) as usize;
+    let mut n = 0;
+    while (at as i64) < j {
+        match decode(bytes, at) {
+            Some((_, size)) => {
+                at += size;
+                n += 1;
+            }
+            None => {
+                return l.returns_all(vec![Value::Nil, Value::Number(at as i64 + 1)]);
+            }
+        }
+    }
+    l.returns(Value::Number(n));
+    Ok(1)
+}
+
+fn utf8_char(l: &mut LuaState) -> LuaResult<i32> {
+    let mut s = String::new();
+    for pos in 1..=l.arg_count()? {
+        let code = l.arg_int(pos)?;
+        let c = u32::try_from(code)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| {
+                l.error(format!(
+                    "bad argument #{} to 'char' (value out of range)",
+                    pos
+                ))
+            })?;
+        s.push(c);
+    }
+    l.returns(Value::LuaString(s.into()));
+    Ok(1)
+}
+
+fn utf8_codepoint(l: &mut LuaState) -> LuaResult<i32> {
+    let s = l.arg_string(1)?;
+    let bytes = s.as_bytes();
+    let len = bytes.len();
+    let i = posrelat(l.arg_opt_int(2, 1)?, len);
+    let j = posrelat(l.arg_opt_int(3, i)?, len);
+    if i < 1 {
+        return Err(l.error("bad argument #2 to 'codepoint' (out of bounds)"));
+    }
+    if j > len as i64 {
+        return Err(l.error("bad argument #3 to 'codepoint' (out of bounds)"));
+    }
+
+    let mut at = (i - 1) as usize;
+    let mut codes = vec![];
+    while (at as i64) < j {
+        let (code, size) = decode(bytes, at).ok_or_else(|| l.error("invalid UTF-8 code"))?;
+        codes.push(Value::Number(code as i64));
+        at += size;
+    }
+    l.returns_all(codes)
+}
+
+pub fn open_utf8(l: &mut LuaState) {
+    l.register_global_lib(
+        "utf8",
+        &[
+            ("len", utf8_len),
+            ("char", utf8_char),
+            ("codepoint", utf8_codepoint),
+        ],
+    );
+}
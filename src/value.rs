@@ -1,7 +1,12 @@
 use crate::state::{LuaError, LuaResult};
-use crate::{function::LuaFunction, table::LuaTable};
+use crate::{function::LuaFunction, table::LuaTable, userdata::LuaUserData};
 
-use std::{fmt, rc::Rc};
+use std::{
+    any::Any,
+    fmt,
+    hash::{Hash, Hasher},
+    rc::Rc,
+};
 
 #[allow(dead_code)]
 #[derive(Clone)]
@@ -9,9 +14,11 @@ pub enum Value {
     Nil,
     Bool(bool),
     Number(i64),
-    LuaString(String),
+    Float(f64),
+    LuaString(Rc<str>),
     Table(Rc<LuaTable>),
     Function(LuaFunction),
+    UserData(Rc<LuaUserData>),
 }
 
 macro_rules! assert_is_table {
@@ -31,9 +38,63 @@ impl Value {
         Value::Table(refc)
     }
 
+    pub fn new_userdata<T: Any>(data: T, mt: Option<Rc<LuaTable>>) -> Self {
+        let ud = LuaUserData::new(data);
+        ud.set_metatable(mt);
+        Value::UserData(Rc::new(ud))
+    }
+
+    // The as_* accessors borrow the payload of one exact variant and
+    // never convert; see to_int / to_float / to_string for coercions
+    pub fn as_number(&self) -> Option<i64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            Value::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::LuaString(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_function(&self) -> Option<&LuaFunction> {
+        match self {
+            Value::Function(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    // Floats with an exact integer value convert, like lua_tointeger
     pub fn to_int(&self) -> Option<i64> {
         match self {
             Value::Number(n) => Some(*n),
+            Value::Float(f) => float_to_int(*f),
+            _ => None,
+        }
+    }
+
+    // Integers are promoted, so any number reads as a float
+    pub fn to_float(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n as f64),
+            Value::Float(f) => Some(*f),
             _ => None,
         }
     }
@@ -42,25 +103,220 @@ impl Value {
         match self {
             Value::LuaString(s) => Some(s.to_string()),
             Value::Number(n) => Some(n.to_string()),
+            Value::Float(f) => Some(fmt_float(*f)),
+            _ => None,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "boolean",
+            Value::Number(_) | Value::Float(_) => "number",
+            Value::LuaString(_) => "string",
+            Value::Table(_) => "table",
+            Value::Function(_) => "function",
+            Value::UserData(_) => "userdata",
+        }
+    }
+
+    // Raw textual form, without consulting any metamethod
+    pub fn to_display_string(&self) -> String {
+        match self {
+            Value::Nil => "nil".to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Number(n) => n.to_string(),
+            Value::Float(f) => fmt_float(*f),
+            Value::LuaString(s) => s.to_string(),
+            Value::Table(t) => format!("table: {:p}", Rc::as_ptr(t)),
+            Value::Function(func) => match (&func.luafn, &func.closure) {
+                (Some(f), _) => format!("function: builtin: {:p}", *f as *const ()),
+                (_, Some(c)) => format!("function: {:p}", Rc::as_ptr(c) as *const ()),
+                _ => format!("function: {:p}", func.proto.code.as_ref()),
+            },
+            Value::UserData(ud) => format!("userdata: {:p}", Rc::as_ptr(ud)),
+        }
+    }
+
+    // Only nil and false are false in conditions
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    // Converts into a number for arithmetic, coercing numeric strings
+    pub fn to_arith(&self) -> Option<Value> {
+        match self {
+            Value::Number(n) => Some(Value::Number(*n)),
+            Value::Float(f) => Some(Value::Float(*f)),
+            Value::LuaString(s) => {
+                let s = s.trim();
+                s.parse::<i64>()
+                    .map(Value::Number)
+                    .or_else(|_| s.parse::<f64>().map(Value::Float))
+                    .ok()
+            }
             _ => None,
         }
     }
 
+    pub fn raw_equal(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a == b,
+            // exact: 2^53 + 1 is not equal to the float 2^53
+            (Value::Number(a), Value::Float(b)) | (Value::Float(b), Value::Number(a)) => {
+                float_to_int(*b) == Some(*a)
+            }
+            // literals of the same text share their storage
+            (Value::LuaString(a), Value::LuaString(b)) => Rc::ptr_eq(a, b) || a == b,
+            (Value::Table(a), Value::Table(b)) => Rc::ptr_eq(a, b),
+            (Value::UserData(a), Value::UserData(b)) => Rc::ptr_eq(a, b),
+            (Value::Function(a), Value::Function(b)) => a.addr() == b.addr(),
+            _ => false,
+        }
+    }
+
+    // Walks a table from Rust the way pairs does from Lua. The
+    // entries are copied first, so the table may be changed while
+    // iterating
+    pub fn iter_table(&self) -> LuaResult<impl Iterator<Item = (Value, Value)>> {
+        Ok(self.ensure_table()?.pairs().into_iter())
+    }
+
     pub fn ensure_table(&self) -> LuaResult<Rc<LuaTable>> {
         let rc = assert_is_table!(self)?;
         Ok(Rc::clone(rc))
     }
 }
 
+// Value as a key in host HashMaps: equality is raw_equal, so 1 and
+// 1.0 are the same key while tables, functions and userdata are
+// keyed by identity, not contents. A table must not be mutated while
+// it is used as a key. Only its identity is hashed today, but that is
+// not part of the contract.
+//
+// These impls are for host code only. LuaTable does not key on Value:
+// LuaTable::set takes integral numbers and strings and rejects other
+// keys.
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Float(a), Value::Float(b)) if a.is_nan() && b.is_nan() => true,
+            _ => self.raw_equal(other),
+        }
+    }
+}
+
+// Unlike raw_equal, == treats every NaN as equal to every other NaN,
+// so that equality is reflexive as Eq requires
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Value::Nil => 0.hash(state),
+            Value::Bool(b) => b.hash(state),
+            Value::Number(n) => n.hash(state),
+            // integral floats hash like the integer they equal
+            Value::Float(f) => match float_to_int(*f) {
+                Some(n) => n.hash(state),
+                None if f.is_nan() => f64::NAN.to_bits().hash(state),
+                None => f.to_bits().hash(state),
+            },
+            Value::LuaString(s) => s.hash(state),
+            Value::Table(t) => Rc::as_ptr(t).hash(state),
+            Value::Function(func) => func.addr().hash(state),
+            Value::UserData(u) => Rc::as_ptr(u).hash(state),
+        }
+    }
+}
+
+// Operands of a numeric operator after Lua's promotion rules
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NumPair {
+    Int(i64, i64),
+    Float(f64, f64),
+}
+
+impl NumPair {
+    // `/` and `^` always work on floats; other operators stay
+    // integral only when both operands are integers
+    pub fn promote(op: char, l: &Value, r: &Value) -> Option<NumPair> {
+        let as_float = |v: &Value| match v {
+            Value::Number(n) => Some(*n as f64),
+            Value::Float(f) => Some(*f),
+            _ => None,
+        };
+        match (l, r) {
+            (Value::Number(n), Value::Number(m)) if op != '/' && op != '^' => {
+                Some(NumPair::Int(*n, *m))
+            }
+            _ => Some(NumPair::Float(as_float(l)?, as_float(r)?)),
+        }
+    }
+}
+
 impl fmt::Debug for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Value::Nil => f.write_str("Value::Nil"),
             Value::Bool(b) => f.debug_tuple("Value::Bool").field(b).finish(),
             Value::Number(n) => f.debug_tuple("Value::Number").field(n).finish(),
+            Value::Float(n) => f.debug_tuple("Value::Float").field(n).finish(),
             Value::LuaString(s) => f.debug_tuple("Value::LuaString").field(s).finish(),
             Value::Table(t) => f.debug_tuple("Value::LuaTable").field(t.as_ref()).finish(),
             Value::Function(_) => f.write_str("Value::Function(LuaFn)"),
+            Value::UserData(u) => f.debug_tuple("Value::UserData").field(u.as_ref()).finish(),
         }
     }
 }
+
+// The integer a float is equal to, if it has one in i64's range
+pub fn float_to_int(f: f64) -> Option<i64> {
+    // 2^63 is exactly representable, so the bounds are exact
+    let bound = 9_223_372_036_854_775_808.0;
+    if f.fract() == 0.0 && (-bound..bound).contains(&f) {
+        Some(f as i64)
+    } else {
+        None
+    }
+}
+
+// Formats a float like Lua's "%.14g", adding ".0" when the result
+// would otherwise read as an integer
+pub fn fmt_float(f: f64) -> String {
+    if f.is_nan() {
+        return if f.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if f.is_infinite() {
+        return if f < 0.0 { "-inf" } else { "inf" }.to_string();
+    }
+
+    let sci = format!("{:.13e}", f);
+    let (mantissa, exp) = sci.split_at(sci.find('e').unwrap());
+    let exp: i32 = exp[1..].parse().unwrap();
+    let s = if !(-4..14).contains(&exp) {
+        let mantissa = trim_fraction(mantissa);
+        let sign = if exp < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", mantissa, sign, exp.abs())
+    } else {
+        let decimals = (13 - exp) as usize;
+        trim_fraction(&format!("{:.*}", decimals, f)).to_string()
+    };
+
+    if s.chars().all(|c| c == '-' || c.is_ascii_digit()) {
+        format!("{}.0", s)
+    } else {
+        s
+    }
+}
+
+fn trim_fraction(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
@@ -0,0 +1,87 @@
+use purua::state::LuaState;
+
+#[test]
+fn analyzer_diagnostics() {
+    use purua::analyze::analyze;
+    use purua::parser::{parse, Rule};
+    let msgs = |r: &Rule| {
+        analyze(r)
+            .into_iter()
+            .map(|d| d.message)
+            .collect::<Vec<_>>()
+    };
+
+    let clean = parse("x = 1\nfunction f(a)\n if a then return a end\n return 2\nend").unwrap();
+    assert!(msgs(&clean).is_empty());
+
+    let src = "for k in pairs(t) do\n break\n x = 1\nend";
+    assert_eq!(
+        msgs(&parse(src).unwrap()),
+        vec!["unreachable statement after break"]
+    );
+
+    let src = "function f()\n repeat\n  break\n  x = 1\n until true\nend";
+    let diags = analyze(&parse(src).unwrap());
+    assert_eq!(diags.len(), 1);
+    assert_eq!(diags[0].location, "function 'f'");
+    assert_eq!(diags[0].line, 4);
+    let src = "for i = 1, 3 do\n if i == 2 then\n  break\n  print(i)\n end\nend";
+    assert_eq!(
+        msgs(&parse(src).unwrap()),
+        vec!["unreachable statement after break"]
+    );
+    assert!(msgs(&parse("for i = 1, 3 do\n print(i)\n break\nend").unwrap()).is_empty());
+
+    // these never reach the analyzer: the parser rejects them
+    assert!(parse("return 1\nx = 2").is_err());
+    assert!(parse("f() = 1").is_err());
+
+    assert!(parse("x = (")
+        .unwrap_err()
+        .message
+        .starts_with("Parse error:"));
+}
+
+#[test]
+fn undefined_locals() {
+    use purua::analyze::check_undefined;
+    use purua::parser::parse;
+    let src = "counter = 0\nfunction bump(step)\n local total = counter\n total = totl\n counter = total\n print(step)\n for k, v in pairs(t) do\n  print(v)\n end\n print(v)\nend\nbump(1)\nprint(step)";
+    let rule = parse(src).unwrap();
+    let l = LuaState::builder().open_libs(true).build();
+    let known = l.g.names().collect::<Vec<_>>();
+    let diags = check_undefined(&rule, &known);
+    let found = diags
+        .iter()
+        .map(|d| (d.name.clone().unwrap(), d.location.clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        found,
+        vec![
+            ("totl".to_string(), "function 'bump'".to_string()),
+            ("t".to_string(), "function 'bump'".to_string()),
+            ("v".to_string(), "function 'bump'".to_string()),
+            ("step".to_string(), "main chunk".to_string()),
+        ]
+    );
+    assert_eq!(
+        diags[0].to_string(),
+        "function 'bump', line 4: undefined variable 'totl'"
+    );
+    let lines = diags.iter().map(|d| d.line).collect::<Vec<_>>();
+    assert_eq!(lines, vec![4, 7, 10, 13]);
+    assert!(check_undefined(&parse("x = 1\ny = x").unwrap(), &[]).is_empty());
+}
+
+#[test]
+fn repeat_condition_sees_body_locals() {
+    use purua::analyze::check_undefined;
+    use purua::parser::parse;
+    let rule = parse("repeat local j = 1 until j").unwrap();
+    assert!(check_undefined(&rule, &[]).is_empty());
+    let rule = parse("repeat\n local j = 1\nuntil j\nprint(j)").unwrap();
+    let diags = check_undefined(&rule, &["print"]);
+    assert_eq!(diags.len(), 1);
+    assert_eq!(diags[0].name.as_deref(), Some("j"));
+    assert_eq!(diags[0].line, 4);
+}
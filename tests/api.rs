@@ -0,0 +1,760 @@
+use combine::parser::char::spaces;
+use combine::stream::position;
+use combine::EasyParser;
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+use purua::state::LuaState;
+use purua::value::Value;
+
+// Collects what print writes
+#[derive(Clone, Default)]
+struct Sink(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink {
+    fn text(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+#[test]
+fn call_arguments_keep_their_order() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let sub = l.get_global("string").unwrap().ensure_table().unwrap();
+    let sub = sub.get_str("sub");
+    let r = l
+        .call(
+            sub,
+            vec![
+                Value::LuaString("hello".into()),
+                Value::Number(2),
+                Value::Number(4),
+            ],
+        )
+        .unwrap();
+    assert_eq!(r[0].to_string().unwrap(), "ell");
+
+    l.run_string("function digits(a, b, c) return a * 100 + b * 10 + c end")
+        .unwrap();
+    let f = l.get_global("digits").unwrap();
+    let r = l
+        .call(
+            f,
+            vec![Value::Number(1), Value::Number(2), Value::Number(3)],
+        )
+        .unwrap();
+    assert!(matches!(r[0], Value::Number(123)));
+    let v = l.run_string("return digits(4, 5, 6)").unwrap();
+    assert!(matches!(v, Value::Number(456)));
+}
+
+#[test]
+fn symbol_names_helper() {
+    use purua::parser::Rule;
+    let r = Rule::SymbolList(vec![
+        Box::new(Rule::Symbol("a".into())),
+        Box::new(Rule::Symbol("b".into())),
+    ]);
+    assert_eq!(purua::eval::symbol_names(&r).unwrap(), vec!["a", "b"]);
+    assert_eq!(
+        purua::eval::symbol_names(&Rule::Symbol("x".into())).unwrap(),
+        vec!["x"]
+    );
+    assert!(purua::eval::symbol_names(&Rule::Nil).is_err());
+}
+
+#[test]
+fn closure_counter() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    let count = Rc::new(Cell::new(0));
+    let c = count.clone();
+    let mut l = LuaState::new(1024);
+    purua::prelude::prelude(&mut l);
+    let mut calls = 0;
+    l.register_global_closure("counter", move |l| {
+        calls += 1;
+        c.set(calls);
+        l.returns(Value::Number(calls));
+        Ok(1)
+    });
+    let mut parser = (spaces(), purua::parser::chunk(), combine::eof());
+    let res = parser
+        .easy_parse(position::Stream::new(
+            "counter()\ncounter()\nx = counter()\n",
+        ))
+        .unwrap();
+    purua::eval::eval_chunk(&mut l, &(res.0).1).unwrap();
+    assert_eq!(count.get(), 3);
+    assert_eq!(l.get_global("x").unwrap().to_int(), Some(3));
+}
+
+#[test]
+fn returns_all_helper() {
+    let mut l = LuaState::new(1024);
+    l.register_global_fn("two", |l| {
+        l.returns_all(vec![Value::Number(1), Value::Number(2)])
+    });
+    let f = l.get_global("two").unwrap();
+    let r = l.funcall(f, vec![]).unwrap();
+    assert_eq!(r.len(), 2);
+    assert_eq!(r[1].to_int(), Some(2));
+    assert_eq!(l.reg.top, 0);
+}
+
+#[test]
+fn arg_value_any_type() {
+    let mut l = LuaState::new(1024);
+    l.register_global_fn("kind", |l| {
+        let v = l.arg_value(1)?;
+        l.returns(Value::LuaString(v.type_name().to_string().into()));
+        Ok(1)
+    });
+    let f = l.get_global("kind").unwrap();
+    let r = l.funcall(f, vec![Value::Bool(true)]).unwrap();
+    assert_eq!(r[0].to_string().unwrap(), "boolean");
+    assert!(l.reg.get(5).is_err());
+}
+
+#[test]
+fn variadic_sum() {
+    let mut l = LuaState::new(1024);
+    l.register_global_fn("sum", |l| {
+        let mut total = 0;
+        for i in 1..=l.arg_count()? {
+            total += l.arg_int(i)?;
+        }
+        assert_eq!(l.args()?.len(), l.arg_count()?);
+        l.returns(Value::Number(total));
+        Ok(1)
+    });
+    let f = l.get_global("sum").unwrap();
+    let r = l
+        .funcall(
+            f.clone(),
+            vec![Value::Number(1), Value::Number(2), Value::Number(3)],
+        )
+        .unwrap();
+    assert_eq!(r[0].to_int(), Some(6));
+    let r = l.funcall(f, vec![]).unwrap();
+    assert_eq!(r[0].to_int(), Some(0));
+}
+
+#[test]
+fn number_display() {
+    assert_eq!(
+        Value::Number(10000000000).to_string().unwrap(),
+        "10000000000"
+    );
+    assert_eq!(
+        Value::Number(-9223372036854775808).to_string().unwrap(),
+        "-9223372036854775808"
+    );
+    assert_eq!(Value::Float(1e10).to_string().unwrap(), "10000000000.0");
+    assert_eq!(Value::Float(1e15).to_string().unwrap(), "1e+15");
+    assert_eq!(Value::Float(1e100).to_string().unwrap(), "1e+100");
+    assert_eq!(Value::Float(2.0).to_string().unwrap(), "2.0");
+    assert_eq!(Value::Float(-0.5).to_string().unwrap(), "-0.5");
+    assert_eq!(
+        Value::Float(3.24159265358979).to_string().unwrap(),
+        "3.2415926535898"
+    );
+    assert_eq!(Value::Float(0.1).to_string().unwrap(), "0.1");
+    assert_eq!(Value::Float(1e-5).to_string().unwrap(), "1e-05");
+    assert_eq!(Value::Float(0.0001).to_string().unwrap(), "0.0001");
+    assert_eq!(
+        Value::Float(123456789012345.0).to_string().unwrap(),
+        "1.2345678901234e+14"
+    );
+    assert_eq!(Value::Float(f64::INFINITY).to_string().unwrap(), "inf");
+}
+
+#[test]
+fn integral_floats_read_as_integers() {
+    assert_eq!(Value::Float(2.0).to_int(), Some(2));
+    assert_eq!(Value::Float(-3.0).to_int(), Some(-3));
+    assert_eq!(Value::Float(2.5).to_int(), None);
+    assert_eq!(Value::Float(f64::NAN).to_int(), None);
+    assert_eq!(Value::Float(1e100).to_int(), None);
+
+    let mut l = LuaState::builder().open_libs(true).build();
+    let v = l
+        .run_string("return string.sub(\"hello\", 2.0, 3)")
+        .unwrap();
+    assert_eq!(v.to_string().unwrap(), "el");
+    let v = l.run_string("return utf8.char(72.0)").unwrap();
+    assert_eq!(v.to_string().unwrap(), "H");
+    let e = l
+        .run_string("return string.sub(\"hello\", 2.5)")
+        .unwrap_err();
+    assert!(
+        e.message.ends_with("number has no integer representation"),
+        "{}",
+        e.message
+    );
+}
+
+#[test]
+fn userdata_roundtrip() {
+    #[derive(Debug, PartialEq)]
+    struct Point {
+        x: i64,
+        y: i64,
+    }
+    let mut l = LuaState::builder().open_libs(true).build();
+    let mt = l.new_metatable("Point");
+    mt.set_str(
+        "__index",
+        Value::Function(purua::function::LuaFunction::from_fn(|l| {
+            let ud = l.arg_userdata(1, "Point")?;
+            let key = l.arg_string(2)?;
+            let p = ud.borrow::<Point>().unwrap();
+            l.returns(Value::Number(if key == "x" { p.x } else { p.y }));
+            Ok(1)
+        })),
+    );
+    let v = Value::new_userdata(Point { x: 1, y: 2 }, Some(mt.clone()));
+    assert_eq!(v.type_name(), "userdata");
+    assert!(v.to_display_string().starts_with("userdata: 0x"));
+    assert!(v.raw_equal(&v.clone()));
+    if let Value::UserData(ud) = &v {
+        assert!(ud.is::<Point>());
+        assert!(ud.borrow::<String>().is_none());
+        ud.borrow_mut::<Point>().unwrap().x = 10;
+        assert_eq!(*ud.borrow::<Point>().unwrap(), Point { x: 10, y: 2 });
+    }
+    let idx = mt.get_str("__index");
+    let r = l
+        .funcall(idx, vec![v.clone(), Value::LuaString("x".into())])
+        .unwrap();
+    assert_eq!(r[0].to_int(), Some(10));
+    let other = Value::new_userdata(Point { x: 1, y: 2 }, None);
+    assert!(!v.raw_equal(&other));
+    let r = l.funcall(
+        mt.get_str("__index"),
+        vec![other, Value::LuaString("x".into())],
+    );
+    assert_eq!(
+        r.unwrap_err().message,
+        "bad argument #1 (Point expected, got userdata)"
+    );
+}
+
+#[test]
+fn registry_grows_to_max() {
+    let src =
+        "function sum(n)\n if n == 0 then return 0 end\n return n + sum(n - 1)\nend\nx = sum(150)";
+    let mut l = LuaState::builder()
+        .open_libs(true)
+        .initial_capacity(4)
+        .max_size(100_000)
+        .build();
+    assert_eq!(l.reg.array.capacity(), 4);
+    l.run_string(src).unwrap();
+    assert_eq!(l.get_global("x").unwrap().to_int(), Some(150 * 151 / 2));
+    assert_eq!(l.reg.max_size, 100_000);
+
+    let mut l = LuaState::builder()
+        .open_libs(true)
+        .initial_capacity(4)
+        .max_size(100)
+        .build();
+    let r = l.run_string(src);
+    assert!(r.unwrap_err().message.ends_with("stack overflow"));
+}
+
+#[test]
+fn registry_limit_covers_returns_and_locals() {
+    fn many(l: &mut LuaState) -> purua::state::LuaResult<i32> {
+        l.returns_all(vec![Value::Nil; 500])
+    }
+    let state = || {
+        let mut l = LuaState::builder().open_libs(true).max_size(100).build();
+        l.register_global_fn("many", many);
+        l
+    };
+
+    let mut l = state();
+    let e = l.run_string("return many()").unwrap_err();
+    assert!(e.message.ends_with("stack overflow"), "{}", e.message);
+    assert!(l.reg.array.len() <= 100);
+
+    let mut l = state();
+    let src = format!("return utf8.codepoint(\"{}\", 1, -1)", "a".repeat(200));
+    let e = l.run_string(&src).unwrap_err();
+    assert!(e.message.ends_with("stack overflow"), "{}", e.message);
+    assert!(l.reg.array.len() <= 100);
+
+    let mut l = state();
+    let locals: String = (0..150)
+        .map(|i| format!("local a{} = {}\n", i, i))
+        .collect();
+    let e = l.run_string(&locals).unwrap_err();
+    assert!(e.message.ends_with("stack overflow"), "{}", e.message);
+    assert!(l.reg.array.len() <= 100);
+}
+
+#[test]
+fn builder_sandbox_and_steps() {
+    let sink = Sink::default();
+    let mut l = LuaState::builder()
+        .open_libs(true)
+        .sandbox(true)
+        .step_limit(50)
+        .output(sink.clone())
+        .build();
+    assert!(l.get_global("io").is_none());
+    assert!(l.get_global("os").is_none());
+    assert!(l.get_global("math").is_some());
+    let r = l.run_string("print(\"hi\")\nx = 1");
+    assert!(r.is_ok(), "{:?}", r);
+    assert_eq!(sink.text(), "hi");
+
+    let mut l = LuaState::builder().open_libs(true).step_limit(50).build();
+    assert!(l.get_global("io").is_some());
+    let r =
+        l.run_string("function f(n)\n if n == 0 then return 0 end\n return f(n - 1)\nend\nf(1000)");
+    assert_eq!(r.unwrap_err().message, "step limit exceeded");
+    assert!(l.steps > 50);
+
+    let l = LuaState::builder().build();
+    assert!(l.get_global("print").is_none());
+}
+
+#[test]
+fn unary_operators() {
+    let l = LuaState::builder().open_libs(true).build();
+    let un = |c: char, v: Value| l.process_unop(&c, v);
+    assert!(matches!(
+        un('-', Value::Number(5)).unwrap(),
+        Value::Number(-5)
+    ));
+    assert!(matches!(un('-', Value::Float(1.5)).unwrap(), Value::Float(f) if f == -1.5));
+    assert_eq!(
+        un('-', Value::Bool(true)).unwrap_err().message,
+        "attempt to perform arithmetic on a boolean value"
+    );
+    assert!(matches!(un('!', Value::Nil).unwrap(), Value::Bool(true)));
+    assert!(matches!(
+        un('!', Value::Bool(false)).unwrap(),
+        Value::Bool(true)
+    ));
+    assert!(matches!(
+        un('!', Value::Number(0)).unwrap(),
+        Value::Bool(false)
+    ));
+    assert!(matches!(
+        un('!', Value::LuaString("".into())).unwrap(),
+        Value::Bool(false)
+    ));
+    assert!(matches!(
+        un('#', Value::LuaString("abc".into())).unwrap(),
+        Value::Number(3)
+    ));
+    let t = Value::newtable();
+    t.ensure_table().unwrap().vec.borrow_mut().extend(vec![
+        Value::Number(1),
+        Value::Number(2),
+        Value::Nil,
+    ]);
+    assert!(matches!(un('#', t).unwrap(), Value::Number(2)));
+    assert_eq!(
+        un('#', Value::Number(1)).unwrap_err().message,
+        "attempt to get length of a number value"
+    );
+    assert!(matches!(
+        un('~', Value::Number(0)).unwrap(),
+        Value::Number(-1)
+    ));
+    assert_eq!(
+        un('~', Value::Nil).unwrap_err().message,
+        "attempt to perform bitwise operation on a nil value"
+    );
+
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.run_string("a = 3\nb = -a\nc = not nil\nd = #\"hello\"\ne = ~a\nf = #{1, 2, 3}")
+        .unwrap();
+    assert_eq!(l.get_global("b").unwrap().to_int(), Some(-3));
+    assert!(matches!(l.get_global("c").unwrap(), Value::Bool(true)));
+    assert_eq!(l.get_global("d").unwrap().to_int(), Some(5));
+    assert_eq!(l.get_global("e").unwrap().to_int(), Some(-4));
+    assert_eq!(l.get_global("f").unwrap().to_int(), Some(3));
+}
+
+#[test]
+#[allow(clippy::mutable_key_type)]
+fn value_as_hashmap_key() {
+    use std::collections::HashMap;
+    let l = LuaState::builder().open_libs(true).build();
+    let mut m: HashMap<Value, &str> = HashMap::new();
+    let t = Value::newtable();
+    m.insert(Value::Number(1), "one");
+    m.insert(Value::LuaString("k".into()), "str");
+    m.insert(t.clone(), "table");
+    m.insert(Value::Bool(true), "bool");
+    m.insert(Value::Nil, "nil");
+    assert_eq!(m.get(&Value::Float(1.0)), Some(&"one"));
+    assert_eq!(m.get(&Value::LuaString("k".into())), Some(&"str"));
+    assert_eq!(m.get(&t.clone()), Some(&"table"));
+    assert_eq!(m.get(&Value::newtable()), None);
+    assert_eq!(m.get(&Value::Nil), Some(&"nil"));
+    let print = l.get_global("print").unwrap();
+    m.insert(print.clone(), "print");
+    assert_eq!(m.get(&print.clone()), Some(&"print"));
+    assert!(Value::Number((1 << 53) + 1) != Value::Float((1u64 << 53) as f64));
+    // NaN is a usable key, though Lua never finds it equal to itself
+    let nan = Value::Float(f64::NAN);
+    m.insert(nan.clone(), "nan");
+    assert_eq!(m.get(&Value::Float(-f64::NAN)), Some(&"nan"));
+    assert!(!nan.raw_equal(&nan));
+    assert_eq!(m.len(), 7);
+}
+
+#[test]
+fn iter_table_from_rust() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.run_string("function f() return {10, nil, 30} end")
+        .unwrap();
+    let f = l.get_global("f").unwrap();
+    let t = l.funcall(f, vec![]).unwrap().remove(0);
+    t.ensure_table()
+        .unwrap()
+        .set_str("name", Value::LuaString("x".into()));
+    let entries: Vec<(Value, Value)> = t.iter_table().unwrap().collect();
+    assert_eq!(
+        entries,
+        vec![
+            (Value::Number(1), Value::Number(10)),
+            (Value::Number(3), Value::Number(30)),
+            (
+                Value::LuaString("name".into()),
+                Value::LuaString("x".into())
+            ),
+        ]
+    );
+    // cleared fields are not entries any more
+    l.run_string("g = {a = 1, b = 2, [1] = 3}\ng.a = nil\ng[1] = nil")
+        .unwrap();
+    let g = l.get_global("g").unwrap();
+    let entries: Vec<(Value, Value)> = g.iter_table().unwrap().collect();
+    assert_eq!(
+        entries,
+        vec![(Value::LuaString("b".into()), Value::Number(2))]
+    );
+    assert!(Value::Number(1).iter_table().is_err());
+}
+
+#[test]
+fn run_string_returns_chunk_value() {
+    let mut l = LuaState::new(65535);
+    purua::prelude::prelude(&mut l);
+    assert_eq!(l.run_string("return 1 + 2").unwrap(), Value::Number(3));
+    assert_eq!(l.run_string("x = 1").unwrap(), Value::Nil);
+    assert_eq!(
+        l.run_string("local a = 5\ndo return a * 2 end\nx = 2")
+            .unwrap(),
+        Value::Number(10)
+    );
+    assert_eq!(l.get_global("x").unwrap(), Value::Number(1));
+    assert_eq!(
+        l.run_string("if 1 < 2 then return 7 end return 0").unwrap(),
+        Value::Number(7)
+    );
+    assert!(l.frame_stack.is_empty());
+    assert!(l.run_string("return nosuch()").is_err());
+    assert!(l.frame_stack.is_empty());
+}
+
+#[test]
+fn strict_globals_mode() {
+    let mut l = LuaState::builder()
+        .open_libs(true)
+        .strict_globals(true)
+        .build();
+    l.declare_global("config");
+    l.run_string("config = 1").unwrap();
+    assert_eq!(l.get_global("config").unwrap(), Value::Number(1));
+    let e = l.run_string("confg = 2").unwrap_err();
+    assert_eq!(e.message, "assign to undeclared variable 'confg'");
+    let e = l.run_string("return confg").unwrap_err();
+    assert_eq!(e.message, "variable 'confg' is not declared");
+    let e = l.run_string("nosuch(1)").unwrap_err();
+    assert_eq!(e.message, "variable 'nosuch' is not declared");
+    // builtins, locals and declared-but-unset names are fine
+    l.declare_global("later");
+    assert_eq!(
+        l.run_string("local v = 3 v = 4 print(\"\") return later")
+            .unwrap(),
+        Value::Nil
+    );
+    l.run_string("config = config + 1").unwrap();
+    assert_eq!(l.get_global("config").unwrap(), Value::Number(2));
+
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.run_string("confg = 2").unwrap();
+    assert_eq!(l.run_string("return undefined").unwrap(), Value::Nil);
+}
+
+#[test]
+fn globals_resolve_per_state() {
+    // each state numbers its own globals
+    let mut a = LuaState::builder().open_libs(true).build();
+    a.run_string("a1, a2, a3 = 1, 2, 3\nfunction getx() return x end")
+        .unwrap();
+    let mut b = LuaState::new(65535);
+    b.run_string("x = 1").unwrap();
+    assert_eq!(b.global_id("x"), 0);
+    assert!(matches!(b.get_global_by_id(0), Value::Number(1)));
+    assert!(b.g.globals.len() < a.g.globals.len());
+
+    // a function resolved in one state looks names up again in another
+    let getx = a.get_global("getx").unwrap();
+    a.assign_global("x", Value::Number(10));
+    assert_eq!(
+        a.funcall(getx.clone(), vec![]).unwrap()[0],
+        Value::Number(10)
+    );
+    assert_eq!(
+        b.funcall(getx.clone(), vec![]).unwrap()[0],
+        Value::Number(1)
+    );
+    assert_eq!(a.funcall(getx, vec![]).unwrap()[0], Value::Number(10));
+
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.assign_global("y", Value::Number(2));
+    let v = l
+        .run_string("local y = 10\nz = y\nfunction g() return y end\nreturn z + g()")
+        .unwrap();
+    assert!(matches!(v, Value::Number(12)));
+
+    let mut l = LuaState::builder()
+        .open_libs(true)
+        .strict_globals(true)
+        .build();
+    let e = l.run_string("return undeclared_name").unwrap_err();
+    assert!(e
+        .message
+        .ends_with("variable 'undeclared_name' is not declared"));
+}
+
+#[test]
+fn table_constructor_expands_last_call() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.register_global_fn("three", |l| {
+        l.returns_all(vec![Value::Number(1), Value::Number(2), Value::Number(3)])
+    });
+    let len = |l: &mut LuaState, src: &str| {
+        let t = l.run_string(src).unwrap();
+        let t = t.ensure_table().unwrap();
+        let ints = t
+            .vec
+            .borrow()
+            .iter()
+            .map(|v| v.to_int())
+            .collect::<Vec<_>>();
+        ints
+    };
+    assert_eq!(
+        len(&mut l, "return {three()}"),
+        vec![Some(1), Some(2), Some(3)]
+    );
+    assert_eq!(
+        len(&mut l, "return {0, three()}"),
+        vec![Some(0), Some(1), Some(2), Some(3)]
+    );
+    // only the last field expands, and parentheses truncate
+    assert_eq!(len(&mut l, "return {three(), 9}"), vec![Some(1), Some(9)]);
+    assert_eq!(len(&mut l, "return {(three())}"), vec![Some(1)]);
+    assert_eq!(len(&mut l, "s = \"ab\" return {s:len()}"), vec![Some(2)]);
+}
+
+#[test]
+fn arg_float_reads_numbers() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.register_global_fn("half", |l| {
+        let f = l.arg_float(1)?;
+        l.returns(Value::Float(f / 2.0));
+        Ok(1)
+    });
+    assert_eq!(l.run_string("return half(3)").unwrap(), Value::Float(1.5));
+    assert_eq!(
+        l.run_string("return half(3 / 2)").unwrap(),
+        Value::Float(0.75)
+    );
+    let e = l.run_string("return half(\"x\")").unwrap_err();
+    assert_eq!(e.message, "bad argument #1 (number expected, got string)");
+    let e = l.run_string("return half({})").unwrap_err();
+    assert_eq!(e.message, "bad argument #1 (number expected, got table)");
+}
+
+#[test]
+fn global_reassignment() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let id = l.global_id("g");
+    l.run_string("g = 1 g = \"two\"").unwrap();
+    assert_eq!(l.get_global("g").unwrap(), Value::LuaString("two".into()));
+    assert_eq!(l.get_global_by_id(id), Value::LuaString("two".into()));
+    l.assign_global("g", Value::Nil);
+    assert_eq!(l.get_global("g"), Some(Value::Nil));
+    assert_eq!(l.global_id("g"), id);
+    assert_eq!(l.g.names().filter(|n| *n == "g").count(), 1);
+}
+
+#[test]
+fn call_script_function_from_rust() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.run_string("function on_event(x) return x * 10 end\ncallback = on_event")
+        .unwrap();
+    let cb = l.get_global("callback").unwrap();
+    assert_eq!(
+        l.call(cb.clone(), vec![Value::Number(4)]).unwrap(),
+        vec![Value::Number(40)]
+    );
+    let tostring = l.get_global("tostring").unwrap();
+    assert_eq!(
+        l.call(tostring, vec![Value::Bool(true)]).unwrap(),
+        vec![Value::LuaString("true".into())]
+    );
+    let top = l.reg.top;
+    let e = l.call(cb.clone(), vec![Value::newtable()]).unwrap_err();
+    assert_eq!(e.message, "attempt to perform arithmetic on a table value");
+    assert!(l.frame_stack.is_empty());
+    assert_eq!(l.reg.top, top);
+    assert_eq!(
+        l.call(cb, vec![Value::Number(1)]).unwrap(),
+        vec![Value::Number(10)]
+    );
+    let e = l.call(Value::Nil, vec![]).unwrap_err();
+    assert_eq!(e.message, "attempt to call a nil value");
+}
+
+#[test]
+fn strict_pragma_and_shebang() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let e = l.run_string("--!strict\nx = 1").unwrap_err();
+    assert_eq!(e.message, "assign to undeclared variable 'x'");
+    assert!(!l.strict_globals);
+    // the pragma does not leak into the next chunk
+    l.run_string("x = 1").unwrap();
+    assert_eq!(l.get_global("x").unwrap().to_int(), Some(1));
+
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.run_string("#!/usr/bin/env purua\n-- plain\n--!whatever\nx = 1\n--!strict")
+        .unwrap();
+    assert!(!l.strict_globals);
+    let e = l
+        .run_string("#!/usr/bin/env purua\n\n  --!strict  \ny = 1")
+        .unwrap_err();
+    assert_eq!(e.message, "assign to undeclared variable 'y'");
+    let e = l.run_string("#!shebang\nerror(\"at 2\")").unwrap_err();
+    assert_eq!(e.message, "input:2: at 2");
+    assert_eq!(
+        purua::parser::pragmas("--!strict"),
+        vec![purua::parser::Pragma::Strict]
+    );
+}
+
+#[test]
+fn value_accessors() {
+    assert_eq!(Value::Number(3).as_number(), Some(3));
+    assert_eq!(Value::Float(3.0).as_number(), None);
+    // unlike to_int, which coerces integral floats
+    assert_eq!(Value::Float(3.0).to_int(), Some(3));
+    assert_eq!(Value::Float(3.5).to_int(), None);
+    assert_eq!(Value::Float(0.5).as_float(), Some(0.5));
+    assert_eq!(Value::Number(1).as_float(), None);
+    assert_eq!(
+        Value::LuaString("hi".to_string().into()).as_str(),
+        Some("hi")
+    );
+    assert_eq!(Value::Number(1).as_str(), None);
+    assert_eq!(Value::Bool(false).as_bool(), Some(false));
+    assert_eq!(Value::Nil.as_bool(), None);
+
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.run_string("function f(x) return x end").unwrap();
+    let f = l.get_global("f").unwrap();
+    assert!(f.as_function().is_some());
+    assert!(Value::Nil.as_function().is_none());
+    assert!(l.get_global("print").unwrap().as_function().is_some());
+}
+
+#[test]
+fn registry_bounds() {
+    use purua::state::Registry;
+    let mut reg = Registry {
+        array: vec![],
+        top: 0,
+        max_size: 16,
+    };
+    for e in [
+        reg.to_int(0).unwrap_err(),
+        reg.to_float(3).unwrap_err(),
+        reg.to_string(0).unwrap_err(),
+    ] {
+        assert!(
+            e.message.starts_with("bad argument: no value at position"),
+            "{}",
+            e.message
+        );
+    }
+    assert!(reg.last().is_none());
+    assert!(reg.pop().is_none());
+    assert_eq!(reg.top, 0);
+    reg.push(Value::Number(7));
+    assert_eq!(reg.to_int(0).unwrap(), 7);
+    assert!(reg.to_int(1).is_err());
+
+    // values left above top by an earlier call are not readable
+    let reg = Registry {
+        array: vec![Value::Number(1), Value::Number(2), Value::Number(3)],
+        top: 1,
+        max_size: 16,
+    };
+    assert_eq!(reg.get(0).unwrap(), Value::Number(1));
+    let e = reg.get(2).unwrap_err();
+    assert_eq!(e.message, "bad argument: no value at position 2");
+
+    // builtins called with no arguments fail instead of panicking
+    let mut l = LuaState::builder().open_libs(true).build();
+    for (lib, name) in [("string", "upper"), ("string", "sub"), ("math", "max")] {
+        let f = l
+            .get_global(lib)
+            .unwrap()
+            .ensure_table()
+            .unwrap()
+            .get_str(name);
+        let e = l.call(f, vec![]).unwrap_err();
+        assert!(
+            e.message.starts_with("bad argument #1"),
+            "{}.{}: {}",
+            lib,
+            name,
+            e.message
+        );
+    }
+}
+
+#[test]
+fn main_chunk_return_value() {
+    let mut l = LuaState::new(65535);
+    purua::prelude::prelude(&mut l);
+    assert!(matches!(
+        l.run_string("return 3").unwrap(),
+        Value::Number(3)
+    ));
+    assert!(matches!(l.run_string("return").unwrap(), Value::Nil));
+    assert!(matches!(l.run_string("x = 1").unwrap(), Value::Nil));
+}
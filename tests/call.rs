@@ -0,0 +1,41 @@
+use combine::parser::char::spaces;
+use combine::stream::position;
+use combine::EasyParser;
+use purua::state::{LuaResult, LuaState};
+use purua::value::Value;
+
+fn eval(l: &mut LuaState, src: &str) {
+    let mut parser = (spaces(), purua::parser::chunk());
+    let chunk = (parser
+        .easy_parse(position::Stream::new(src))
+        .expect("parse")
+        .0)
+        .1;
+    purua::eval::eval_chunk(l, chunk.as_ref()).unwrap();
+}
+
+fn sub(l: &mut LuaState) -> LuaResult<i32> {
+    let a = l.arg_int(1)?;
+    let b = l.arg_int(2)?;
+    l.returns(Value::Number(a - b));
+    Ok(1)
+}
+
+#[test]
+fn arguments_bind_first_to_last() {
+    let mut l = LuaState::new(65535);
+    purua::prelude::prelude(&mut l);
+    l.register_global_fn("sub", sub);
+    let f = l.get_global("sub").unwrap();
+    let r = l
+        .funcall(f, vec![Value::Number(10), Value::Number(3)])
+        .unwrap();
+    assert_eq!(r[0].to_int(), Some(7));
+
+    eval(&mut l, "function first(a)\n return a\nend\n");
+    let f = l.get_global("first").unwrap();
+    let r = l
+        .funcall(f, vec![Value::Number(1), Value::Number(2)])
+        .unwrap();
+    assert_eq!(r[0].to_int(), Some(1));
+}
@@ -0,0 +1,1329 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+use purua::state::LuaState;
+use purua::value::Value;
+
+// Collects what print writes
+#[derive(Clone, Default)]
+struct Sink(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink {
+    fn text(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+#[test]
+fn forin_example() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.run_string("tbl = { 1, 3, 5 }\nfor i, v in pairs(tbl)\ndo\n print(v)\nend\n")
+        .unwrap();
+}
+
+#[test]
+fn ops_dispatch() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.run_string("a = 1 <= 2\nb = 3 >= 4\nc = \"a\" < \"b\"\nd = 1 == \"1\"\ne = \"10\" + 1\nf = nil == nil\n").unwrap();
+    assert!(matches!(l.get_global("a").unwrap(), Value::Bool(true)));
+    assert!(matches!(l.get_global("b").unwrap(), Value::Bool(false)));
+    assert!(matches!(l.get_global("c").unwrap(), Value::Bool(true)));
+    assert!(matches!(l.get_global("d").unwrap(), Value::Bool(false)));
+    assert_eq!(l.get_global("e").unwrap().to_int(), Some(11));
+    assert!(matches!(l.get_global("f").unwrap(), Value::Bool(true)));
+    let r = l.run_string("x = 1 < \"a\"\n");
+    assert_eq!(
+        r.unwrap_err().message,
+        "attempt to compare number with string"
+    );
+}
+
+#[test]
+fn call_non_function() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let r = l.run_string("x = 5\nx()\n");
+    assert_eq!(
+        r.unwrap_err().message,
+        "attempt to call a number value (global 'x')"
+    );
+    let r = l.run_string("function f()\n local y = \"s\"\n y()\nend\nf()\n");
+    assert_eq!(
+        r.unwrap_err().message,
+        "attempt to call a string value (local 'y')"
+    );
+    l.run_string("function f()\n local p = print\n p(\"ok\")\nend\nf()\n")
+        .unwrap();
+}
+
+#[test]
+fn call_undefined_global() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let r = l.run_string("undefined_fn()\n");
+    assert_eq!(
+        r.unwrap_err().message,
+        "attempt to call a nil value (global 'undefined_fn')"
+    );
+}
+
+#[test]
+fn undefined_global_is_nil() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.run_string("if x == nil then\n y = 1\nend\nz = x\n")
+        .unwrap();
+    assert_eq!(l.get_global("y").unwrap().to_int(), Some(1));
+    assert!(matches!(l.get_global("z").unwrap(), Value::Nil));
+}
+
+#[test]
+fn local_scoping() {
+    let src = "x = \"global\"
+function f()
+  local x = \"outer\"
+  do
+    local x = \"inner\"
+    a = x
+  end
+  b = x
+  if true then
+    c = x
+    x = \"changed\"
+  end
+  d = x
+end
+f()
+e = x
+";
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.run_string(src).unwrap();
+    let g = |n: &str| l.get_global(n).unwrap().to_string().unwrap();
+    assert_eq!(g("a"), "inner");
+    assert_eq!(g("b"), "outer");
+    assert_eq!(g("c"), "outer");
+    assert_eq!(g("d"), "changed");
+    assert_eq!(g("e"), "global");
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.run_string("x = 1\ndo\n local x = 2\n y = x\nend\nz = x\n")
+        .unwrap();
+    assert_eq!(l.get_global("y").unwrap().to_int(), Some(2));
+    assert_eq!(l.get_global("z").unwrap().to_int(), Some(1));
+}
+
+#[test]
+fn nested_call_bases() {
+    let src = "function inner(x)
+  local y = x
+  return y
+end
+function outer(a)
+  local b = inner(a)
+  local c = inner(\"other\")
+  return b
+end
+r = outer(\"mine\")
+function noarg(p)
+  return p
+end
+n = noarg()
+";
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.run_string(src).unwrap();
+    assert_eq!(l.get_global("r").unwrap().to_string().unwrap(), "mine");
+    assert!(matches!(l.get_global("n").unwrap(), Value::Nil));
+    assert_eq!(l.reg.top, 0);
+    assert_eq!(l.reg.array.len(), 0);
+}
+
+#[test]
+fn multiple_assignment_swap() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.run_string("a, b = 1, 2\na, b = b, a\nc, d = 5\ne = 1, 2\n")
+        .unwrap();
+    assert_eq!(l.get_global("a").unwrap().to_int(), Some(2));
+    assert_eq!(l.get_global("b").unwrap().to_int(), Some(1));
+    assert!(matches!(l.get_global("d").unwrap(), Value::Nil));
+    assert_eq!(l.get_global("e").unwrap().to_int(), Some(1));
+    l.run_string(
+        "function f()\n local x = 1\n local y = 2\n x, y = y, x\n r1 = x\n r2 = y\nend\nf()\n",
+    )
+    .unwrap();
+    assert_eq!(l.get_global("r1").unwrap().to_int(), Some(2));
+    assert_eq!(l.get_global("r2").unwrap().to_int(), Some(1));
+}
+
+#[test]
+fn mixed_numeric_ops() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let op = |c: char, a: Value, b: Value| l.process_op(&c, a, b).unwrap();
+    let r = op('+', Value::Number(1), Value::Float(2.0));
+    assert!(matches!(r, Value::Float(f) if f == 3.0));
+    assert_eq!(r.to_display_string(), "3.0");
+    let r = op('/', Value::Number(3), Value::Number(2));
+    assert!(matches!(r, Value::Float(f) if f == 1.5));
+    let r = op('^', Value::Number(2), Value::Number(2));
+    assert_eq!(r.to_display_string(), "4.0");
+    let r = op('*', Value::Number(2), Value::Number(3));
+    assert!(matches!(r, Value::Number(6)));
+    assert!(matches!(
+        op('<', Value::Number(1), Value::Float(1.5)),
+        Value::Bool(true)
+    ));
+    assert!(matches!(
+        op('e', Value::Number(1), Value::Float(1.0)),
+        Value::Bool(true)
+    ));
+    assert_eq!(
+        op('/', Value::Number(1), Value::Number(0)).to_display_string(),
+        "inf"
+    );
+    let v = l.run_string("return 3 / 2");
+    assert!(matches!(v.unwrap(), Value::Float(f) if f == 1.5));
+}
+
+#[test]
+fn deep_expression_errors_cleanly() {
+    use purua::parser::Rule;
+    let mut l = LuaState::new(100);
+    let mut e = Box::new(Rule::Exp(Box::new(Rule::Numeral(1))));
+    for _ in 0..5_000 {
+        e = Box::new(Rule::Exp(Box::new(Rule::Prefixexp(e))));
+    }
+    let r = purua::eval::eval_exp(&mut l, &e);
+    assert_eq!(r.unwrap_err().message, "expression too complex");
+    assert_eq!(l.depth, 0);
+
+    // the parser itself needs a large stack for this much nesting
+    std::thread::Builder::new()
+        .stack_size(64 << 20)
+        .spawn(|| {
+            let src = format!("x = {}1{}", "(".repeat(90), ")".repeat(90));
+            let mut l = LuaState::builder().open_libs(true).max_depth(50).build();
+            let r = l.run_string(&src);
+            assert_eq!(r.unwrap_err().message, "expression too complex");
+            let mut l = LuaState::builder().open_libs(true).build();
+            l.run_string(&src).unwrap();
+            assert_eq!(l.get_global("x").unwrap().to_int(), Some(1));
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+#[test]
+fn long_operator_chains() {
+    let mut l = LuaState::builder().open_libs(true).max_depth(5).build();
+    let chain = |term: &str, op: &str, n: usize| vec![term; n].join(op);
+    let v = l
+        .run_string(&format!("return {}", chain("1", " + ", 250)))
+        .unwrap();
+    assert!(matches!(v, Value::Number(250)));
+    let v = l
+        .run_string(&format!("return {}", chain("\"a\"", " .. ", 250)))
+        .unwrap();
+    assert_eq!(v.to_string().unwrap(), "a".repeat(250));
+    let v = l
+        .run_string(&format!("return {}", chain("1", " ^ ", 250)))
+        .unwrap();
+    assert!(matches!(v, Value::Float(f) if f == 1.0));
+    let v = l
+        .run_string(&format!("return {} and 7", chain("1", " and ", 250)))
+        .unwrap();
+    assert!(matches!(v, Value::Number(7)));
+    let v = l
+        .run_string(&format!("return {} or false", chain("nil", " or ", 250)))
+        .unwrap();
+    assert!(matches!(v, Value::Bool(false)));
+    // the usual associativity still holds
+    let v = l.run_string("return 10 - 2 - 3").unwrap();
+    assert!(matches!(v, Value::Number(5)));
+    let v = l.run_string("return 2 ^ 3 ^ 2").unwrap();
+    assert!(matches!(v, Value::Float(f) if f == 512.0));
+    let v = l.run_string("return 1 .. 2 + 3 .. 4").unwrap();
+    assert_eq!(v.to_string().unwrap(), "154");
+    assert_eq!(l.depth, 0);
+}
+
+#[test]
+fn runaway_recursion_is_stack_overflow() {
+    std::thread::Builder::new()
+        .stack_size(16 << 20)
+        .spawn(|| {
+            let mut l = LuaState::builder().open_libs(true).build();
+            let e = l
+                .run_string("function f(n) return f(n + 1) end\nf(1)")
+                .unwrap_err();
+            assert!(e.message.ends_with("stack overflow"), "{}", e.message);
+            assert_eq!(l.call_depth, 0);
+            let v = l
+                .run_string("function d(n) if n == 0 then return 0 end return 1 + d(n - 1) end\nreturn d(150)")
+                .unwrap();
+            assert!(matches!(v, Value::Number(150)));
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+
+    let mut l = LuaState::builder()
+        .open_libs(true)
+        .max_call_depth(10)
+        .build();
+    l.run_string("function d(n) if n == 0 then return 0 end return 1 + d(n - 1) end")
+        .unwrap();
+    assert!(matches!(
+        l.run_string("return d(5)").unwrap(),
+        Value::Number(5)
+    ));
+    let e = l.run_string("return d(20)").unwrap_err();
+    assert!(e.message.ends_with("stack overflow"), "{}", e.message);
+}
+
+#[test]
+fn negation() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.run_string("x = 5\na = -x\nb = -(-5)\nc = - \"3\"\nd = -(-(-x))\ne = - \"1.5\"")
+        .unwrap();
+    assert_eq!(l.get_global("a").unwrap().to_int(), Some(-5));
+    assert_eq!(l.get_global("b").unwrap().to_int(), Some(5));
+    assert_eq!(l.get_global("c").unwrap().to_int(), Some(-3));
+    assert_eq!(l.get_global("d").unwrap().to_int(), Some(-5));
+    assert!(matches!(l.get_global("e").unwrap(), Value::Float(f) if f == -1.5));
+    let r = l.process_unop(&'-', Value::Number(i64::MIN)).unwrap();
+    assert!(matches!(r, Value::Number(i64::MIN)));
+    assert_eq!(
+        l.process_unop(&'-', Value::LuaString("abc".into()))
+            .unwrap_err()
+            .message,
+        "attempt to perform arithmetic on a string value"
+    );
+}
+
+#[test]
+fn bitwise_not() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.run_string("a = ~0\nb = ~5\nc = ~(~7)\nd = ~\"5\"")
+        .unwrap();
+    assert_eq!(l.get_global("a").unwrap().to_int(), Some(-1));
+    assert_eq!(l.get_global("b").unwrap().to_int(), Some(-6));
+    assert_eq!(l.get_global("c").unwrap().to_int(), Some(7));
+    assert_eq!(l.get_global("d").unwrap().to_int(), Some(-6));
+    let un = |v: Value| l.process_unop(&'~', v);
+    assert!(matches!(un(Value::Float(5.0)).unwrap(), Value::Number(-6)));
+    assert_eq!(
+        un(Value::Float(5.5)).unwrap_err().message,
+        "number has no integer representation"
+    );
+    assert_eq!(
+        un(Value::Float(1e300)).unwrap_err().message,
+        "number has no integer representation"
+    );
+    assert_eq!(
+        un(Value::Bool(true)).unwrap_err().message,
+        "attempt to perform bitwise operation on a boolean value"
+    );
+}
+
+#[test]
+fn division_and_floor_division() {
+    let mut l = LuaState::new(65535);
+    purua::prelude::prelude(&mut l);
+    let mut eval = |src: &str| l.run_string(&format!("return {}", src));
+    assert_eq!(eval("7/2").unwrap(), Value::Float(3.5));
+    assert!(matches!(eval("6/2").unwrap(), Value::Float(f) if f == 3.0));
+    assert!(matches!(eval("7//2").unwrap(), Value::Number(3)));
+    assert!(matches!(eval("-7//2").unwrap(), Value::Number(-4)));
+    assert!(matches!(eval("7//-2").unwrap(), Value::Number(-4)));
+    assert!(matches!(eval("-8//2").unwrap(), Value::Number(-4)));
+    assert!(matches!(eval("7 // 2 * 2").unwrap(), Value::Number(6)));
+    assert!(matches!(eval("\"7\"//2").unwrap(), Value::Number(3)));
+    assert!(matches!(eval("7/2//1").unwrap(), Value::Float(f) if f == 3.0));
+    assert_eq!(
+        eval("1//0").unwrap_err().message,
+        "attempt to perform 'n//0'"
+    );
+}
+
+#[test]
+fn or_default_idiom() {
+    let mut l = LuaState::new(65535);
+    purua::prelude::prelude(&mut l);
+    l.run_string("x = x or 10").unwrap();
+    assert_eq!(l.get_global("x").unwrap(), Value::Number(10));
+    l.run_string("x = x or 20").unwrap();
+    assert_eq!(l.get_global("x").unwrap(), Value::Number(10));
+    l.run_string("y = nil y = y or \"d\"").unwrap();
+    assert_eq!(l.get_global("y").unwrap(), Value::LuaString("d".into()));
+    l.run_string("z = false z = z or 0").unwrap();
+    assert_eq!(l.get_global("z").unwrap(), Value::Number(0));
+    l.run_string("w = 0 w = w or 5").unwrap();
+    assert_eq!(l.get_global("w").unwrap(), Value::Number(0));
+
+    assert_eq!(l.run_string("return nil and nosuch()").unwrap(), Value::Nil);
+    assert_eq!(
+        l.run_string("return 1 or nosuch()").unwrap(),
+        Value::Number(1)
+    );
+    assert_eq!(
+        l.run_string("return 1 and \"b\"").unwrap(),
+        Value::LuaString("b".into())
+    );
+    assert_eq!(l.run_string("return false or nil").unwrap(), Value::Nil);
+    assert_eq!(
+        l.run_string("return nil and 1 or 2").unwrap(),
+        Value::Number(2)
+    );
+}
+
+#[test]
+fn nested_table_constructors() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let t = l.run_string("return { {1, 2}, {3, {4}} }").unwrap();
+    let t = t.ensure_table().unwrap();
+    let first = t.get_int(1).ensure_table().unwrap();
+    assert_eq!(first.get_int(2), Value::Number(2));
+    let second = t.get_int(2).ensure_table().unwrap();
+    assert_eq!(second.get_int(1), Value::Number(3));
+    let inner = second.get_int(2).ensure_table().unwrap();
+    assert_eq!(inner.get_int(1), Value::Number(4));
+    assert_eq!(t.border(), 2);
+
+    let t = l.run_string("return { a = {x = 1}, {y = {}}, 5 }").unwrap();
+    let t = t.ensure_table().unwrap();
+    let a = t.get_str("a").ensure_table().unwrap();
+    assert_eq!(a.get_str("x"), Value::Number(1));
+    let y = t.get_int(1).ensure_table().unwrap().get_str("y");
+    assert!(y.ensure_table().unwrap().pairs().is_empty());
+    assert_eq!(t.get_int(2), Value::Number(5));
+}
+
+#[test]
+fn return_inside_loop_leaves_function() {
+    let sink = Sink::default();
+    let mut l = LuaState::builder()
+        .open_libs(true)
+        .output(sink.clone())
+        .build();
+    let r = l
+        .run_string(
+            "function find(t)\n\
+               for i, v in pairs(t) do\n\
+                 print(v)\n\
+                 if v == 20 then return i end\n\
+               end\n\
+               return 0\n\
+             end\n\
+             return find({10, 20, 30})",
+        )
+        .unwrap();
+    assert_eq!(r, Value::Number(2));
+    assert_eq!(sink.text(), "1020");
+    assert_eq!(l.run_string("return find({})").unwrap(), Value::Number(0));
+    // at the top level the return ends the chunk
+    let r = l
+        .run_string("for i, v in pairs({7, 8}) do return v end return 0")
+        .unwrap();
+    assert_eq!(r, Value::Number(7));
+    assert!(l.frame_stack.is_empty());
+}
+
+#[test]
+fn error_position_prefix() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let e = l.run_string("x = 1\n\nerror(\"boom\")").unwrap_err();
+    assert_eq!(e.message, "input:3: boom");
+    let e = l
+        .run_string("function f(m)\n  x = 2\n  error(m)\nend\n\n\nf(\"in f\")")
+        .unwrap_err();
+    assert_eq!(e.message, "input:3: in f");
+    let e = l
+        .run_string("-- comment\nreturn error(\"last\")")
+        .unwrap_err();
+    assert_eq!(e.message, "input:2: last");
+
+    let error = l.get_global("error").unwrap();
+    let e = l
+        .funcall(
+            error.clone(),
+            vec![Value::LuaString("plain".into()), Value::Number(0)],
+        )
+        .unwrap_err();
+    assert_eq!(e.message, "plain");
+    l.line = 4;
+    let e = l.funcall(error, vec![Value::Number(42)]).unwrap_err();
+    assert_eq!(e.message, "42");
+
+    // the prefix names the chunk the state was given
+    let mut l = LuaState::builder()
+        .open_libs(true)
+        .chunk_name("scripts/init.lua")
+        .build();
+    let e = l.run_string("\nerror(\"bad\")").unwrap_err();
+    assert_eq!(e.message, "scripts/init.lua:2: bad");
+}
+
+#[test]
+fn boolean_operators() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    assert_eq!(
+        l.run_string("return true == false").unwrap(),
+        Value::Bool(false)
+    );
+    assert_eq!(
+        l.run_string("return true ~= false").unwrap(),
+        Value::Bool(true)
+    );
+    assert_eq!(
+        l.run_string("return false == false").unwrap(),
+        Value::Bool(true)
+    );
+    assert_eq!(
+        l.run_string("return true == 1").unwrap(),
+        Value::Bool(false)
+    );
+    let e = l.run_string("return true + 1").unwrap_err();
+    assert_eq!(
+        e.message,
+        "attempt to perform arithmetic on a boolean value"
+    );
+    let e = l.run_string("return 1 - false").unwrap_err();
+    assert_eq!(
+        e.message,
+        "attempt to perform arithmetic on a boolean value"
+    );
+    let e = l.run_string("return true < false").unwrap_err();
+    assert_eq!(e.message, "attempt to compare two boolean values");
+}
+
+#[test]
+fn parenthesized_call_targets() {
+    let sink = Sink::default();
+    let mut l = LuaState::builder()
+        .open_libs(true)
+        .output(sink.clone())
+        .build();
+    l.run_string(
+        "function hello(x) return x + 1 end\n\
+         function getfn() return hello end",
+    )
+    .unwrap();
+    assert_eq!(
+        l.run_string("return (getfn())(1)").unwrap(),
+        Value::Number(2)
+    );
+    assert_eq!(l.run_string("return getfn()(2)").unwrap(), Value::Number(3));
+    assert_eq!(
+        l.run_string("return ( hello )(3) * 2").unwrap(),
+        Value::Number(8)
+    );
+    l.run_string("x = (print)(\"p\")").unwrap();
+    assert_eq!(sink.text(), "p");
+    let e = l.run_string("return (1)()").unwrap_err();
+    assert_eq!(e.message, "attempt to call a number value");
+    let e = l.run_string("return hello(1)()").unwrap_err();
+    assert_eq!(e.message, "attempt to call a number value");
+    assert!(purua::analyze::check_undefined(
+        &purua::parser::parse("return (getfn())(y)").unwrap(),
+        &["getfn"]
+    )
+    .iter()
+    .any(|d| d.name.as_deref() == Some("y")));
+}
+
+#[test]
+fn forin_with_single_value_iterator() {
+    let sink = Sink::default();
+    let mut l = LuaState::builder()
+        .open_libs(true)
+        .output(sink.clone())
+        .build();
+    l.run_string(
+        "n = 0\n\
+         function step() if n < 3 then n = n + 1 return n end return nil end\n\
+         function iter() return step end\n\
+         for x in iter() do print(x) end\n\
+         n = 0\n\
+         for x, extra in step do print(x) print(extra) end",
+    )
+    .unwrap();
+    assert_eq!(sink.text(), "1231nil2nil3nil");
+    let e = l.run_string("for x in 5 do end").unwrap_err();
+    assert_eq!(e.message, "attempt to call a number value");
+}
+
+#[test]
+fn positional_and_explicit_keys() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let table_ints = |t: &Value| {
+        let t = t.ensure_table().unwrap();
+        (1..=3).map(|i| t.get_int(i).to_int()).collect::<Vec<_>>()
+    };
+    let t = l.run_string("return {10, [2]=20}").unwrap();
+    assert_eq!(table_ints(&t), vec![Some(10), Some(20), None]);
+    let t = l.run_string("return {[1]=10, 20}").unwrap();
+    assert_eq!(table_ints(&t), vec![Some(20), None, None]);
+    let t = l.run_string("return {[2]=20, 10}").unwrap();
+    assert_eq!(table_ints(&t), vec![Some(10), Some(20), None]);
+    let t = l
+        .run_string("return {10, [1] = 99, [ 1 + 2 ] = 30, \"x\", [2.0] = 7, [\"k\"] = 5}")
+        .unwrap();
+    assert_eq!(table_ints(&t), vec![Some(10), None, Some(30)]);
+    let t = t.ensure_table().unwrap();
+    assert_eq!(t.get_int(2), Value::LuaString("x".to_string().into()));
+    assert_eq!(t.get_str("k"), Value::Number(5));
+    let e = l.run_string("return {[nil] = 1}").unwrap_err();
+    assert_eq!(e.message, "table index is nil");
+    let e = l.run_string("return {[0/0] = 1}").unwrap_err();
+    assert_eq!(e.message, "table index is NaN");
+}
+
+#[test]
+fn modulo() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let cases: &[(&str, Value)] = &[
+        ("return 5 % 3", Value::Number(2)),
+        ("return -5 % 3", Value::Number(1)),
+        ("return 5 % -3", Value::Number(-1)),
+        ("return -5 % -3", Value::Number(-2)),
+        ("return 6 % 3", Value::Number(0)),
+        ("return 2 + 7 % 4 * 2", Value::Number(8)),
+        ("return 5.5 % 2", Value::Float(1.5)),
+        ("return -5.5 % 2", Value::Float(0.5)),
+        ("return \"7\" % 4", Value::Number(3)),
+    ];
+    for (src, want) in cases {
+        let got = l.run_string(src).unwrap();
+        assert_eq!(got.type_name(), want.type_name(), "{}", src);
+        assert_eq!(&got, want, "{}", src);
+    }
+    assert_eq!(
+        l.run_string("return -9223372036854775807 - 1 % -1")
+            .unwrap(),
+        Value::Number(-9223372036854775807)
+    );
+    let e = l.run_string("return 5 % 0").unwrap_err();
+    assert_eq!(e.message, "attempt to perform 'n%0'");
+    let nan = l.run_string("return 5.0 % 0").unwrap();
+    assert!(nan.to_float().unwrap().is_nan());
+}
+
+#[test]
+fn index_function_form() {
+    use purua::function::LuaFunction;
+    use std::rc::Rc;
+
+    let mut l = LuaState::builder().open_libs(true).build();
+    // a default-value proxy: missing keys read as their length
+    let proxy = Value::newtable();
+    let mt = Rc::new(purua::table::LuaTable::empty());
+    mt.set_str(
+        "__index",
+        Value::Function(LuaFunction::from_fn(|l| {
+            let key = l.arg_string(2)?;
+            l.returns(Value::Number(key.len() as i64));
+            Ok(1)
+        })),
+    );
+    let t = proxy.ensure_table().unwrap();
+    t.set_metatable(Some(mt.clone()));
+    t.set_str("own", Value::Number(42));
+    assert_eq!(l.index_str(&proxy, "abc").unwrap(), Value::Number(3));
+    assert_eq!(l.index_str(&proxy, "own").unwrap(), Value::Number(42));
+
+    // the function form also serves method lookups
+    let obj = Value::newtable();
+    let omt = Rc::new(purua::table::LuaTable::empty());
+    omt.set_str(
+        "__index",
+        Value::Function(LuaFunction::from_fn(|l| {
+            let key = l.arg_string(2)?;
+            l.returns(Value::Function(LuaFunction::from_closure(move |l| {
+                l.returns(Value::LuaString(format!("called {}", key).into()));
+                Ok(1)
+            })));
+            Ok(1)
+        })),
+    );
+    obj.ensure_table().unwrap().set_metatable(Some(omt));
+    l.assign_global("obj", obj);
+    assert_eq!(
+        l.run_string("return obj:hello()").unwrap(),
+        Value::LuaString("called hello".to_string().into())
+    );
+
+    // a table whose __index is itself never finds the key
+    let looped = Value::newtable();
+    let lt = looped.ensure_table().unwrap();
+    let lmt = Rc::new(purua::table::LuaTable::empty());
+    lmt.set_str("__index", looped.clone());
+    lt.set_metatable(Some(lmt));
+    let e = l.index_str(&looped, "x").unwrap_err();
+    assert_eq!(e.message, "'__index' chain too long; possibly a loop");
+}
+
+#[test]
+fn exponentiation() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let cases: &[(&str, f64)] = &[
+        ("return 2^10", 1024.0),
+        ("return 2^2^3", 256.0),
+        ("return -2^2", -4.0),
+        ("return - 2 ^ 2", -4.0),
+        ("return 2^-1", 0.5),
+        ("return 2^-1^2", 0.5),
+        ("return 3 * 2^2", 12.0),
+        ("return 2^3 % 5", 3.0),
+        ("return (2^2)^3", 64.0),
+        ("return \"2\"^2", 4.0),
+    ];
+    for (src, want) in cases {
+        let got = l.run_string(src).unwrap();
+        assert_eq!(got.type_name(), "number", "{}", src);
+        assert!(matches!(got, Value::Float(_)), "{}: {:?}", src, got);
+        assert_eq!(got, Value::Float(*want), "{}", src);
+    }
+    let e = l.run_string("return {}^2").unwrap_err();
+    assert_eq!(e.message, "attempt to perform arithmetic on a table value");
+}
+
+#[test]
+fn method_definitions_and_metatables() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.run_string(
+        "Account = { util = {} }\n\
+         function Account.new(n) return n * 2 end\n\
+         function Account:me() return self end\n\
+         function Account:plus(v) return v + 1 end\n\
+         function Account.util.twice(x) return x .. x end\n\
+         function Account.util:name() return \"util\" end\n\
+         obj = {}",
+    )
+    .unwrap();
+    let account = l.get_global("Account").unwrap();
+    let obj = l.get_global("obj").unwrap();
+    let t = account.ensure_table().unwrap();
+    assert_eq!(t.get_str("me").type_name(), "function");
+    assert!(l.get_global("new").is_none());
+    t.set_str("__index", account.clone());
+
+    let setmt = l.get_global("setmetatable").unwrap();
+    let ret = l.call(setmt, vec![obj.clone(), account.clone()]).unwrap();
+    assert_eq!(ret, vec![obj.clone()]);
+    let getmt = l.get_global("getmetatable").unwrap();
+    assert_eq!(
+        l.call(getmt.clone(), vec![obj.clone()]).unwrap(),
+        vec![account.clone()]
+    );
+
+    assert_eq!(l.run_string("return obj:me()").unwrap(), obj);
+    assert_eq!(
+        l.run_string("return obj:plus(41)").unwrap(),
+        Value::Number(42)
+    );
+    assert_eq!(
+        l.run_string("return Account:plus(1)").unwrap(),
+        Value::Number(2)
+    );
+    let util = l.index_str(&account, "util").unwrap();
+    let name = l.index_str(&util, "name").unwrap();
+    assert_eq!(
+        l.call(name, vec![util.clone()]).unwrap(),
+        vec![Value::LuaString("util".to_string().into())]
+    );
+    let twice = l.index_str(&util, "twice").unwrap();
+    assert_eq!(
+        l.call(twice, vec![Value::LuaString("ab".to_string().into())])
+            .unwrap(),
+        vec![Value::LuaString("abab".to_string().into())]
+    );
+
+    let e = l.run_string("function missing.f() end").unwrap_err();
+    assert_eq!(e.message, "attempt to index a nil value");
+
+    // a __metatable field protects the metatable
+    account
+        .ensure_table()
+        .unwrap()
+        .set_str("__metatable", Value::LuaString("locked".to_string().into()));
+    assert_eq!(
+        l.call(getmt, vec![obj.clone()]).unwrap(),
+        vec![Value::LuaString("locked".to_string().into())]
+    );
+    let setmt = l.get_global("setmetatable").unwrap();
+    let e = l.call(setmt, vec![obj, Value::Nil]).unwrap_err();
+    assert_eq!(e.message, "cannot change a protected metatable");
+}
+
+#[test]
+fn numeric_for() {
+    let sink = Sink::default();
+    let mut l = LuaState::builder()
+        .open_libs(true)
+        .output(sink.clone())
+        .build();
+    l.run_string("for i=1,3 do print(i) end").unwrap();
+    assert_eq!(sink.text(), "123");
+    l.run_string("for i=3,1,-1 do print(i) end").unwrap();
+    assert_eq!(sink.text(), "123321");
+    l.run_string("for i=1,0 do print(i) end\nfor i=0,1,-1 do print(i) end")
+        .unwrap();
+    assert_eq!(sink.text(), "123321");
+    l.run_string("for i=1,2.5 do print(i) end").unwrap();
+    assert_eq!(sink.text(), "12332112");
+    l.run_string("for x=0.5,1.5,0.5 do print(x) end").unwrap();
+    assert_eq!(sink.text(), "123321120.51.01.5");
+
+    let mut l = LuaState::builder().open_libs(true).build();
+    let r = l
+        .run_string("function first(n) for i=n,100 do return i * 2 end end return first(7)")
+        .unwrap();
+    assert_eq!(r, Value::Number(14));
+    // the loop variable is local to the body
+    let r = l.run_string("i = 5 for i=1,3 do end return i").unwrap();
+    assert_eq!(r, Value::Number(5));
+    // ends of the integer range do not overflow
+    let r = l
+        .run_string("n = 0 for i=9223372036854775806,9223372036854775807 do n = n + 1 end return n")
+        .unwrap();
+    assert_eq!(r, Value::Number(2));
+    let r = l
+        .run_string(
+            "n = 0 for i=1,9223372036854775807,4611686018427387904 do n = n + 1 end return n",
+        )
+        .unwrap();
+    assert_eq!(r, Value::Number(2));
+    let r = l
+        .run_string("n = 0 for i=1,1e100 do n = n + 1 if n == 3 then return n end end")
+        .unwrap();
+    assert_eq!(r, Value::Number(3));
+
+    let e = l.run_string("for i=1,3,0 do end").unwrap_err();
+    assert_eq!(e.message, "'for' step is zero");
+    let e = l.run_string("for i=1,\"x\" do end").unwrap_err();
+    assert_eq!(e.message, "'for' limit must be a number");
+    let e = l.run_string("for i=nil,1 do end").unwrap_err();
+    assert_eq!(e.message, "'for' initial value must be a number");
+}
+
+#[test]
+fn break_loops() {
+    let sink = Sink::default();
+    let mut l = LuaState::builder()
+        .open_libs(true)
+        .output(sink.clone())
+        .build();
+    l.run_string("for i=1,10 do if i == 4 then break end print(i) end print(\"|\")")
+        .unwrap();
+    assert_eq!(sink.text(), "123|");
+    l.run_string(
+        "for i=1,3 do\n\
+           for j=1,3 do\n\
+             if j > i then break end\n\
+             print(j)\n\
+           end\n\
+           print(\";\")\n\
+         end",
+    )
+    .unwrap();
+    assert_eq!(sink.text(), "123|1;12;123;");
+    l.run_string("for k, v in pairs({5, 6, 7}) do print(v) break end")
+        .unwrap();
+    assert_eq!(sink.text(), "123|1;12;123;5");
+    l.run_string("for x=0.5,3.5 do do break end print(x) end")
+        .unwrap();
+    assert_eq!(sink.text(), "123|1;12;123;5");
+
+    // the flag does not leak into code after the loop
+    let r = l
+        .run_string(
+            "function f()\n\
+               local n = 0\n\
+               for i=1,5 do n = i if i == 2 then break end end\n\
+               for i=1,5 do n = n + 10 end\n\
+               return n\n\
+             end\n\
+             return f()",
+        )
+        .unwrap();
+    assert_eq!(r, Value::Number(52));
+
+    let e = l.run_string("break").unwrap_err();
+    assert_eq!(e.message, "break outside a loop");
+    let e = l
+        .run_string("function g() break end for i=1,2 do g() end")
+        .unwrap_err();
+    assert_eq!(e.message, "break outside a loop");
+    assert_eq!(l.run_string("return 1").unwrap(), Value::Number(1));
+}
+
+#[test]
+fn goto_forward() {
+    let sink = Sink::default();
+    let mut l = LuaState::builder()
+        .open_libs(true)
+        .output(sink.clone())
+        .build();
+    l.run_string("print(1)\ngoto skip\nprint(2)\n::skip::\nprint(3)")
+        .unwrap();
+    assert_eq!(sink.text(), "13");
+    // continue
+    l.run_string(
+        "for i=1,5 do\n\
+           if i % 2 == 0 then goto continue end\n\
+           print(i)\n\
+           ::continue::\n\
+         end",
+    )
+    .unwrap();
+    assert_eq!(sink.text(), "13135");
+    // out of nested loops
+    l.run_string(
+        "for i=1,3 do\n\
+           for j=1,3 do\n\
+             if i * j == 4 then goto done end\n\
+             print(j)\n\
+           end\n\
+         end\n\
+         ::done:: print(\"!\")",
+    )
+    .unwrap();
+    assert_eq!(sink.text(), "131351231!");
+    // backwards
+    let r = l
+        .run_string("n = 0\n::top::\nn = n + 1\nif n < 5 then goto top end\nreturn n")
+        .unwrap();
+    assert_eq!(r, Value::Number(5));
+
+    let e = l.run_string("goto nowhere").unwrap_err();
+    assert_eq!(e.message, "no visible label 'nowhere' for goto");
+    let e = l
+        .run_string("function f() goto out end ::out:: f()")
+        .unwrap_err();
+    assert_eq!(e.message, "no visible label 'out' for goto");
+}
+
+#[test]
+fn pairs_follow_insertion() {
+    let sink = Sink::default();
+    let mut l = LuaState::builder()
+        .open_libs(true)
+        .output(sink.clone())
+        .build();
+    let keys = ["c", "a", "b", "zz", "m", "q", "e", "k"];
+    let fields: Vec<String> = keys.iter().map(|k| format!("[\"{}\"] = 1", k)).collect();
+    let src = format!(
+        "t = {{10, 20, {}}}\nfor k, v in pairs(t) do print(k) end\nprint(next({{}}))",
+        fields.join(", ")
+    );
+    let r = l.run_string(&src);
+    assert!(r.is_ok(), "{:?}", r);
+    let mut want = vec!["1".to_string(), "2".to_string()];
+    want.extend(keys.iter().map(|k| k.to_string()));
+    want.push("nil".to_string());
+    assert_eq!(sink.text(), want.concat());
+
+    let t = purua::table::LuaTable::empty();
+    t.set_str("b", Value::Number(1));
+    t.set_str("a", Value::Number(2));
+    let (k, _) = t.next(&Value::LuaString("b".into())).unwrap().unwrap();
+    assert_eq!(k.as_str(), Some("a"));
+    assert!(t.next(&Value::LuaString("a".into())).unwrap().is_none());
+    let e = t.next(&Value::LuaString("x".into())).unwrap_err();
+    assert_eq!(e.message, "invalid key to 'next'");
+
+    // clearing fields while walking keeps the walk going
+    let r = l.run_string(
+        "t = {a = 1, b = 2, c = 3}\nn = 0\nfor k in pairs(t) do t[k] = nil n = n + 1 end\nreturn n == 3 and next(t) == nil",
+    );
+    assert_eq!(r.unwrap(), Value::Bool(true));
+}
+
+#[test]
+fn functions_equal_by_identity() {
+    let sink = Sink::default();
+    let mut l = LuaState::builder()
+        .open_libs(true)
+        .output(sink.clone())
+        .build();
+    let src = "function f() end\ng = f\nprint(f == g)\nprint(f ~= g)\nh1 = function() end\nh2 = function() end\nprint(h1 == h2)\nprint(print == print)";
+    let r = l.run_string(src);
+    assert!(r.is_ok(), "{:?}", r);
+    assert_eq!(sink.text(), "truefalsefalsetrue");
+}
+
+#[test]
+fn repeat_until() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let r = l.run_string("n = 0\nrepeat n = n + 1 until true");
+    assert!(r.is_ok(), "{:?}", r);
+    assert_eq!(l.get_global("n").unwrap().to_int(), Some(1));
+
+    let src = "function f()\n local i = 0\n repeat\n  local j = i * 2\n  i = i + 1\n until j >= 6\n return i\nend\nx = f()";
+    let mut l = LuaState::builder().open_libs(true).build();
+    let r = l.run_string(src);
+    assert!(r.is_ok(), "{:?}", r);
+    assert_eq!(l.get_global("x").unwrap().to_int(), Some(4));
+
+    let src = "function g()\n local i = 0\n repeat\n  i = i + 1\n  if i == 3 then return i * 10 end\n until false\nend\ny = g()\nz = 0\nrepeat z = z + 1\n if z == 5 then break end\nuntil false";
+    let mut l = LuaState::builder().open_libs(true).build();
+    let r = l.run_string(src);
+    assert!(r.is_ok(), "{:?}", r);
+    assert_eq!(l.get_global("y").unwrap().to_int(), Some(30));
+    assert_eq!(l.get_global("z").unwrap().to_int(), Some(5));
+
+    let e = purua::parser::parse("repeat x = 1").unwrap_err().message;
+    assert!(e.contains("'until' to close 'repeat'"), "{}", e);
+}
+
+#[test]
+fn mutual_recursion_between_globals() {
+    let src = "function a() return b() end\nfunction b() return 1 end\nx = a()\nfunction even(n)\n if n == 0 then return true end\n return odd(n - 1)\nend\nfunction odd(n)\n if n == 0 then return false end\n return even(n - 1)\nend\ny = even(10)\nz = odd(7)";
+    let mut l = LuaState::builder().open_libs(true).build();
+    let r = l.run_string(src);
+    assert!(r.is_ok(), "{:?}", r);
+    assert_eq!(l.get_global("x").unwrap().to_int(), Some(1));
+    assert_eq!(l.get_global("y").unwrap().as_bool(), Some(true));
+    assert_eq!(l.get_global("z").unwrap().as_bool(), Some(true));
+}
+
+#[test]
+fn do_return_leaves_function() {
+    let sink = Sink::default();
+    let mut l = LuaState::builder()
+        .open_libs(true)
+        .output(sink.clone())
+        .build();
+    let r = l.run_string("function f()\n do return 5 end print(99)\nend\nx = f()");
+    assert!(r.is_ok(), "{:?}", r);
+    assert_eq!(l.get_global("x").unwrap().to_int(), Some(5));
+    assert_eq!(sink.text(), "");
+    let mut l = LuaState::builder().open_libs(true).build();
+    assert_eq!(l.run_string("do return end").unwrap(), Value::Nil);
+    let r = l.run_string("function g()\n do return end\n error(\"unreachable\")\nend\ng()\ndo return end\nerror(\"unreachable\")");
+    assert!(r.is_ok(), "{:?}", r);
+    let r = l.run_string("do return 7 end\nerror(\"unreachable\")");
+    assert_eq!(r.unwrap().to_int(), Some(7));
+}
+
+#[test]
+fn call_with_argument_lists() {
+    let sink = Sink::default();
+    let mut l = LuaState::builder()
+        .open_libs(true)
+        .output(sink.clone())
+        .build();
+    l.register_global_closure("sum3", |l| {
+        let n = l.arg_count()?;
+        let s = l.arg_int(1)? + l.arg_int(2)? * 10 + l.arg_int(3)? * 100;
+        l.returns(Value::Number(s * 10 + n as i64));
+        Ok(1)
+    });
+    let src = "x = sum3(1, 2, 3)\nfunction two() return 5 end\ny = sum3(1, 2, two())\nfunction first(a) return a end\nz = first(7, 8)\nprint(1, \"a\", nil)\nprint()";
+    let r = l.run_string(src);
+    assert!(r.is_ok(), "{:?}", r);
+    assert_eq!(l.get_global("x").unwrap().to_int(), Some(3213));
+    assert_eq!(l.get_global("y").unwrap().to_int(), Some(5213));
+    assert_eq!(l.get_global("z").unwrap().to_int(), Some(7));
+    assert_eq!(sink.text(), "1\ta\tnil");
+}
+
+#[test]
+fn functions_take_several_params() {
+    let src = "function add(a, b) return a + b end\nx = add(2,3)\nfunction pick( a, b , c ) return c end\ny = pick(1, 2)\nz = pick(1, 2, 3, 4)\nf = function(p, q) return p * q end\nw = f(6, 7)";
+    let mut l = LuaState::builder().open_libs(true).build();
+    let r = l.run_string(src);
+    assert!(r.is_ok(), "{:?}", r);
+    assert_eq!(l.get_global("x").unwrap().to_int(), Some(5));
+    assert_eq!(l.get_global("y").unwrap(), Value::Nil);
+    assert_eq!(l.get_global("z").unwrap().to_int(), Some(3));
+    assert_eq!(l.get_global("w").unwrap().to_int(), Some(42));
+}
+
+#[test]
+fn multiple_returns() {
+    let sink = Sink::default();
+    let mut l = LuaState::builder()
+        .open_libs(true)
+        .output(sink.clone())
+        .build();
+    let src = "function two() return 1, 2 end\nfunction none() end\nfunction bare() return end\nfunction wrap() return 0, two() end\na, b = two()\nc, d, e = wrap()\nprint(two())\nprint(none())\nprint(bare())\nprint(wrap())\nprint((two()))";
+    let r = l.run_string(src);
+    assert!(r.is_ok(), "{:?}", r);
+    let ints: Vec<_> = ["a", "b", "c", "d", "e"]
+        .iter()
+        .map(|n| l.get_global(n).unwrap().to_int())
+        .collect();
+    assert_eq!(ints, vec![Some(1), Some(2), Some(0), Some(1), Some(2)]);
+    assert_eq!(sink.text(), "1\t20\t1\t21");
+
+    let mut l = LuaState::builder().open_libs(true).build();
+    let f = l
+        .run_string("return function(x) return x, x * 2, x * 3 end")
+        .unwrap();
+    let ret = l.call(f, vec![Value::Number(2)]).unwrap();
+    assert_eq!(
+        ret,
+        vec![Value::Number(2), Value::Number(4), Value::Number(6)]
+    );
+}
+
+#[test]
+fn constructor_string_keys() {
+    let mut l = LuaState::new(65535);
+    purua::prelude::prelude(&mut l);
+    let v = l
+        .run_string("t = { 10, x = 1, 20, [\"y\"] = 2 }\nreturn t.x + t.y + t[1] + t[2] + #t")
+        .unwrap();
+    assert!(matches!(v, Value::Number(35)));
+}
+
+#[test]
+fn dot_field_access() {
+    let mut l = LuaState::new(65535);
+    purua::prelude::prelude(&mut l);
+    let v = l.run_string("t = { 10, x = 1, 20 }\nreturn t.x").unwrap();
+    assert!(matches!(v, Value::Number(1)));
+    let v = l
+        .run_string("u = { a = { b = \"deep\" } }\nreturn u.a.b")
+        .unwrap();
+    assert_eq!(v.as_str(), Some("deep"));
+    let v = l.run_string("return t.nope").unwrap();
+    assert!(matches!(v, Value::Nil));
+    let e = l.run_string("return nope.x").unwrap_err();
+    assert!(
+        e.message
+            .contains("attempt to index a nil value (global 'nope')"),
+        "{}",
+        e.message
+    );
+    let e = l.run_string("return u.c.d").unwrap_err();
+    assert!(e.message.contains("(field 'c')"), "{}", e.message);
+    let v = l.run_string("return \"a\"..t.x..\"b\"").unwrap();
+    assert_eq!(v.as_str(), Some("a1b"));
+}
+
+#[test]
+fn bracket_index_access() {
+    let mut l = LuaState::new(65535);
+    purua::prelude::prelude(&mut l);
+    l.run_string("t = { 10, 20, k = \"v\" }\nn = 2").unwrap();
+    for (src, want) in [
+        ("return t[1]", Value::Number(10)),
+        ("return t[n]", Value::Number(20)),
+        ("return t[1.0]", Value::Number(10)),
+        ("return t[3]", Value::Nil),
+        ("return t[0]", Value::Nil),
+        ("return t[\"k\"]", Value::LuaString("v".into())),
+        ("return t[\"nope\"]", Value::Nil),
+    ] {
+        let v = l.run_string(src).unwrap();
+        assert!(v.raw_equal(&want), "{}: {:?}", src, v);
+    }
+    let e = l.run_string("return nope[1]").unwrap_err();
+    assert!(
+        e.message
+            .contains("attempt to index a nil value (global 'nope')"),
+        "{}",
+        e.message
+    );
+}
+
+#[test]
+fn field_assignment() {
+    let mut l = LuaState::new(65535);
+    purua::prelude::prelude(&mut l);
+    l.run_string("t = { 1, x = \"old\" }\nt.y = \"new\"\nt.x = \"over\"\nt[\"z\"] = 3\nt[3] = 9")
+        .unwrap();
+    let t = match l.get_global("t").unwrap() {
+        Value::Table(t) => t,
+        v => panic!("{:?}", v),
+    };
+    assert_eq!(t.get_str("y").as_str(), Some("new"));
+    assert_eq!(t.get_str("x").as_str(), Some("over"));
+    assert!(matches!(t.get_str("z"), Value::Number(3)));
+    assert!(matches!(t.get_int(1), Value::Number(1)));
+    assert!(matches!(t.get_int(2), Value::Nil));
+    assert!(matches!(t.get_int(3), Value::Number(9)));
+    assert_eq!(t.vec.borrow().len(), 1);
+    // sparse keys stay out of the array part until the gap fills
+    let v = l
+        .run_string("s = {}\ns[1e9] = 1\ns[0] = 2\ns[-1] = 3\nreturn s[1e9] + s[0] + s[-1] + #s")
+        .unwrap();
+    assert!(matches!(v, Value::Number(6)));
+    l.run_string("t[2] = 8").unwrap();
+    assert_eq!(t.vec.borrow().len(), 3);
+    assert!(matches!(t.get_int(3), Value::Number(9)));
+    let v = l
+        .run_string("n = { a = {} }\nn.a.b, n.a[1] = 5, 6\nreturn n.a.b + n.a[1]")
+        .unwrap();
+    assert!(matches!(v, Value::Number(11)));
+    let e = l.run_string("nope.x = 1").unwrap_err();
+    assert!(
+        e.message
+            .contains("attempt to index a nil value (global 'nope')"),
+        "{}",
+        e.message
+    );
+    assert!(l.run_string("f() = 1").is_err());
+}
+
+#[test]
+fn length_operator() {
+    let mut l = LuaState::new(65535);
+    purua::prelude::prelude(&mut l);
+    assert!(matches!(
+        l.run_string("return #\"hello\"").unwrap(),
+        Value::Number(5)
+    ));
+    assert!(matches!(
+        l.run_string("return #{ 1, 2, 3 }").unwrap(),
+        Value::Number(3)
+    ));
+    assert!(matches!(
+        l.run_string("t = { 1, 2, 3 }\nt[4] = 4\nreturn #t")
+            .unwrap(),
+        Value::Number(4)
+    ));
+    assert!(l.run_string("return #5").is_err());
+}
+
+#[test]
+fn local_name_lists() {
+    let mut l = LuaState::new(65535);
+    purua::prelude::prelude(&mut l);
+    let v = l
+        .run_string("local a, b; return a == nil and b == nil")
+        .unwrap();
+    assert!(matches!(v, Value::Bool(true)));
+    let v = l
+        .run_string(
+            "local x, y, z = 1, 2\nlocal p, q = x + 10\nreturn x + y + p, z == nil and q == nil",
+        )
+        .unwrap();
+    assert!(matches!(v, Value::Number(14)));
+    let v = l
+        .run_string("local n = 1\nlocal n, m = n + 1, n\nreturn n * 10 + m")
+        .unwrap();
+    assert!(matches!(v, Value::Number(21)));
+}
+
+#[test]
+fn assigning_the_for_variable() {
+    let mut l = LuaState::new(65535);
+    purua::prelude::prelude(&mut l);
+    let v = l
+        .run_string("n = 0\nfor i = 1, 3 do\n i = 10\n n = n + 1\nend\nreturn n")
+        .unwrap();
+    assert!(matches!(v, Value::Number(3)));
+}
+
+#[test]
+fn unary_minus_and_bnot() {
+    let mut l = LuaState::new(65535);
+    purua::prelude::prelude(&mut l);
+    for (src, want) in [
+        ("return -5", -5),
+        ("return -(2 + 3)", -5),
+        ("return ~0", -1),
+        ("y = 4\nx = -y\nreturn x", -4),
+        ("return - -y", 4),
+    ] {
+        assert!(
+            matches!(l.run_string(src).unwrap(), Value::Number(n) if n == want),
+            "{}",
+            src
+        );
+    }
+    assert!(matches!(l.run_string("return -2.5").unwrap(), Value::Float(f) if f == -2.5));
+    assert!(l.run_string("return ~1.5").is_err());
+    assert!(l.run_string("return -{}").is_err());
+}
+
+#[test]
+fn goto_continue() {
+    let mut l = LuaState::new(65535);
+    purua::prelude::prelude(&mut l);
+    let v = l
+        .run_string(
+            "s = 0\nfor i = 1, 10 do\n if i % 2 == 1 then\n  goto cont\n end\n s = s + i\n ::cont::\nend\nreturn s",
+        )
+        .unwrap();
+    assert!(matches!(v, Value::Number(30)));
+}
+
+#[test]
+fn short_circuit_operands() {
+    let mut l = LuaState::new(65535);
+    purua::prelude::prelude(&mut l);
+    assert!(matches!(
+        l.run_string("return nil or 5").unwrap(),
+        Value::Number(5)
+    ));
+    assert!(matches!(
+        l.run_string("return 1 and 2").unwrap(),
+        Value::Number(2)
+    ));
+    assert!(matches!(
+        l.run_string("return false and error(\"boom\")").unwrap(),
+        Value::Bool(false)
+    ));
+    assert!(matches!(
+        l.run_string("return 3 or error(\"boom\")").unwrap(),
+        Value::Number(3)
+    ));
+    assert!(matches!(
+        l.run_string("return nil and 1").unwrap(),
+        Value::Nil
+    ));
+    assert!(l.run_string("return true and error(\"boom\")").is_err());
+}
+
+#[test]
+fn and_binds_tighter_than_or() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let cases: &[(&str, Value)] = &[
+        ("return true or false and false", Value::Bool(true)),
+        ("return 1 or 2 and nil", Value::Number(1)),
+        ("return nil and 1 or 2", Value::Number(2)),
+        ("return false or nil and error(\"boom\")", Value::Nil),
+        ("return (true or false) and false", Value::Bool(false)),
+        ("return 1 < 2 and 3 or 4", Value::Number(3)),
+    ];
+    for (src, want) in cases {
+        assert_eq!(&l.run_string(src).unwrap(), want, "{}", src);
+    }
+}
+
+#[test]
+fn local_from_multi_value_call() {
+    let mut l = LuaState::new(65535);
+    purua::prelude::prelude(&mut l);
+    l.run_string("function two() return 1, 2 end\nfunction three() return 1, 2, 3 end")
+        .unwrap();
+    let v = l
+        .run_string("local a, b, c = two()\nreturn a + b == 3 and c == nil")
+        .unwrap();
+    assert!(matches!(v, Value::Bool(true)));
+    let v = l
+        .run_string("local a, b, c = three()\nreturn a * 100 + b * 10 + c")
+        .unwrap();
+    assert!(matches!(v, Value::Number(123)));
+    // only the last expression spreads
+    let v = l
+        .run_string("local a, b, c = two(), 10\nreturn a + b, c == nil")
+        .unwrap();
+    assert!(matches!(v, Value::Number(11)));
+    let v = l
+        .run_string("x, y, z = 0, two()\nreturn x + y + z")
+        .unwrap();
+    assert!(matches!(v, Value::Number(3)));
+}
@@ -0,0 +1,28 @@
+use purua::state::LuaState;
+use purua::value::Value;
+
+fn os_fn(l: &LuaState, name: &str) -> Value {
+    let os = l.get_global("os").unwrap().ensure_table().unwrap();
+    let f = os.strdict.borrow().get(name).unwrap().clone();
+    f
+}
+
+#[test]
+fn difftime_of_two_times() {
+    let mut l = LuaState::new(65535);
+    purua::prelude::prelude(&mut l);
+    let now = l.funcall(os_fn(&l, "time"), vec![]).unwrap()[0].clone();
+    assert!(now.to_int().unwrap() > 1_600_000_000);
+    let later = Value::Number(now.to_int().unwrap() + 42);
+    let r = l.funcall(os_fn(&l, "difftime"), vec![later, now]).unwrap();
+    assert_eq!(r[0].to_int(), Some(42));
+}
+
+#[test]
+fn difftime_returns_a_float() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let r = l.run_string("return os.difftime(100, 58)").unwrap();
+    assert!(matches!(r, Value::Float(f) if f == 42.0));
+    let r = l.run_string("return os.difftime(1.5, 1)").unwrap();
+    assert!(matches!(r, Value::Float(f) if f == 0.5));
+}
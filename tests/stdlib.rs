@@ -0,0 +1,373 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+use purua::state::LuaState;
+use purua::value::Value;
+
+// Collects what print writes
+#[derive(Clone, Default)]
+struct Sink(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink {
+    fn text(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+#[test]
+fn string_len_bytes() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let r = l.run_string("s = \"é\"\nreturn #s").unwrap();
+    assert_eq!(r.to_int(), Some(2));
+    let r = l.run_string("return string.len(\"é\")").unwrap();
+    assert_eq!(r.to_int(), Some(2));
+}
+
+#[test]
+fn utf8_lib() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let r = l.run_string("return utf8.len(\"aé日\")").unwrap();
+    assert_eq!(r.to_int(), Some(3));
+    l.run_string("n, pos = utf8.len(\"é\", 2)").unwrap();
+    assert!(matches!(l.get_global("n").unwrap(), Value::Nil));
+    assert_eq!(l.get_global("pos").unwrap().to_int(), Some(2));
+    let r = l.run_string("return utf8.char(72, 233)").unwrap();
+    assert_eq!(r.to_string().unwrap(), "Hé");
+    l.run_string("a, b, c = utf8.codepoint(\"aé\", 1, -1)")
+        .unwrap();
+    assert_eq!(l.get_global("a").unwrap().to_int(), Some(97));
+    assert_eq!(l.get_global("b").unwrap().to_int(), Some(233));
+    assert!(matches!(l.get_global("c").unwrap(), Value::Nil));
+}
+
+#[test]
+fn missing_argument() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let e = l.run_string("return os.difftime(1)").unwrap_err();
+    assert_eq!(e.message, "bad argument #2: value expected");
+}
+
+// A file under the temp dir that no other test or concurrent run
+// shares
+fn temp_path(name: &str) -> String {
+    let file = format!("purua_{}_{}.txt", name, std::process::id());
+    std::env::temp_dir()
+        .join(file)
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+#[test]
+fn io_files() {
+    let p = temp_path("io_files");
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.assign_global("path", Value::LuaString(p.clone().into()));
+    l.run_string("f = io.open(path, \"w\")\nf:write(\"one\\n\", 2, \"\\nthree\\n\")\nf:close()")
+        .unwrap();
+    assert_eq!(std::fs::read_to_string(&p).unwrap(), "one\n2\nthree\n");
+
+    l.run_string("f = io.open(path)\na, b = f:read(\"n\", \"a\")")
+        .unwrap();
+    assert!(matches!(l.get_global("a").unwrap(), Value::Nil));
+    l.run_string("a, b = f:read(\"l\", \"n\")").unwrap();
+    assert_eq!(l.get_global("a").unwrap().to_string().unwrap(), "one");
+    assert_eq!(l.get_global("b").unwrap().to_int(), Some(2));
+    let r = l.run_string("return f:read(\"a\")").unwrap();
+    assert_eq!(r.to_string().unwrap(), "\nthree\n");
+
+    l.run_string("f, msg, code = io.open(\"/nonexistent/zzz\")")
+        .unwrap();
+    assert!(matches!(l.get_global("f").unwrap(), Value::Nil));
+    assert_eq!(
+        l.get_global("msg").unwrap().to_string().unwrap(),
+        "/nonexistent/zzz: No such file or directory"
+    );
+    assert_eq!(l.get_global("code").unwrap().to_int(), Some(2));
+    std::fs::remove_file(&p).unwrap();
+}
+
+#[test]
+fn io_lines_in_generic_for() {
+    let p = temp_path("io_lines");
+    std::fs::write(&p, "one\n2\n\nthree").unwrap();
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.assign_global("path", Value::LuaString(p.clone().into()));
+    let r = l
+        .run_string(
+            "local s = \"\"\n\
+             for line in io.lines(path) do s = s .. \"[\" .. line .. \"]\" end\n\
+             return s",
+        )
+        .unwrap();
+    assert_eq!(r.to_string().unwrap(), "[one][2][][three]");
+    let r = l
+        .run_string(
+            "local f = io.open(path)\n\
+             local n = 0\n\
+             for line in f:lines() do n = n + 1 end\n\
+             f:close()\n\
+             return n",
+        )
+        .unwrap();
+    assert_eq!(r.to_int(), Some(4));
+    std::fs::remove_file(&p).unwrap();
+}
+
+#[test]
+fn io_closed_handle() {
+    let p = temp_path("io_closed");
+    std::fs::write(&p, "a\nb\n").unwrap();
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.assign_global("path", Value::LuaString(p.clone().into()));
+    l.run_string("f = io.open(path)\nit = f:lines()\nf:close()")
+        .unwrap();
+    let e = l.run_string("f:read()").unwrap_err();
+    assert_eq!(e.message, "attempt to use a closed file");
+    let e = l.run_string("f:close()").unwrap_err();
+    assert_eq!(e.message, "attempt to use a closed file");
+    let e = l.run_string("it()").unwrap_err();
+    assert_eq!(e.message, "file is already closed");
+    std::fs::remove_file(&p).unwrap();
+}
+
+#[test]
+fn math_min_max() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let cases: &[(&str, Value)] = &[
+        ("return math.max(1, 2.0)", Value::Float(2.0)),
+        ("return math.max(1, 2)", Value::Number(2)),
+        ("return math.max(3.5, 2, 7)", Value::Number(7)),
+        ("return math.min(1, 1.0)", Value::Number(1)),
+        ("return math.min(1.0, 1)", Value::Float(1.0)),
+        ("return math.min(4, -0.5)", Value::Float(-0.5)),
+    ];
+    for (src, want) in cases {
+        let got = l.run_string(src).unwrap();
+        assert_eq!(got.type_name(), want.type_name(), "{}", src);
+        assert_eq!(&got, want, "{}", src);
+    }
+    let e = l.run_string("return math.max()").unwrap_err();
+    assert_eq!(
+        e.message,
+        "bad argument #1 to 'max' (number expected, got no value)"
+    );
+    let e = l.run_string("return math.min(1, true)").unwrap_err();
+    assert_eq!(
+        e.message,
+        "bad argument #2 to 'min' (number expected, got boolean)"
+    );
+}
+
+#[test]
+fn string_methods() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.run_string("a = (\"abc\"):sub(2)\nb = (\"hello\"):upper()\ns = \"Lua\"\nc = s:lower():upper():len()\nd = s:sub(-2)")
+        .unwrap();
+    assert_eq!(l.get_global("a").unwrap().to_string().unwrap(), "bc");
+    assert_eq!(l.get_global("b").unwrap().to_string().unwrap(), "HELLO");
+    assert_eq!(l.get_global("c").unwrap().to_int(), Some(3));
+    assert_eq!(l.get_global("d").unwrap().to_string().unwrap(), "ua");
+
+    for (i, j, want) in &[
+        (2, 3, "el"),
+        (0, 10, "hello"),
+        (-3, -2, "ll"),
+        (4, 2, ""),
+        (-100, 1, "h"),
+    ] {
+        let r = l
+            .run_string(&format!("return string.sub(\"hello\", {}, {})", i, j))
+            .unwrap();
+        assert_eq!(r.to_string().unwrap(), *want);
+    }
+    // byte indices, which may not cut a character in two
+    let r = l.run_string("return (\"héllo\"):sub(2, 3)").unwrap();
+    assert_eq!(r.to_string().unwrap(), "é");
+    assert_eq!(
+        l.run_string("return #(\"héllo\"):sub(4)").unwrap().to_int(),
+        Some(3)
+    );
+    let e = l.run_string("return (\"héllo\"):sub(2, 2)").unwrap_err();
+    assert_eq!(
+        e.message,
+        "bad argument to 'sub' (range splits a UTF-8 character)"
+    );
+    let e = l.run_string("x = 1\ny = x:foo()").unwrap_err();
+    assert_eq!(e.message, "attempt to index a number value");
+    let e = l.run_string("s = \"a\"\ny = s:nosuch()").unwrap_err();
+    assert_eq!(e.message, "attempt to call a nil value (method 'nosuch')");
+}
+
+#[test]
+fn table_move() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let cases = [
+        (
+            "a = {1, 2, 3, 4, 5} b = {} r = table.move(a, 2, 4, 1, b)",
+            "b",
+            "2,3,4",
+        ),
+        // shifting right overlaps: must copy from the end
+        (
+            "a = {1, 2, 3, 4, 5} r = table.move(a, 1, 4, 2)",
+            "a",
+            "1,1,2,3,4",
+        ),
+        // shifting left overlaps: must copy from the start
+        (
+            "a = {1, 2, 3, 4, 5} r = table.move(a, 2, 5, 1)",
+            "a",
+            "2,3,4,5,5",
+        ),
+        // moving past the end leaves a hole, so the border stays
+        ("a = {1, 2} r = table.move(a, 1, 2, 4)", "a", "1,2"),
+        // an empty range moves nothing
+        ("a = {1, 2} r = table.move(a, 3, 1, 1)", "a", "1,2"),
+    ];
+    for (src, dest, want) in cases {
+        l.run_string(src).unwrap();
+        let r = l.run_string(&format!("return r == {}", dest)).unwrap();
+        assert_eq!(r, Value::Bool(true), "{}", src);
+        let got = l.run_string(&format!(
+            "local s = \"\" for i = 1, #{0} do s = s .. (i > 1 and \",\" or \"\") .. tostring({0}[i]) end return s",
+            dest
+        ));
+        assert_eq!(got.unwrap().to_string().unwrap(), want, "{}", src);
+    }
+    let r = l.run_string("a = {1, 2} table.move(a, 1, 2, 4) return a[4] == 1 and a[5] == 2");
+    assert_eq!(r.unwrap(), Value::Bool(true));
+    // a far destination is a single sparse entry, not a huge array
+    let r = l.run_string("a = {1} table.move(a, 1, 1, 2^62) return a[2^62] == 1 and #a == 1");
+    assert_eq!(r.unwrap(), Value::Bool(true));
+    let e = l.run_string("table.move(1, 1, 1, 1)").unwrap_err();
+    assert_eq!(e.message, "bad argument #1 (table expected, got number)");
+}
+
+#[test]
+fn string_gmatch_split() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let cases = [
+        ("a  b c", "%S+", "a|b|c|"),
+        ("ab12cd3", "%D+", "ab|cd|"),
+        ("ab12cd3", "%A+", "12|3|"),
+        ("x=1, y=22", "(%a)=(%d+)", "x 1|y 22|"),
+        // empty matches advance instead of looping
+        ("abc", "x*", "||||"),
+        ("f(a(b)) g(c)", "%b()", "(a(b))|(c)|"),
+        ("THE (quick) fox", "%f[%a]%a+", "THE|quick|fox|"),
+        ("k1 v2", "[^%s]-(%d)", "1|2|"),
+    ];
+    for (s, pat, want) in cases {
+        l.assign_global("s", Value::LuaString(s.into()));
+        l.assign_global("pat", Value::LuaString(pat.into()));
+        let got = l
+            .run_string("local out = \"\"\nfor a, b in string.gmatch(s, pat) do\n out = out .. a .. (b and \" \" .. b or \"\") .. \"|\"\nend\nreturn out")
+            .unwrap();
+        assert_eq!(got.to_string().unwrap(), want, "{} {}", s, pat);
+    }
+
+    let e = l
+        .run_string("local it = string.gmatch(\"a\", \"[a\")\nit()")
+        .unwrap_err();
+    assert_eq!(e.message, "malformed pattern (missing ']')");
+
+    let r = l
+        .run_string("n = 0 s = \"a  b c\" for w in s:gmatch(\"%S+\") do n = n + 1 end return n")
+        .unwrap();
+    assert_eq!(r.to_int(), Some(3));
+}
+
+#[test]
+fn print_matches_tostring() {
+    let sink = Sink::default();
+    let mut l = LuaState::builder()
+        .open_libs(true)
+        .output(sink.clone())
+        .build();
+    let named = Value::newtable();
+    let mt = purua::table::LuaTable::empty();
+    mt.set_str(
+        "__tostring",
+        Value::Function(purua::function::LuaFunction::from_fn(|l| {
+            l.returns(Value::LuaString("<point>".into()));
+            Ok(1)
+        })),
+    );
+    named
+        .ensure_table()
+        .unwrap()
+        .set_metatable(Some(Rc::new(mt)));
+    l.assign_global("plain", Value::newtable());
+    l.assign_global("named", named);
+
+    for src in [
+        "plain", "named", "42", "3 / 2", "true", "nil", "\"s\"", "print",
+    ] {
+        sink.0.borrow_mut().clear();
+        l.run_string(&format!("print({})", src)).unwrap();
+        let shown = l.run_string(&format!("return tostring({})", src)).unwrap();
+        assert_eq!(Value::LuaString(sink.text().into()), shown, "{}", src);
+    }
+    assert_eq!(
+        sink.text(),
+        l.value_to_display(&l.get_global("print").unwrap()).unwrap()
+    );
+    assert_eq!(
+        l.run_string("return tostring(named)").unwrap(),
+        Value::LuaString("<point>".into())
+    );
+    assert!(matches!(l.run_string("return tostring(plain)").unwrap(),
+        Value::LuaString(s) if s.starts_with("table: 0x")));
+    assert_eq!(
+        l.run_string("return tostring(true)").unwrap(),
+        Value::LuaString("true".into())
+    );
+}
+
+#[test]
+fn assert_returns_its_arguments() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    assert_eq!(
+        l.run_string("local x = assert(5) return x == 5").unwrap(),
+        Value::Bool(true)
+    );
+    assert_eq!(
+        l.run_string("return assert(\"v\")").unwrap(),
+        Value::LuaString("v".into())
+    );
+    let e = l.run_string("assert(nil)").unwrap_err();
+    assert_eq!(e.message, "assertion failed!");
+    let assert = l.get_global("assert").unwrap();
+    let e = l.funcall(assert.clone(), vec![]).unwrap_err();
+    assert_eq!(e.message, "bad argument #1 to 'assert' (value expected)");
+    let r = l
+        .funcall(
+            assert.clone(),
+            vec![Value::Number(1), Value::LuaString("m".into()), Value::Nil],
+        )
+        .unwrap();
+    assert_eq!(
+        r,
+        vec![Value::Number(1), Value::LuaString("m".into()), Value::Nil]
+    );
+    let e = l
+        .funcall(
+            assert,
+            vec![Value::Bool(false), Value::LuaString("boom".into())],
+        )
+        .unwrap_err();
+    assert_eq!(e.message, "boom");
+}
@@ -0,0 +1,479 @@
+use combine::stream::position;
+use combine::EasyParser;
+use purua::parser::parse;
+use purua::state::LuaState;
+use purua::value::Value;
+
+#[test]
+fn table_constructors() {
+    for src in &[
+        "t = {}",
+        "t = {1}",
+        "t = {1,2,}",
+        "t = { }",
+        "t = {1, 2, 3}",
+    ] {
+        assert!(parse(src).is_ok(), "{}", src);
+    }
+    for src in &["t = {;}", "t = {,}", "t = {1,,}", "t = {1;;}"] {
+        assert!(parse(src).is_err(), "{}", src);
+    }
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.run_string("t = {1,2,}\ne = {}\n").unwrap();
+    assert_eq!(
+        l.get_global("t")
+            .unwrap()
+            .ensure_table()
+            .unwrap()
+            .vec
+            .borrow()
+            .len(),
+        2
+    );
+    assert_eq!(
+        l.get_global("e")
+            .unwrap()
+            .ensure_table()
+            .unwrap()
+            .vec
+            .borrow()
+            .len(),
+        0
+    );
+}
+
+#[test]
+fn pairs_empty() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.run_string("for i, v in pairs({}) do print(v) end\n")
+        .unwrap();
+}
+
+#[test]
+fn table_mixed_separators() {
+    for src in &[
+        "t = {1; 2, 3;}",
+        "t = {1; 2; 3}",
+        "t = {1, 2; 3,}",
+        "x = 1\nt = {x; x, 2}",
+        "t = { \"a\" ; \"b\" }",
+    ] {
+        assert!(parse(src).is_ok(), "{}", src);
+    }
+    assert!(parse("t = {1,,2}").is_err());
+    let mut l = LuaState::builder().open_libs(true).build();
+    let r = l.run_string("t = {1; 2, 3;}\n");
+    r.unwrap();
+    assert_eq!(
+        l.get_global("t")
+            .unwrap()
+            .ensure_table()
+            .unwrap()
+            .vec
+            .borrow()
+            .len(),
+        3
+    );
+}
+
+#[test]
+fn unterminated_constructs() {
+    for (src, msg) in &[
+        ("function f()\n  print(1)\n", "'end' to close 'function'"),
+        ("if true then\n  print(1)\n", "'end' to close 'if'"),
+        (
+            "if true then x = 1 elseif false then x = 2 else\n",
+            "'end' to close 'if'",
+        ),
+        ("do\n x = 1\n", "'end' to close 'do'"),
+        ("for i in pairs(t) do\n x = 1\n", "'end' to close 'for'"),
+        ("for i = 1, 2 do\n x = 1\n", "'end' to close 'for'"),
+        ("function f()\n do x = 1 end\n", "'end' to close 'function'"),
+        ("x = \"abc\n", "closing '\"' of the string"),
+        ("print(\"abc)\n", "closing '\"' of the string"),
+        ("x = {1, 2\n", "'}' to close the table"),
+        ("print(\"a\"\n", "')' to close the arguments"),
+    ] {
+        let e = parse(src).unwrap_err().message;
+        assert!(e.contains(msg), "{:?}: {}", src, e);
+    }
+    assert!(parse("done = 1\nfor_x = 2\nif done then x = ending end").is_ok());
+    assert!(parse("do\n x = 1\n")
+        .unwrap_err()
+        .message
+        .contains("line: 3"));
+}
+
+#[test]
+fn deep_syntax_errors_cleanly() {
+    std::thread::Builder::new()
+        .stack_size(8 << 20)
+        .spawn(deep_syntax_check)
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+fn deep_syntax_check() {
+    for src in &[
+        format!("x = {}1{}", "(".repeat(10_000), ")".repeat(10_000)),
+        format!("x = {}", "{".repeat(10_000)),
+        "do ".repeat(10_000),
+        format!("x = {}1", "- ".repeat(10_000)),
+    ] {
+        let e = parse(src).unwrap_err().message;
+        assert!(e.contains("chunk has too many syntax levels"), "{}", e);
+    }
+    let ok = format!("x = {}1{}", "(".repeat(98), ")".repeat(98));
+    assert!(parse(&ok).is_ok());
+    assert!(parse("do do do x = 1 end end end").is_ok());
+}
+
+#[test]
+fn comments_inside_constructors_and_args() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let t = l
+        .run_string("return { -- a comment\n 1, --[[ two ]] 2 -- last\n }")
+        .unwrap();
+    assert_eq!(t.ensure_table().unwrap().border(), 2);
+    let r = l
+        .run_string("return tostring( --[[x]] 1 ) .. tostring( 2 --[==[ ]] ]=] ]==] ) -- done")
+        .unwrap();
+    assert_eq!(r.to_string().unwrap(), "12");
+    let r = l
+        .run_string(
+            "-- leading comment\n\
+             function pick(a)\n\
+               for k, --[[ value ]] v in pairs(a) do return v end\n\
+             end\n\
+             return pick({ 7 }) --[[ trailing ]]",
+        )
+        .unwrap();
+    assert_eq!(r, Value::Number(7));
+    assert_eq!(
+        l.run_string("return 5 - -3 -- minus minus").unwrap(),
+        Value::Number(8)
+    );
+    let e = l.run_string("x = 1 --[[ never closed").unwrap_err();
+    assert!(
+        e.message.contains("unfinished long comment"),
+        "{}",
+        e.message
+    );
+}
+
+#[test]
+fn crlf_line_endings() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let src = "-- header\r\nx = 1 -- trailing\r\n--[[ long\r\n comment ]]\r\ny = \"a\"\r\nerror(\"here\")\r\n";
+    let e = l.run_string(src).unwrap_err();
+    assert_eq!(e.message, "input:6: here");
+    assert_eq!(l.get_global("y").unwrap().to_string().unwrap(), "a");
+    assert_eq!(l.run_string("x = 2\rreturn x\r").unwrap(), Value::Number(2));
+    let e = parse("x = \"open\r\ny = 1").unwrap_err();
+    assert!(e.message.contains("line: 1"), "{}", e.message);
+}
+
+#[test]
+fn float_numerals() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let cases: &[(&str, Value)] = &[
+        ("return 3.25", Value::Float(3.25)),
+        ("return 0.5 + 1", Value::Float(1.5)),
+        ("return 1e3", Value::Float(1000.0)),
+        ("return 2.5E-1", Value::Float(0.25)),
+        ("return .5", Value::Float(0.5)),
+        ("return 3.", Value::Float(3.0)),
+        ("return 0x10", Value::Number(16)),
+        ("return 0xffffffffffffffff", Value::Number(-1)),
+        ("return 9223372036854775807", Value::Number(i64::MAX)),
+        (
+            "return 9223372036854775808",
+            Value::Float(9223372036854775808.0),
+        ),
+        ("return 2 * 1.5", Value::Float(3.0)),
+    ];
+    for (src, want) in cases {
+        let r = l.run_string(src);
+        let got = r.unwrap();
+        assert_eq!(got.type_name(), want.type_name(), "{}", src);
+        assert_eq!(&got, want, "{}", src);
+    }
+    let r = l.run_string("return 1.5");
+    assert!(matches!(r.unwrap(), Value::Float(_)));
+}
+
+#[test]
+fn negative_numerals() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    let r = l.run_string("x = -3\ny = 1 - -2\nz = 2 - - 3\nw = 3 -1\nreturn -0");
+    assert_eq!(r.unwrap(), Value::Number(0));
+    assert_eq!(l.get_global("x"), Some(Value::Number(-3)));
+    assert_eq!(l.get_global("y"), Some(Value::Number(3)));
+    assert_eq!(l.get_global("z"), Some(Value::Number(5)));
+    assert_eq!(l.get_global("w"), Some(Value::Number(2)));
+    let r = l.run_string("return -1.5");
+    assert_eq!(r.unwrap(), Value::Float(-1.5));
+
+    let mut parser = purua::parser::exp();
+    let (e, _) = parser.easy_parse(position::Stream::new("-3")).unwrap();
+    let tree = format!("{:?}", e);
+    assert!(
+        tree.contains("Numeral(-3)") && !tree.contains("UnOp"),
+        "{}",
+        tree
+    );
+}
+
+#[test]
+fn comments_between_statements() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    l.run_string("x = 1 -- one\n--[==[ spans\n]] still\n]==] y = 2\n-- between\nz = x + y --[[ eof ]]\n-- tail")
+        .unwrap();
+    assert_eq!(l.get_global("z"), Some(Value::Number(3)));
+    assert_eq!(l.get_global("y"), Some(Value::Number(2)));
+}
+
+#[test]
+fn empty_blocks() {
+    let mut l = LuaState::builder().open_libs(true).build();
+    assert_eq!(l.run_string("").unwrap(), Value::Nil);
+    assert_eq!(l.run_string("-- only a comment\n").unwrap(), Value::Nil);
+    let r = l
+        .run_string(
+            "function noop() end\n\
+             do end\n\
+             if 1 then end\n\
+             if nil then else end\n\
+             function f() if nil then elseif nil then else end end\n\
+             for k, v in pairs({}) do end\n\
+             return noop()",
+        )
+        .unwrap();
+    assert_eq!(r, Value::Nil);
+    assert_eq!(l.run_string("return f()").unwrap(), Value::Nil);
+}
+
+#[test]
+fn return_takes_one_semicolon() {
+    for src in &[
+        "return;",
+        "return 1;",
+        "return 1, 2 ;",
+        "function f() return 1; end",
+        "do return; end",
+    ] {
+        assert!(parse(src).is_ok(), "{}", src);
+    }
+    for src in &["return;;", "return 1;;", "return 1; x = 2"] {
+        assert!(parse(src).is_err(), "{}", src);
+    }
+    let mut l = LuaState::builder().open_libs(true).build();
+    let r = l.run_string("function f() return 3; end\nreturn f() + 1;");
+    assert_eq!(r.unwrap().to_int(), Some(4));
+}
+
+#[test]
+fn lenient_not_equal() {
+    let mut l = LuaState::builder().open_libs(true).lenient(true).build();
+    assert_eq!(l.run_string("return 1 != 2").unwrap(), Value::Bool(true));
+    assert_eq!(l.run_string("return 1 != 1").unwrap(), Value::Bool(false));
+    assert_eq!(l.run_string("return 1 ~= 2").unwrap(), Value::Bool(true));
+
+    let mut l = LuaState::builder().open_libs(true).build();
+    let e = l.run_string("return 1 != 2").unwrap_err();
+    assert!(e.message.starts_with("Parse error"), "{}", e.message);
+    assert!(e.message.contains("'!=', use '~='"), "{}", e.message);
+    assert!(parse("x = 1 != 2").is_err());
+    assert!(purua::parser::parse_with("x = 1 != 2", true).is_ok());
+}
+
+#[test]
+fn unparse_round_trip() {
+    let src = r#"#!/usr/bin/env purua
+-- a comment
+local x = 1 local y
+a, b = -2, 3.5 ; c = - -1
+t = {1, "two\n", [3] = 4, k = {}, f = function(a, b) return a .. b end}
+function t.m.n:go(p, q)
+  if p < q and not q then return p
+  elseif p >= q or #t ~= 0 then
+    print((p + q) * 2 ^ -2 // 3 % 4, x:y(1)(2))
+  else
+    goto done
+  end
+  ::done::
+end
+for i = 1, 10, 2 do break end
+for k, v in pairs(t) do
+  repeat local z = k until z
+end
+do return end
+"#;
+    for text in std::iter::once(src.to_string()).chain(
+        std::fs::read_dir("lua_examples")
+            .unwrap()
+            .map(|e| std::fs::read_to_string(e.unwrap().path()).unwrap()),
+    ) {
+        let tree = parse(&text).unwrap();
+        let out = tree.to_source();
+        let again = parse(&out).unwrap_or_else(|e| panic!("{}\n{}", e, out));
+        assert_eq!(tree, again, "{}", out);
+    }
+}
+
+#[test]
+fn unparse_while_and_local_function() {
+    use purua::parser::{Rule, StatKind};
+    // the parser does not build these yet, so the trees are made by hand
+    let chunk = |stats: Vec<Box<Rule>>| {
+        let lines = vec![1; stats.len()];
+        Box::new(Rule::Block(Box::new(Rule::Chunk(stats, None, lines))))
+    };
+    let call = Box::new(Rule::Stat(
+        StatKind::FunctionCall,
+        Some(Box::new(Rule::FunctionCall(
+            Box::new(Rule::Symbol("f".to_string())),
+            Box::new(Rule::Args(vec![])),
+        ))),
+        None,
+        None,
+        None,
+        None,
+    ));
+    let stat = Rule::Stat(
+        StatKind::While,
+        Some(Box::new(Rule::Bool(true))),
+        Some(chunk(vec![call])),
+        None,
+        None,
+        None,
+    );
+    assert_eq!(stat.to_source(), "while true do f() end");
+    let stat = Rule::Stat(
+        StatKind::LocalFunction,
+        Some(Box::new(Rule::Symbol("g".to_string()))),
+        Some(Box::new(Rule::FuncBody(None, chunk(vec![])))),
+        None,
+        None,
+        None,
+    );
+    assert_eq!(stat.to_source(), "local function g() end");
+}
+
+#[test]
+fn minus_after_binary_minus() {
+    let mut l = LuaState::new(65535);
+    purua::prelude::prelude(&mut l);
+    l.run_string("a = 5\nb = 3\nx = 2").unwrap();
+    for (src, want) in [
+        ("return a - -b", 8),
+        ("return 1 - -1", 2),
+        ("return - -x", 2),
+        ("return 2 - - 1", 3),
+        ("return a--b\n", 5),
+    ] {
+        assert!(
+            matches!(l.run_string(src).unwrap(), Value::Number(n) if n == want),
+            "{}",
+            src
+        );
+    }
+}
+
+#[test]
+fn literals_share_storage() {
+    let mut src = String::from("t = {");
+    for _ in 0..1000 {
+        src.push_str("\"repeated literal\", ");
+    }
+    src.push_str("}\nother = \"another literal\"");
+    let mut l = LuaState::new(65535);
+    purua::prelude::prelude(&mut l);
+    l.run_string(&src).unwrap();
+    let t = match l.get_global("t").unwrap() {
+        Value::Table(t) => t,
+        v => panic!("{:?}", v),
+    };
+    let first = match t.get_int(1) {
+        Value::LuaString(s) => s,
+        v => panic!("{:?}", v),
+    };
+    for i in 2..=1000 {
+        match t.get_int(i) {
+            Value::LuaString(s) => assert!(std::rc::Rc::ptr_eq(&first, &s)),
+            v => panic!("{:?}", v),
+        }
+    }
+    match l.get_global("other").unwrap() {
+        Value::LuaString(s) => assert!(!std::rc::Rc::ptr_eq(&first, &s)),
+        v => panic!("{:?}", v),
+    }
+    assert!(t.get_int(1).raw_equal(&t.get_int(1000)));
+    // the tree and the 1000 slots hold the one allocation
+    assert!(std::rc::Rc::strong_count(&first) > 1000);
+}
+
+#[test]
+fn funcbody_params() {
+    use purua::parser::Rule;
+    let mut l = LuaState::new(65535);
+    purua::prelude::prelude(&mut l);
+    let src = "function zero() return 7 end\nfunction one(a) return a + 1 end\nfunction spaced( ) return 8 end\nanon = function() return 9 end\nreturn zero() + one(1) + spaced() + anon()";
+    assert!(matches!(l.run_string(src).unwrap(), Value::Number(26)));
+    assert!(l.run_string("function bad(a,) end").is_err());
+
+    let block = Box::new(Rule::Block(Box::new(Rule::Chunk(vec![], None, vec![1]))));
+    let (params, _) =
+        purua::eval::eval_funcbody(&mut l, &Rule::FuncBody(None, block.clone())).unwrap();
+    assert!(params.is_empty());
+    let one = Box::new(Rule::ParList1(Box::new(Rule::Symbol("a".into()))));
+    let (params, _) =
+        purua::eval::eval_funcbody(&mut l, &Rule::FuncBody(Some(one), block.clone())).unwrap();
+    assert_eq!(params, vec!["a".to_string()]);
+
+    let bad = Box::new(Rule::ParList1(Box::new(Rule::Nil)));
+    let e =
+        purua::eval::eval_funcbody(&mut l, &Rule::FuncBody(Some(bad), block.clone())).unwrap_err();
+    assert!(
+        e.message.contains("Invalid function parameters"),
+        "{}",
+        e.message
+    );
+    let e = purua::eval::eval_funcbody(&mut l, &Rule::FuncBody(Some(block.clone()), block.clone()))
+        .unwrap_err();
+    assert!(
+        e.message.contains("Invalid function parameters"),
+        "{}",
+        e.message
+    );
+    let e =
+        purua::eval::eval_funcbody(&mut l, &Rule::FuncBody(None, Box::new(Rule::Nil))).unwrap_err();
+    assert!(e.message.contains("expected a block"), "{}", e.message);
+}
+
+#[test]
+fn not_operator_and_word_boundaries() {
+    let mut l = LuaState::new(65535);
+    purua::prelude::prelude(&mut l);
+    for (src, want) in [
+        ("return not nil", true),
+        ("return not false", true),
+        ("return not 0", false),
+        ("return not \"\"", false),
+        ("return not not nil", false),
+        ("return not not 0", true),
+    ] {
+        assert!(
+            matches!(l.run_string(src).unwrap(), Value::Bool(b) if b == want),
+            "{}",
+            src
+        );
+    }
+    let v = l
+        .run_string("notes = 1\norder = 2\nandy = 3\nreturn notes + order + andy")
+        .unwrap();
+    assert!(matches!(v, Value::Number(6)));
+    let v = l.run_string("return nil or order and andy").unwrap();
+    assert!(matches!(v, Value::Number(3)));
+}